@@ -0,0 +1,390 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+
+use super::mmd_animation::MmdAnimation;
+use super::mmd_animation_track::{MmdBoneAnimationTrack, MmdMorphAnimationTrack, MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
+use super::mmd_runtime_animation::{BoundAnimation, MmdRuntimeAnimation};
+
+// tracks carry no names on this side, so sources are runtime animations bound to the same model
+// and tracks are matched by the bones and morphs they are bound to
+
+#[inline]
+fn is_composable(runtime_animation: &MmdRuntimeAnimation) -> bool {
+    let animation = runtime_animation.animation();
+    animation.compressed_tracks().is_none() && !animation.is_additive()
+}
+
+// index of the keyframe that holds frame for stepped tracks
+#[inline]
+fn step_index(frame_numbers: &[u32], frame: u32) -> usize {
+    frame_numbers.partition_point(|frame_number| *frame_number <= frame).saturating_sub(1)
+}
+
+// tracks with the same key across sources, in source order
+struct TrackGroups<K> {
+    keys: Vec<K>,
+    tracks: Vec<Vec<(usize, usize)>>,
+    indices: HashMap<K, usize>,
+}
+
+impl<K: Clone + Eq + Hash> TrackGroups<K> {
+    fn new() -> Self {
+        Self {
+            keys: Vec::new(),
+            tracks: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, key: K, source: usize, track: usize) {
+        let index = *self.indices.entry(key.clone()).or_insert_with(|| {
+            self.keys.push(key);
+            self.tracks.push(Vec::new());
+            self.keys.len() - 1
+        });
+        self.tracks[index].push((source, track));
+    }
+}
+
+// each source owns its keyframes from its offset until the next source that animates the same track
+fn concatenate_keyframes<'a, T>(
+    parts: &[(usize, usize)],
+    offsets: &[u32],
+    track: impl Fn(usize, usize) -> &'a T,
+    frame_numbers: impl Fn(&T) -> &[u32],
+) -> Vec<(usize, &'a T, usize, u32)> {
+    let mut keyframes = Vec::new();
+    for (i, (source, track_index)) in parts.iter().enumerate() {
+        let end = parts.get(i + 1).map(|(next_source, _)| offsets[*next_source]).unwrap_or(u32::MAX);
+        let source_track = track(*source, *track_index);
+        for (index, frame_number) in frame_numbers(source_track).iter().enumerate() {
+            let frame_number = frame_number.saturating_add(offsets[*source]);
+            if end <= frame_number {
+                break;
+            }
+            keyframes.push((*source, source_track, index, frame_number));
+        }
+    }
+    keyframes
+}
+
+fn concatenate_track_keyframes<'a, T>(
+    parts: &[(usize, usize)],
+    offsets: &[u32],
+    track: impl Fn(usize, usize) -> &'a T,
+    frame_numbers: impl Fn(&T) -> &[u32],
+) -> Vec<(&'a T, usize, u32)> {
+    concatenate_keyframes(parts, offsets, track, frame_numbers)
+        .into_iter()
+        .map(|(_, track, index, frame_number)| (track, index, frame_number))
+        .collect()
+}
+
+// unions the tracks of sources, colliding tracks are taken from the source with the highest priority
+// and the earlier source wins ties
+pub(crate) fn merge_animations(sources: &[(&MmdRuntimeAnimation, i32)]) -> Option<BoundAnimation> {
+    if !sources.iter().all(|(runtime_animation, _)| is_composable(runtime_animation)) {
+        return None;
+    }
+    let mut order: Vec<usize> = (0..sources.len()).collect();
+    order.sort_by_key(|source| std::cmp::Reverse(sources[*source].1));
+
+    let mut bone_tracks = Vec::new();
+    let mut bone_bind_index_map = Vec::new();
+    let mut movable_bone_tracks = Vec::new();
+    let mut movable_bone_bind_index_map = Vec::new();
+    let mut morph_tracks = Vec::new();
+    let mut morph_bind_index_map = Vec::new();
+
+    let mut claimed_bones = HashSet::new();
+    let mut claimed_morphs = HashSet::new();
+    for source in order.iter().copied() {
+        let runtime_animation = sources[source].0;
+        let animation = runtime_animation.animation();
+
+        // a source may bind the same bone twice, so claims are applied after the whole source is taken
+        let mut source_bones = Vec::new();
+        for (track, bone) in animation.bone_tracks().iter().zip(runtime_animation.bone_bind_index_map()) {
+            if *bone < 0 || claimed_bones.contains(bone) {
+                continue;
+            }
+            bone_tracks.push(track.clone());
+            bone_bind_index_map.push(*bone);
+            source_bones.push(*bone);
+        }
+        for (track, bone) in animation.movable_bone_tracks().iter().zip(runtime_animation.movable_bone_bind_index_map()) {
+            if *bone < 0 || claimed_bones.contains(bone) {
+                continue;
+            }
+            movable_bone_tracks.push(track.clone());
+            movable_bone_bind_index_map.push(*bone);
+            source_bones.push(*bone);
+        }
+        claimed_bones.extend(source_bones);
+
+        let mut source_morphs = Vec::new();
+        for (track, morphs) in animation.morph_tracks().iter().zip(runtime_animation.morph_bind_index_map()) {
+            if morphs.iter().all(|morph| *morph < 0) || morphs.iter().any(|morph| claimed_morphs.contains(morph)) {
+                continue;
+            }
+            morph_tracks.push(track.clone());
+            morph_bind_index_map.push(morphs.clone());
+            source_morphs.extend_from_slice(morphs);
+        }
+        claimed_morphs.extend(source_morphs);
+    }
+
+    // visibility and each ik solver state come from the highest priority source that has them
+    let property_track_of = |source: usize| sources[source].0.animation().property_track();
+    let visibility_source = order.iter().copied().find(|source| !property_track_of(*source).frame_numbers.is_empty());
+    let mut ik_sources = Vec::new();
+    let mut ik_solver_bind_index_map = Vec::new();
+    for source in order.iter().copied() {
+        if property_track_of(source).frame_numbers.is_empty() {
+            continue;
+        }
+        for (ik_track, ik_solver) in sources[source].0.ik_solver_bind_index_map().iter().enumerate() {
+            if 0 <= *ik_solver && !ik_solver_bind_index_map.contains(ik_solver) {
+                ik_sources.push((source, ik_track));
+                ik_solver_bind_index_map.push(*ik_solver);
+            }
+        }
+    }
+
+    let mut frame_numbers = BTreeSet::new();
+    for source in visibility_source.iter().chain(ik_sources.iter().map(|(source, _)| source)) {
+        frame_numbers.extend(property_track_of(*source).frame_numbers.iter().copied());
+    }
+    let mut property_track = MmdPropertyAnimationTrack::new(frame_numbers.len(), ik_sources.len());
+    for (i, frame_number) in frame_numbers.iter().copied().enumerate() {
+        property_track.frame_numbers[i] = frame_number;
+        if let Some(source) = visibility_source {
+            let source_track = property_track_of(source);
+            let visibility = source_track.visibilities()[step_index(&source_track.frame_numbers, frame_number) as u32];
+            property_track.visibilities_mut()[i as u32] = visibility;
+        }
+        for (ik_index, (source, ik_track)) in ik_sources.iter().enumerate() {
+            let source_track = property_track_of(*source);
+            let ik_state = source_track.ik_states(*ik_track)[step_index(&source_track.frame_numbers, frame_number) as u32];
+            property_track.ik_states_mut(ik_index)[i as u32] = ik_state;
+        }
+    }
+
+    Some(BoundAnimation {
+        animation: MmdAnimation::new(
+            bone_tracks.into_boxed_slice(),
+            movable_bone_tracks.into_boxed_slice(),
+            morph_tracks.into_boxed_slice(),
+            property_track,
+        ),
+        bone_bind_index_map: bone_bind_index_map.into_boxed_slice(),
+        movable_bone_bind_index_map: movable_bone_bind_index_map.into_boxed_slice(),
+        morph_bind_index_map: morph_bind_index_map.into_boxed_slice(),
+        ik_solver_bind_index_map: ik_solver_bind_index_map.into_boxed_slice(),
+    })
+}
+
+// places each source at its frame offset, a source takes over a track from the previous one at its offset
+pub(crate) fn concatenate_animations(sources: &[(&MmdRuntimeAnimation, u32)]) -> Option<BoundAnimation> {
+    if !sources.iter().all(|(runtime_animation, _)| is_composable(runtime_animation)) {
+        return None;
+    }
+    let mut order: Vec<usize> = (0..sources.len()).collect();
+    order.sort_by_key(|source| sources[*source].1);
+    let offsets: Vec<u32> = sources.iter().map(|(_, offset)| *offset).collect();
+    let animation_of = |source: usize| sources[source].0.animation();
+
+    // a bone moved by any source gets a single movable track, so that the runtime does not animate it twice.
+    // bone tracks of such bones are converted and appended after the movable tracks of their source
+    let moved_bones: HashSet<i32> = sources.iter()
+        .flat_map(|(runtime_animation, _)| runtime_animation.movable_bone_bind_index_map().iter().copied())
+        .filter(|bone| 0 <= *bone)
+        .collect();
+    let mut source_movable_bone_tracks: Vec<Vec<Cow<MmdMovableBoneAnimationTrack>>> = sources.iter()
+        .map(|(runtime_animation, _)| runtime_animation.animation().movable_bone_tracks().iter().map(Cow::Borrowed).collect())
+        .collect();
+
+    let mut bone_groups = TrackGroups::new();
+    let mut movable_bone_groups = TrackGroups::new();
+    let mut morph_groups = TrackGroups::new();
+    for source in order.iter().copied() {
+        let runtime_animation = sources[source].0;
+        let movable_bone_bind_index_map = runtime_animation.movable_bone_bind_index_map();
+        for (track, bone) in movable_bone_bind_index_map.iter().enumerate() {
+            if 0 <= *bone {
+                movable_bone_groups.push(*bone, source, track);
+            }
+        }
+        for (track, bone) in runtime_animation.bone_bind_index_map().iter().enumerate() {
+            if *bone < 0 {
+                continue;
+            }
+            if !moved_bones.contains(bone) {
+                bone_groups.push(*bone, source, track);
+            } else if !movable_bone_bind_index_map.contains(bone) {
+                let bone_track = &runtime_animation.animation().bone_tracks()[track];
+                source_movable_bone_tracks[source].push(Cow::Owned(MmdMovableBoneAnimationTrack::from_bone_track(bone_track)));
+                movable_bone_groups.push(*bone, source, source_movable_bone_tracks[source].len() - 1);
+            }
+        }
+        for (track, morphs) in runtime_animation.morph_bind_index_map().iter().enumerate() {
+            if morphs.iter().any(|morph| 0 <= *morph) {
+                morph_groups.push(morphs.clone(), source, track);
+            }
+        }
+    }
+
+    let bone_tracks: Vec<MmdBoneAnimationTrack> = bone_groups.tracks.iter()
+        .map(|parts| MmdBoneAnimationTrack::from_keyframes(&concatenate_track_keyframes(
+            parts,
+            &offsets,
+            |source, track| &animation_of(source).bone_tracks()[track],
+            |track| &track.frame_numbers,
+        )))
+        .collect();
+    let movable_bone_tracks: Vec<MmdMovableBoneAnimationTrack> = movable_bone_groups.tracks.iter()
+        .map(|parts| MmdMovableBoneAnimationTrack::from_keyframes(&concatenate_track_keyframes(
+            parts,
+            &offsets,
+            |source, track| &*source_movable_bone_tracks[source][track],
+            |track| &track.frame_numbers,
+        )))
+        .collect();
+    let morph_tracks: Vec<MmdMorphAnimationTrack> = morph_groups.tracks.iter()
+        .map(|parts| MmdMorphAnimationTrack::from_keyframes(&concatenate_track_keyframes(
+            parts,
+            &offsets,
+            |source, track| &animation_of(source).morph_tracks()[track],
+            |track| &track.frame_numbers,
+        )))
+        .collect();
+
+    // ik solvers a source does not bind stay enabled while that source owns the property track
+    let mut ik_solver_bind_index_map: Vec<i32> = Vec::new();
+    for source in order.iter().copied() {
+        for ik_solver in sources[source].0.ik_solver_bind_index_map().iter() {
+            if 0 <= *ik_solver && !ik_solver_bind_index_map.contains(ik_solver) {
+                ik_solver_bind_index_map.push(*ik_solver);
+            }
+        }
+    }
+    let property_parts: Vec<(usize, usize)> = order.iter().copied()
+        .filter(|source| !animation_of(*source).property_track().frame_numbers.is_empty())
+        .map(|source| (source, 0))
+        .collect();
+    let property_keyframes = concatenate_keyframes(
+        &property_parts,
+        &offsets,
+        |source, _| animation_of(source).property_track(),
+        |track| &track.frame_numbers,
+    );
+
+    let mut property_track = MmdPropertyAnimationTrack::new(property_keyframes.len(), ik_solver_bind_index_map.len());
+    for (i, (source, source_track, index, frame_number)) in property_keyframes.iter().enumerate() {
+        property_track.frame_numbers[i] = *frame_number;
+        property_track.visibilities_mut()[i as u32] = source_track.visibilities()[*index as u32];
+
+        let source_ik_solver_bind_index_map = sources[*source].0.ik_solver_bind_index_map();
+        for (ik_index, ik_solver) in ik_solver_bind_index_map.iter().enumerate() {
+            if let Some(ik_track) = source_ik_solver_bind_index_map.iter().position(|source_ik_solver| source_ik_solver == ik_solver) {
+                property_track.ik_states_mut(ik_index)[i as u32] = source_track.ik_states(ik_track)[*index as u32];
+            }
+        }
+    }
+
+    Some(BoundAnimation {
+        animation: MmdAnimation::new(
+            bone_tracks.into_boxed_slice(),
+            movable_bone_tracks.into_boxed_slice(),
+            morph_tracks.into_boxed_slice(),
+            property_track,
+        ),
+        bone_bind_index_map: bone_groups.keys.into_boxed_slice(),
+        movable_bone_bind_index_map: movable_bone_groups.keys.into_boxed_slice(),
+        morph_bind_index_map: morph_groups.keys.into_boxed_slice(),
+        ik_solver_bind_index_map: ik_solver_bind_index_map.into_boxed_slice(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3};
+
+    use super::concatenate_animations;
+    use crate::animation::mmd_animation::MmdAnimation;
+    use crate::animation::mmd_animation_track::{MmdBoneAnimationTrack, MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
+    use crate::animation::mmd_runtime_animation::MmdRuntimeAnimation;
+
+    fn bone_track(frame_numbers: &[u32], angle: f32) -> MmdBoneAnimationTrack {
+        let mut track = MmdBoneAnimationTrack::new(frame_numbers.len());
+        track.frame_numbers.copy_from_slice(frame_numbers);
+        for i in 0..frame_numbers.len() {
+            track.rotations_mut()[i as u32] = Quat::from_rotation_y(angle * i as f32);
+        }
+        track
+    }
+
+    fn movable_bone_track(frame_numbers: &[u32], offset: Vec3) -> MmdMovableBoneAnimationTrack {
+        let mut track = MmdMovableBoneAnimationTrack::new(frame_numbers.len());
+        track.frame_numbers.copy_from_slice(frame_numbers);
+        for i in 0..frame_numbers.len() {
+            track.positions_mut()[i as u32] = offset * i as f32;
+        }
+        track
+    }
+
+    fn runtime_animation(
+        bone_tracks: Vec<(MmdBoneAnimationTrack, i32)>,
+        movable_bone_tracks: Vec<(MmdMovableBoneAnimationTrack, i32)>,
+    ) -> MmdRuntimeAnimation {
+        let (bone_tracks, bone_bind_index_map): (Vec<_>, Vec<_>) = bone_tracks.into_iter().unzip();
+        let (movable_bone_tracks, movable_bone_bind_index_map): (Vec<_>, Vec<_>) = movable_bone_tracks.into_iter().unzip();
+        let animation = MmdAnimation::new(
+            bone_tracks.into_boxed_slice(),
+            movable_bone_tracks.into_boxed_slice(),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+        MmdRuntimeAnimation::new(animation, bone_bind_index_map.into(), movable_bone_bind_index_map.into(), Box::new([]), Box::new([]))
+    }
+
+    #[test]
+    fn concatenated_keyframes_are_offset_and_taken_over() {
+        // the first source keeps its keyframes before the offset of the second, which takes the bone over from there
+        let first = runtime_animation(vec![(bone_track(&[0, 10, 30], 0.1), 0), (bone_track(&[0, 40], 0.2), 1)], vec![]);
+        let second = runtime_animation(vec![(bone_track(&[0, 10], 0.3), 0)], vec![]);
+
+        let bound_animation = concatenate_animations(&[(&second, 20), (&first, 0)]).unwrap();
+        assert_eq!(&*bound_animation.bone_bind_index_map, &[0, 1]);
+        let bone_tracks = bound_animation.animation.bone_tracks();
+        assert_eq!(&*bone_tracks[0].frame_numbers, &[0, 10, 20, 30]);
+        let expected = [0.0, 0.1, 0.0, 0.3].map(Quat::from_rotation_y);
+        for (i, expected) in expected.iter().enumerate() {
+            assert!(bone_tracks[0].rotations()[i as u32].angle_between(*expected) < 1e-4);
+        }
+        // bones only the first source animates keep all of its keyframes
+        assert_eq!(&*bone_tracks[1].frame_numbers, &[0, 40]);
+    }
+
+    #[test]
+    fn bone_and_movable_tracks_of_one_bone_are_merged() {
+        let rotating = runtime_animation(vec![(bone_track(&[0, 10], 0.5), 0), (bone_track(&[0, 5], 0.5), 1)], vec![]);
+        let moving = runtime_animation(vec![], vec![(movable_bone_track(&[0, 10], Vec3::X), 0)]);
+
+        let bound_animation = concatenate_animations(&[(&rotating, 0), (&moving, 15)]).unwrap();
+        assert_eq!(&*bound_animation.bone_bind_index_map, &[1]);
+        assert_eq!(&*bound_animation.movable_bone_bind_index_map, &[0]);
+
+        let animation = &bound_animation.animation;
+        assert_eq!(animation.bone_tracks().len(), 1);
+        let track = &animation.movable_bone_tracks()[0];
+        assert_eq!(&*track.frame_numbers, &[0, 10, 15, 25]);
+        // the rotation part holds the rest position and the moving part starts from the rest rotation
+        assert_eq!(track.positions()[1], Vec3::ZERO);
+        assert!(track.rotations()[1].angle_between(Quat::from_rotation_y(0.5)) < 1e-4);
+        assert_eq!(track.positions()[3], Vec3::X);
+        assert_eq!(track.rotations()[2], Quat::IDENTITY);
+    }
+}
@@ -1,10 +1,26 @@
+use glam::{Quat, Vec3};
 use wasm_bindgen::prelude::*;
 
-use crate::animation::mmd_animation_track::{MmdBoneAnimationTrack, MmdPropertyAnimationTrack, MmdMovableBoneAnimationTrack};
+use crate::animation::mmd_animation_track::{
+    InterpolationScalar,
+    InterpolationVector3,
+    MmdBoneAnimationTrack,
+    MmdCameraAnimationTrack,
+    MmdLightAnimationTrack,
+    MmdPropertyAnimationTrack,
+    MmdMovableBoneAnimationTrack,
+    MmdSelfShadowAnimationTrack,
+};
 use crate::mmd_model::MmdModel;
 
-use super::mmd_animation::MmdAnimation;
-use super::mmd_runtime_animation::MmdRuntimeAnimation;
+use super::animation_composition::{concatenate_animations, merge_animations};
+use super::ik_baking::bake_ik_to_fk;
+use super::pose_recording::record_pose;
+use super::mmd_animation::{AnimationTrackKind, MmdAnimation};
+use super::mmd_camera_animation::MmdCameraAnimation;
+use super::mmd_light_animation::MmdLightAnimation;
+use super::mmd_runtime_animation::{BoundAnimation, MmdRuntimeAnimation, PlaybackLoopMode};
+use super::retargeting::Retargeting;
 use super::mmd_animation_track::MmdMorphAnimationTrack;
 
 #[wasm_bindgen]
@@ -13,6 +29,10 @@ pub struct AnimationPool {
     animations: Vec<Box<MmdAnimation>>,
     #[allow(clippy::vec_box)]
     runtime_animations: Vec<Box<MmdRuntimeAnimation>>,
+    #[allow(clippy::vec_box)]
+    camera_animations: Vec<Box<MmdCameraAnimation>>,
+    #[allow(clippy::vec_box)]
+    light_animations: Vec<Box<MmdLightAnimation>>,
 }
 
 #[wasm_bindgen]
@@ -21,6 +41,8 @@ impl AnimationPool {
         Self {
             animations: Vec::new(),
             runtime_animations: Vec::new(),
+            camera_animations: Vec::new(),
+            light_animations: Vec::new(),
         }
     }
 
@@ -177,6 +199,15 @@ impl AnimationPool {
         track.weights_mut().as_mut_ptr()
     }
 
+    #[wasm_bindgen(js_name = "getMorphTrackWeightInterpolations")]
+    pub fn get_morph_track_weight_interpolations(&self, tracks: *mut usize, index: usize) -> *mut u8 {
+        let tracks = tracks as *mut MmdMorphAnimationTrack;
+        let track = unsafe {
+            &mut *tracks.add(index)
+        };
+        track.weight_interpolations_mut().as_mut_ptr() as *mut u8
+    }
+
     #[wasm_bindgen(js_name = "createAnimation")]
     #[allow(clippy::too_many_arguments)]
     pub fn create_animation(
@@ -205,13 +236,13 @@ impl AnimationPool {
         };
         let property_track = MmdPropertyAnimationTrack::new(property_track_length as usize, property_track_ik_count as usize);
 
-        let animation = Box::new(MmdAnimation::new(
+        let mut animation = Box::new(MmdAnimation::new(
             bone_tracks,
             movable_bone_tracks,
             morph_tracks,
             property_track,
         ));
-        let ptr = &*animation as *const MmdAnimation as *mut usize;
+        let ptr = &mut *animation as *mut MmdAnimation as *mut usize;
         self.animations.push(animation);
         ptr
     }
@@ -226,6 +257,16 @@ impl AnimationPool {
         animation.property_track_mut().frame_numbers.as_mut_ptr() as *mut u32
     }
 
+    #[wasm_bindgen(js_name = "getPropertyTrackVisibilities")]
+    pub fn get_property_track_visibilities(&self, animation_ptr: *mut usize) -> *mut u8 {
+        let animation_ptr = animation_ptr as *mut MmdAnimation;
+        self.check_animation_ptr(animation_ptr);
+        let animation = unsafe {
+            &mut *animation_ptr
+        };
+        animation.property_track_mut().visibilities_mut().as_mut_ptr()
+    }
+
     #[wasm_bindgen(js_name = "getPropertyTrackIkStates")]
     pub fn get_property_track_ik_states(&self, animation_ptr: *mut usize, index: usize) -> *mut u8 {
         let animation_ptr = animation_ptr as *mut MmdAnimation;
@@ -236,6 +277,229 @@ impl AnimationPool {
         animation.property_track_mut().ik_states_mut(index).as_mut_ptr() as *mut u8
     }
 
+    // must be called after the tracks are filled, negative reference_frame means the rest pose,
+    // returns false if the animation is already additive or compressed
+    #[wasm_bindgen(js_name = "makeAnimationAdditive")]
+    pub fn make_animation_additive(&mut self, animation_ptr: *mut usize, reference_frame: f32) -> bool {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        animation.make_additive(if reference_frame < 0.0 { None } else { Some(reference_frame) })
+    }
+
+    // returns (max rotation error, max position error, removed keyframe count), empty if the animation is compressed
+    #[wasm_bindgen(js_name = "reduceAnimationKeyframes")]
+    pub fn reduce_animation_keyframes(&mut self, animation_ptr: *mut usize, rotation_tolerance: f32, position_tolerance: f32) -> Vec<f32> {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return Vec::new(),
+        };
+
+        let report = match animation.reduce_keyframes(rotation_tolerance, position_tolerance) {
+            Some(report) => report,
+            None => return Vec::new(),
+        };
+
+        self.reset_runtime_animation_states(animation_ptr);
+
+        vec![report.max_rotation_error, report.max_position_error, report.removed_keyframe_count as f32]
+    }
+
+    // returns [max_rotation_error, max_position_error, compressed_size, uncompressed_size], empty if the tracks can not be quantized
+    #[wasm_bindgen(js_name = "compressAnimation")]
+    pub fn compress_animation(&mut self, animation_ptr: *mut usize) -> Vec<f32> {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return Vec::new(),
+        };
+
+        let report = match animation.compress() {
+            Some(report) => report,
+            None => return Vec::new(),
+        };
+
+        self.reset_runtime_animation_states(animation_ptr);
+
+        vec![report.max_rotation_error, report.max_position_error, report.compressed_size as f32, report.uncompressed_size as f32]
+    }
+
+    // kind is 0: bone, 1: movable bone, 2: morph, 3: property (track_index is ignored),
+    // returns the index of the keyframe at frame_number or -1 if the track can not be edited
+    #[wasm_bindgen(js_name = "insertAnimationKeyframe")]
+    pub fn insert_animation_keyframe(&mut self, animation_ptr: *mut usize, kind: u8, track_index: usize, frame_number: u32) -> i32 {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return -1,
+        };
+        let kind = match AnimationTrackKind::from_u8(kind) {
+            Some(kind) => kind,
+            None => return -1,
+        };
+
+        let index = animation.insert_keyframe(kind, track_index, frame_number);
+        self.reset_runtime_animation_states(animation_ptr);
+        index.map(|index| index as i32).unwrap_or(-1)
+    }
+
+    #[wasm_bindgen(js_name = "removeAnimationKeyframe")]
+    pub fn remove_animation_keyframe(&mut self, animation_ptr: *mut usize, kind: u8, track_index: usize, keyframe_index: usize) -> bool {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        let kind = match AnimationTrackKind::from_u8(kind) {
+            Some(kind) => kind,
+            None => return false,
+        };
+
+        let removed = animation.remove_keyframe(kind, track_index, keyframe_index);
+        self.reset_runtime_animation_states(animation_ptr);
+        removed
+    }
+
+    // returns the new index of the keyframe or -1 if another keyframe is at frame_number
+    #[wasm_bindgen(js_name = "moveAnimationKeyframe")]
+    pub fn move_animation_keyframe(&mut self, animation_ptr: *mut usize, kind: u8, track_index: usize, keyframe_index: usize, frame_number: u32) -> i32 {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return -1,
+        };
+        let kind = match AnimationTrackKind::from_u8(kind) {
+            Some(kind) => kind,
+            None => return -1,
+        };
+
+        let index = animation.move_keyframe(kind, track_index, keyframe_index, frame_number);
+        self.reset_runtime_animation_states(animation_ptr);
+        index.map(|index| index as i32).unwrap_or(-1)
+    }
+
+    // rotation is [x, y, z, w], interpolation is [x1, x2, y1, y2]
+    #[wasm_bindgen(js_name = "setBoneKeyframe")]
+    pub fn set_bone_keyframe(&mut self, animation_ptr: *mut usize, track_index: usize, keyframe_index: usize, rotation: &[f32], rotation_interpolation: &[u8]) -> bool {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        let (rotation, rotation_interpolation) = match (rotation, InterpolationScalar::from_bytes(rotation_interpolation)) {
+            ([x, y, z, w], Some(rotation_interpolation)) => (Quat::from_xyzw(*x, *y, *z, *w).normalize(), rotation_interpolation),
+            _ => return false,
+        };
+
+        animation.edit_bone_track(track_index, |track, _| {
+            if track.frame_numbers.len() <= keyframe_index {
+                return false;
+            }
+            track.rotations_mut()[keyframe_index as u32] = rotation;
+            track.rotation_interpolations_mut()[keyframe_index as u32] = rotation_interpolation;
+            true
+        }).unwrap_or(false)
+    }
+
+    // position is the offset from the bone rest position, position_interpolation is [x1, x2, y1, y2] for each of x, y, z
+    #[wasm_bindgen(js_name = "setMovableBoneKeyframe")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_movable_bone_keyframe(
+        &mut self,
+        animation_ptr: *mut usize,
+        track_index: usize,
+        keyframe_index: usize,
+        position: &[f32],
+        position_interpolation: &[u8],
+        rotation: &[f32],
+        rotation_interpolation: &[u8],
+    ) -> bool {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        let position = match position {
+            [x, y, z] => Vec3::new(*x, *y, *z),
+            _ => return false,
+        };
+        let position_interpolation = match position_interpolation.chunks(4).map(InterpolationScalar::from_bytes).collect::<Option<Vec<_>>>() {
+            Some(interpolations) if interpolations.len() == 3 => {
+                let mut interpolations = interpolations.into_iter();
+                InterpolationVector3 {
+                    x: interpolations.next().unwrap(),
+                    y: interpolations.next().unwrap(),
+                    z: interpolations.next().unwrap(),
+                }
+            }
+            _ => return false,
+        };
+        let (rotation, rotation_interpolation) = match (rotation, InterpolationScalar::from_bytes(rotation_interpolation)) {
+            ([x, y, z, w], Some(rotation_interpolation)) => (Quat::from_xyzw(*x, *y, *z, *w).normalize(), rotation_interpolation),
+            _ => return false,
+        };
+
+        animation.edit_movable_bone_track(track_index, |track, _| {
+            if track.frame_numbers.len() <= keyframe_index {
+                return false;
+            }
+            track.positions_mut()[keyframe_index as u32] = position;
+            track.position_interpolations_mut()[keyframe_index as u32] = position_interpolation;
+            track.rotations_mut()[keyframe_index as u32] = rotation;
+            track.rotation_interpolations_mut()[keyframe_index as u32] = rotation_interpolation;
+            true
+        }).unwrap_or(false)
+    }
+
+    // empty weight_interpolation keeps the current interpolation
+    #[wasm_bindgen(js_name = "setMorphKeyframe")]
+    pub fn set_morph_keyframe(&mut self, animation_ptr: *mut usize, track_index: usize, keyframe_index: usize, weight: f32, weight_interpolation: &[u8]) -> bool {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        let weight_interpolation = match InterpolationScalar::from_bytes(weight_interpolation) {
+            Some(weight_interpolation) => Some(weight_interpolation),
+            None if weight_interpolation.is_empty() => None,
+            None => return false,
+        };
+
+        animation.edit_morph_track(track_index, |track, _| {
+            if track.frame_numbers.len() <= keyframe_index {
+                return false;
+            }
+            track.weights_mut()[keyframe_index as u32] = weight;
+            if let Some(weight_interpolation) = weight_interpolation {
+                track.weight_interpolations_mut()[keyframe_index as u32] = weight_interpolation;
+            }
+            true
+        }).unwrap_or(false)
+    }
+
+    // ik_states holds one state for each ik of the property track
+    #[wasm_bindgen(js_name = "setPropertyKeyframe")]
+    pub fn set_property_keyframe(&mut self, animation_ptr: *mut usize, keyframe_index: usize, visibility: u8, ik_states: &[u8]) -> bool {
+        let animation_ptr = animation_ptr as *const MmdAnimation;
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        let property_track = animation.property_track_mut();
+        if property_track.frame_numbers.len() <= keyframe_index || property_track.ik_count() != ik_states.len() {
+            return false;
+        }
+
+        property_track.visibilities_mut()[keyframe_index as u32] = visibility;
+        for (i, ik_state) in ik_states.iter().enumerate() {
+            property_track.ik_states_mut(i)[keyframe_index as u32] = *ik_state;
+        }
+        true
+    }
+
     #[wasm_bindgen(js_name = "destroyAnimation")]
     pub fn destroy_animation(&mut self, animation_ptr: *const usize) {
         let animation_ptr = animation_ptr as *const MmdAnimation;
@@ -247,7 +511,7 @@ impl AnimationPool {
         };
         self.animations.remove(index);
 
-        if let Some(index) = self.runtime_animations.iter().position(|animation| animation.animation() as *const MmdAnimation == animation_ptr) {
+        if let Some(index) = self.runtime_animations.iter().position(|animation| animation.animation_ptr() == animation_ptr) {
             self.runtime_animations.remove(index);
         }
     }
@@ -365,7 +629,7 @@ impl AnimationPool {
         };
 
         let runtime_animation = Box::new(MmdRuntimeAnimation::new(
-            animation,
+            animation_ptr,
             bone_bind_index_map,
             movable_bone_bind_index_map,
             morph_bind_index_map,
@@ -376,6 +640,149 @@ impl AnimationPool {
         ptr
     }
 
+    // creates an animation with ik solved into plain bone tracks and a runtime animation bound to mmd_model,
+    // returns the runtime animation or null if the source animation is compressed, additive or retargeted
+    #[wasm_bindgen(js_name = "bakeIkToFk")]
+    pub fn bake_ik_to_fk(&mut self, runtime_animation_ptr: *mut usize, mmd_model_ptr: *mut usize, rotation_tolerance: f32, position_tolerance: f32) -> *mut usize {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &*runtime_animation_ptr
+        };
+        let mmd_model = unsafe {
+            &*(mmd_model_ptr as *const MmdModel)
+        };
+
+        match bake_ik_to_fk(mmd_model, runtime_animation, rotation_tolerance, position_tolerance) {
+            Some(bound_animation) => self.push_bound_animation(bound_animation),
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    // merges runtime animations bound to the same model, colliding tracks are taken from the higher priority source,
+    // returns the runtime animation or null if any source is compressed or additive
+    #[wasm_bindgen(js_name = "mergeRuntimeAnimations")]
+    pub fn merge_runtime_animations(&mut self, runtime_animations_ptr: *const usize, priorities_ptr: *const i32, count: usize) -> *mut usize {
+        let runtime_animation_ptrs = unsafe {
+            std::slice::from_raw_parts(runtime_animations_ptr, count)
+        };
+        let priorities = unsafe {
+            std::slice::from_raw_parts(priorities_ptr, count)
+        };
+        let mut sources = Vec::with_capacity(count);
+        for (runtime_animation_ptr, priority) in runtime_animation_ptrs.iter().zip(priorities.iter()) {
+            let runtime_animation_ptr = *runtime_animation_ptr as *mut MmdRuntimeAnimation;
+            self.check_runtime_animation_ptr(runtime_animation_ptr);
+            sources.push((unsafe { &*runtime_animation_ptr }, *priority));
+        }
+
+        match merge_animations(&sources) {
+            Some(bound_animation) => self.push_bound_animation(bound_animation),
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    // places runtime animations bound to the same model at their frame offsets,
+    // returns the runtime animation or null if any source is compressed or additive
+    #[wasm_bindgen(js_name = "concatenateRuntimeAnimations")]
+    pub fn concatenate_runtime_animations(&mut self, runtime_animations_ptr: *const usize, offsets_ptr: *const u32, count: usize) -> *mut usize {
+        let runtime_animation_ptrs = unsafe {
+            std::slice::from_raw_parts(runtime_animations_ptr, count)
+        };
+        let offsets = unsafe {
+            std::slice::from_raw_parts(offsets_ptr, count)
+        };
+        let mut sources = Vec::with_capacity(count);
+        for (runtime_animation_ptr, offset) in runtime_animation_ptrs.iter().zip(offsets.iter()) {
+            let runtime_animation_ptr = *runtime_animation_ptr as *mut MmdRuntimeAnimation;
+            self.check_runtime_animation_ptr(runtime_animation_ptr);
+            sources.push((unsafe { &*runtime_animation_ptr }, *offset));
+        }
+
+        match concatenate_animations(&sources) {
+            Some(bound_animation) => self.push_bound_animation(bound_animation),
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    // records the current pose of mmd_model into the animation of runtime_animation at frame_number,
+    // bones and morphs without a track get new tracks that stay unbound on the other runtime animations of the animation,
+    // post_ik also disables the enabled ik solvers at frame_number through the property track,
+    // returns false if the animation is compressed, additive or retargeted, or if post_ik can not disable the ik solvers
+    #[wasm_bindgen(js_name = "recordRuntimeAnimationPose")]
+    pub fn record_runtime_animation_pose(
+        &mut self,
+        runtime_animation_ptr: *mut usize,
+        mmd_model_ptr: *mut usize,
+        frame_number: u32,
+        post_ik: bool,
+        changed_only: bool,
+    ) -> bool {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &*runtime_animation_ptr
+        };
+        let animation_ptr = runtime_animation.animation_ptr();
+        let animation = match self.animation_mut(animation_ptr) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        let mmd_model = unsafe {
+            &*(mmd_model_ptr as *const MmdModel)
+        };
+
+        let appended_tracks = match record_pose(animation, runtime_animation, mmd_model, frame_number, post_ik, changed_only) {
+            Some(appended_tracks) => appended_tracks,
+            None => return false,
+        };
+
+        for runtime_animation in self.runtime_animations.iter_mut() {
+            if runtime_animation.animation_ptr() == animation_ptr {
+                let bound = std::ptr::eq(&**runtime_animation, runtime_animation_ptr);
+                runtime_animation.bind_appended_tracks(&appended_tracks, bound);
+            }
+        }
+        self.reset_runtime_animation_states(animation_ptr);
+        true
+    }
+
+    // bone index of each bone track, used to name the tracks appended by bakeIkToFk
+    #[wasm_bindgen(js_name = "getRuntimeAnimationBoneBindIndexMap")]
+    pub fn get_runtime_animation_bone_bind_index_map(&self, runtime_animation_ptr: *mut usize) -> Vec<i32> {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &*runtime_animation_ptr
+        };
+        runtime_animation.bone_bind_index_map().to_vec()
+    }
+
+    // negative bone disables root motion extraction
+    #[wasm_bindgen(js_name = "setRuntimeAnimationRootMotion")]
+    pub fn set_runtime_animation_root_motion(&mut self, runtime_animation_ptr: *mut usize, bone: i32) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+        runtime_animation.set_root_motion_bone(if bone < 0 { None } else { Some(bone) });
+    }
+
+    // returns [position x, y, z, rotation x, y, z, w] of the last frame, empty if root motion is disabled
+    #[wasm_bindgen(js_name = "getRuntimeAnimationRootMotionDelta")]
+    pub fn get_runtime_animation_root_motion_delta(&self, runtime_animation_ptr: *mut usize) -> Vec<f32> {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &*runtime_animation_ptr
+        };
+        match runtime_animation.root_motion_delta() {
+            Some((position, rotation)) => vec![position.x, position.y, position.z, rotation.x, rotation.y, rotation.z, rotation.w],
+            None => Vec::new(),
+        }
+    }
+
     #[wasm_bindgen(js_name = "destroyRuntimeAnimation")]
     pub fn destroy_runtime_animation(&mut self, runtime_animation_ptr: *const usize) {
         let runtime_animation_ptr = runtime_animation_ptr as *const MmdRuntimeAnimation;
@@ -388,6 +795,300 @@ impl AnimationPool {
         self.runtime_animations.remove(index);
     }
 
+    #[wasm_bindgen(js_name = "setRuntimeAnimationBoneMask")]
+    pub fn set_runtime_animation_bone_mask(&mut self, runtime_animation_ptr: *mut usize, bone_mask_ptr: *const f32, bone_count: usize) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+
+        let bone_mask = unsafe {
+            std::slice::from_raw_parts(bone_mask_ptr, bone_count)
+        };
+        runtime_animation.set_bone_mask(Some(bone_mask.into()));
+    }
+
+    #[wasm_bindgen(js_name = "clearRuntimeAnimationBoneMask")]
+    pub fn clear_runtime_animation_bone_mask(&mut self, runtime_animation_ptr: *mut usize) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+        runtime_animation.set_bone_mask(None);
+    }
+
+    #[wasm_bindgen(js_name = "setRuntimeAnimationMorphMask")]
+    pub fn set_runtime_animation_morph_mask(&mut self, runtime_animation_ptr: *mut usize, morph_mask_ptr: *const f32, morph_count: usize) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+
+        let morph_mask = unsafe {
+            std::slice::from_raw_parts(morph_mask_ptr, morph_count)
+        };
+        runtime_animation.set_morph_mask(Some(morph_mask.into()));
+    }
+
+    #[wasm_bindgen(js_name = "clearRuntimeAnimationMorphMask")]
+    pub fn clear_runtime_animation_morph_mask(&mut self, runtime_animation_ptr: *mut usize) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+        runtime_animation.set_morph_mask(None);
+    }
+
+    // source_rest_positions_ptr holds the source skeleton's local rest offset (x, y, z) for each target bone
+    #[wasm_bindgen(js_name = "setRuntimeAnimationRetargeting")]
+    pub fn set_runtime_animation_retargeting(
+        &mut self,
+        runtime_animation_ptr: *mut usize,
+        mmd_model_ptr: *mut usize,
+        source_rest_positions_ptr: *const f32,
+        bone_count: usize,
+        leg_bones_ptr: *const u32,
+        leg_bone_count: usize,
+    ) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+
+        let mmd_model = unsafe {
+            &*(mmd_model_ptr as *const MmdModel)
+        };
+        let source_rest_positions = unsafe {
+            std::slice::from_raw_parts(source_rest_positions_ptr, bone_count * 3)
+        };
+        let leg_bones = unsafe {
+            std::slice::from_raw_parts(leg_bones_ptr, leg_bone_count)
+        };
+
+        let mut retargeted_bones = vec![false; mmd_model.bone_count()];
+        let bound_bones = runtime_animation.bone_bind_index_map().iter().chain(runtime_animation.movable_bone_bind_index_map().iter());
+        for bone in bound_bones {
+            if let Some(retargeted) = retargeted_bones.get_mut(*bone as usize) {
+                *retargeted = true;
+            }
+        }
+
+        let retargeting = Retargeting::new(source_rest_positions, mmd_model.bone_arena(), leg_bones, &retargeted_bones);
+        runtime_animation.set_retargeting(Some(retargeting));
+    }
+
+    #[wasm_bindgen(js_name = "clearRuntimeAnimationRetargeting")]
+    pub fn clear_runtime_animation_retargeting(&mut self, runtime_animation_ptr: *mut usize) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+        runtime_animation.set_retargeting(None);
+    }
+
+    #[wasm_bindgen(js_name = "setRuntimeAnimationPlayback")]
+    pub fn set_runtime_animation_playback(&mut self, runtime_animation_ptr: *mut usize, time_offset: f32, speed: f32) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+        runtime_animation.set_playback(time_offset, speed);
+    }
+
+    #[wasm_bindgen(js_name = "setRuntimeAnimationLoop")]
+    pub fn set_runtime_animation_loop(&mut self, runtime_animation_ptr: *mut usize, loop_mode: u8, loop_start: f32, loop_end: f32, seamless: bool) {
+        let runtime_animation_ptr = runtime_animation_ptr as *mut MmdRuntimeAnimation;
+        self.check_runtime_animation_ptr(runtime_animation_ptr);
+        let runtime_animation = unsafe {
+            &mut *runtime_animation_ptr
+        };
+        let loop_mode = match PlaybackLoopMode::from_u8(loop_mode) {
+            Some(loop_mode) => loop_mode,
+            None => return,
+        };
+        runtime_animation.set_loop(loop_mode, loop_start, loop_end, seamless);
+    }
+
+    #[wasm_bindgen(js_name = "createCameraAnimation")]
+    pub fn create_camera_animation(&mut self, frame_count: usize) -> *mut usize {
+        let camera_animation = Box::new(MmdCameraAnimation::new(MmdCameraAnimationTrack::new(frame_count)));
+        let ptr = &*camera_animation as *const MmdCameraAnimation as *mut usize;
+        self.camera_animations.push(camera_animation);
+        ptr
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackFrameNumbers")]
+    pub fn get_camera_track_frame_numbers(&self, camera_animation_ptr: *mut usize) -> *mut u32 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().frame_numbers.as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackDistances")]
+    pub fn get_camera_track_distances(&self, camera_animation_ptr: *mut usize) -> *mut f32 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().distances_mut().as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackPositions")]
+    pub fn get_camera_track_positions(&self, camera_animation_ptr: *mut usize) -> *mut f32 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().positions_mut().as_mut_ptr() as *mut f32
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackRotations")]
+    pub fn get_camera_track_rotations(&self, camera_animation_ptr: *mut usize) -> *mut f32 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().rotations_mut().as_mut_ptr() as *mut f32
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackInterpolations")]
+    pub fn get_camera_track_interpolations(&self, camera_animation_ptr: *mut usize) -> *mut u8 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().interpolations_mut().as_mut_ptr() as *mut u8
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackFovs")]
+    pub fn get_camera_track_fovs(&self, camera_animation_ptr: *mut usize) -> *mut f32 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().fovs_mut().as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "getCameraTrackIsPerspectives")]
+    pub fn get_camera_track_is_perspectives(&self, camera_animation_ptr: *mut usize) -> *mut u8 {
+        let camera_animation_ptr = camera_animation_ptr as *mut MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+        let camera_animation = unsafe {
+            &mut *camera_animation_ptr
+        };
+        camera_animation.track_mut().is_perspectives_mut().as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "destroyCameraAnimation")]
+    pub fn destroy_camera_animation(&mut self, camera_animation_ptr: *const usize) {
+        let camera_animation_ptr = camera_animation_ptr as *const MmdCameraAnimation;
+        self.check_camera_animation_ptr(camera_animation_ptr);
+
+        let index = match self.camera_animations.iter().position(|animation| std::ptr::eq(&**animation, camera_animation_ptr)) {
+            Some(index) => index,
+            None => return,
+        };
+        self.camera_animations.remove(index);
+    }
+
+    #[wasm_bindgen(js_name = "createLightAnimation")]
+    pub fn create_light_animation(&mut self, light_frame_count: usize, self_shadow_frame_count: usize) -> *mut usize {
+        let light_animation = Box::new(MmdLightAnimation::new(
+            MmdLightAnimationTrack::new(light_frame_count),
+            MmdSelfShadowAnimationTrack::new(self_shadow_frame_count),
+        ));
+        let ptr = &*light_animation as *const MmdLightAnimation as *mut usize;
+        self.light_animations.push(light_animation);
+        ptr
+    }
+
+    #[wasm_bindgen(js_name = "getLightTrackFrameNumbers")]
+    pub fn get_light_track_frame_numbers(&self, light_animation_ptr: *mut usize) -> *mut u32 {
+        let light_animation_ptr = light_animation_ptr as *mut MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+        let light_animation = unsafe {
+            &mut *light_animation_ptr
+        };
+        light_animation.light_track_mut().frame_numbers.as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "getLightTrackColors")]
+    pub fn get_light_track_colors(&self, light_animation_ptr: *mut usize) -> *mut f32 {
+        let light_animation_ptr = light_animation_ptr as *mut MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+        let light_animation = unsafe {
+            &mut *light_animation_ptr
+        };
+        light_animation.light_track_mut().colors_mut().as_mut_ptr() as *mut f32
+    }
+
+    #[wasm_bindgen(js_name = "getLightTrackDirections")]
+    pub fn get_light_track_directions(&self, light_animation_ptr: *mut usize) -> *mut f32 {
+        let light_animation_ptr = light_animation_ptr as *mut MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+        let light_animation = unsafe {
+            &mut *light_animation_ptr
+        };
+        light_animation.light_track_mut().directions_mut().as_mut_ptr() as *mut f32
+    }
+
+    #[wasm_bindgen(js_name = "getSelfShadowTrackFrameNumbers")]
+    pub fn get_self_shadow_track_frame_numbers(&self, light_animation_ptr: *mut usize) -> *mut u32 {
+        let light_animation_ptr = light_animation_ptr as *mut MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+        let light_animation = unsafe {
+            &mut *light_animation_ptr
+        };
+        light_animation.self_shadow_track_mut().frame_numbers.as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "getSelfShadowTrackModes")]
+    pub fn get_self_shadow_track_modes(&self, light_animation_ptr: *mut usize) -> *mut u8 {
+        let light_animation_ptr = light_animation_ptr as *mut MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+        let light_animation = unsafe {
+            &mut *light_animation_ptr
+        };
+        light_animation.self_shadow_track_mut().modes_mut().as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "getSelfShadowTrackDistances")]
+    pub fn get_self_shadow_track_distances(&self, light_animation_ptr: *mut usize) -> *mut f32 {
+        let light_animation_ptr = light_animation_ptr as *mut MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+        let light_animation = unsafe {
+            &mut *light_animation_ptr
+        };
+        light_animation.self_shadow_track_mut().distances_mut().as_mut_ptr()
+    }
+
+    #[wasm_bindgen(js_name = "destroyLightAnimation")]
+    pub fn destroy_light_animation(&mut self, light_animation_ptr: *const usize) {
+        let light_animation_ptr = light_animation_ptr as *const MmdLightAnimation;
+        self.check_light_animation_ptr(light_animation_ptr);
+
+        let index = match self.light_animations.iter().position(|animation| std::ptr::eq(&**animation, light_animation_ptr)) {
+            Some(index) => index,
+            None => return,
+        };
+        self.light_animations.remove(index);
+    }
+
     #[wasm_bindgen(js_name = "animateMmdModel")]
     pub fn animate_mmd_model(&mut self, animation_ptr: *mut usize, mmd_model_ptr: *mut usize, frame_time: f32) {
         let animation_ptr = animation_ptr as *mut MmdRuntimeAnimation;
@@ -404,6 +1105,45 @@ impl AnimationPool {
         animation.animate(frame_time, mmd_model);
     }
 
+    // stores the generated animation and returns a runtime animation bound with its maps
+    fn push_bound_animation(&mut self, bound_animation: BoundAnimation) -> *mut usize {
+        let mut animation = Box::new(bound_animation.animation);
+        let animation_ptr = &mut *animation as *mut MmdAnimation;
+        self.animations.push(animation);
+
+        let runtime_animation = Box::new(MmdRuntimeAnimation::new(
+            animation_ptr,
+            bound_animation.bone_bind_index_map,
+            bound_animation.movable_bone_bind_index_map,
+            bound_animation.morph_bind_index_map,
+            bound_animation.ik_solver_bind_index_map,
+        ));
+        let ptr = &*runtime_animation as *const MmdRuntimeAnimation as *mut usize;
+        self.runtime_animations.push(runtime_animation);
+        ptr
+    }
+
+    // runtime animations hold raw pointers to the animation, so edits go through the same pointer
+    // instead of reborrowing the box, which would invalidate them
+    fn animation_mut(&mut self, animation_ptr: *const MmdAnimation) -> Option<&mut MmdAnimation> {
+        self.check_animation_ptr(animation_ptr);
+        if !self.animations.iter().any(|animation| std::ptr::eq(&**animation, animation_ptr)) {
+            return None;
+        }
+        Some(unsafe {
+            &mut *(animation_ptr as *mut MmdAnimation)
+        })
+    }
+
+    // cached frame indices of the runtime animations of animation_ptr are invalid once its tracks change
+    fn reset_runtime_animation_states(&mut self, animation_ptr: *const MmdAnimation) {
+        for runtime_animation in self.runtime_animations.iter_mut() {
+            if runtime_animation.animation_ptr() == animation_ptr {
+                runtime_animation.reset_state();
+            }
+        }
+    }
+
     #[inline]
     fn check_animation_ptr(&self, animation_ptr: *const MmdAnimation) {
         #[cfg(debug_assertions)]
@@ -415,4 +1155,16 @@ impl AnimationPool {
         #[cfg(debug_assertions)]
         assert!(self.runtime_animations.iter().any(|animation| &**animation as *const MmdRuntimeAnimation == animation_ptr), "AnimationPool: animation_ptr is invalid");
     }
+
+    #[inline]
+    fn check_camera_animation_ptr(&self, animation_ptr: *const MmdCameraAnimation) {
+        #[cfg(debug_assertions)]
+        assert!(self.camera_animations.iter().any(|animation| std::ptr::eq(&**animation, animation_ptr)), "AnimationPool: animation_ptr is invalid");
+    }
+
+    #[inline]
+    fn check_light_animation_ptr(&self, animation_ptr: *const MmdLightAnimation) {
+        #[cfg(debug_assertions)]
+        assert!(self.light_animations.iter().any(|animation| std::ptr::eq(&**animation, animation_ptr)), "AnimationPool: animation_ptr is invalid");
+    }
 }
@@ -1,6 +1,11 @@
+use std::collections::HashMap;
+
+#[cfg(test)]
 const ITERATIONS: i32 = 15;
 const EPSILON: f32 = 1e-5;
 
+// bisection solver, kept as the reference for the curve table tests and benchmarks
+#[cfg(test)]
 pub(crate) fn bezier_interpolation(x1: f32, x2: f32, y1: f32, y2: f32, x: f32) -> f32 {
     let mut c = 0.5;
     let mut t = c;
@@ -34,3 +39,278 @@ pub(crate) fn bezier_interpolation(x1: f32, x2: f32, y1: f32, y2: f32, x: f32) -
     }
     sst3 * y1 + stt3 * y2 + ttt
 }
+
+const TABLE_SIZE: usize = 64;
+const NEWTON_ITERATIONS: i32 = 4;
+
+pub(crate) const LINEAR_CURVE: u32 = u32::MAX;
+
+// curve parameter t sampled at uniform x, refined with a newton step on evaluation
+struct BezierCurve {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+    t_table: [f32; TABLE_SIZE + 1],
+}
+
+impl BezierCurve {
+    fn new(x1: f32, x2: f32, y1: f32, y2: f32) -> Self {
+        let mut t_table = [0.0; TABLE_SIZE + 1];
+        for (i, t) in t_table.iter_mut().enumerate() {
+            let x = i as f32 / TABLE_SIZE as f32;
+            *t = Self::solve_t(x1, x2, x);
+        }
+
+        Self {
+            x1,
+            x2,
+            y1,
+            y2,
+            t_table,
+        }
+    }
+
+    fn solve_t(x1: f32, x2: f32, x: f32) -> f32 {
+        let mut low = 0.0;
+        let mut high = 1.0;
+        for _ in 0..24 {
+            let t = (low + high) * 0.5;
+            if Self::cubic(x1, x2, t) < x {
+                low = t;
+            } else {
+                high = t;
+            }
+        }
+        (low + high) * 0.5
+    }
+
+    #[inline]
+    fn cubic(p1: f32, p2: f32, t: f32) -> f32 {
+        let s = 1.0 - t;
+        3.0 * s * s * t * p1 + 3.0 * s * t * t * p2 + t * t * t
+    }
+
+    #[inline]
+    fn cubic_derivative(p1: f32, p2: f32, t: f32) -> f32 {
+        let s = 1.0 - t;
+        3.0 * s * s * p1 + 6.0 * s * t * (p2 - p1) + 3.0 * t * t * (1.0 - p2)
+    }
+
+    #[inline]
+    fn evaluate(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        let position = x * TABLE_SIZE as f32;
+        let index = (position as usize).min(TABLE_SIZE - 1);
+        let fraction = position - index as f32;
+
+        // the table cell brackets the solution, newton steps falling outside of it are replaced by bisection
+        let mut low = self.t_table[index];
+        let mut high = self.t_table[index + 1];
+        let mut t = low + (high - low) * fraction;
+        for _ in 0..NEWTON_ITERATIONS {
+            let error = Self::cubic(self.x1, self.x2, t) - x;
+            if error.abs() < EPSILON {
+                break;
+            }
+            if error < 0.0 {
+                low = t;
+            } else {
+                high = t;
+            }
+
+            let derivative = Self::cubic_derivative(self.x1, self.x2, t);
+            let next_t = t - error / derivative;
+            t = if low < next_t && next_t < high { next_t } else { (low + high) * 0.5 };
+        }
+
+        Self::cubic(self.y1, self.y2, t)
+    }
+}
+
+// splits the curve at x with de casteljau, returns the control points of both halves as [x1, x2, y1, y2]
+// normalized to their own ranges, None for a half whose value does not change
+pub(crate) fn split_bezier(x1: f32, x2: f32, y1: f32, y2: f32, x: f32) -> (Option<[f32; 4]>, Option<[f32; 4]>) {
+    let t = BezierCurve::solve_t(x1, x2, x);
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+
+    let split = |p1: f32, p2: f32| {
+        let p01 = lerp(0.0, p1);
+        let p12 = lerp(p1, p2);
+        let p23 = lerp(p2, 1.0);
+        let p012 = lerp(p01, p12);
+        let p123 = lerp(p12, p23);
+        (p01, p012, lerp(p012, p123), p123, p23)
+    };
+    let (x01, x012, x_split, x123, x23) = split(x1, x2);
+    let (y01, y012, y_split, y123, y23) = split(y1, y2);
+
+    let normalize = |value: f32, start: f32, end: f32| ((value - start) / (end - start)).clamp(0.0, 1.0);
+    let before = (EPSILON < y_split).then(|| [
+        normalize(x01, 0.0, x_split),
+        normalize(x012, 0.0, x_split),
+        normalize(y01, 0.0, y_split),
+        normalize(y012, 0.0, y_split),
+    ]);
+    let after = (y_split < 1.0 - EPSILON).then(|| [
+        normalize(x123, x_split, 1.0),
+        normalize(x23, x_split, 1.0),
+        normalize(y123, y_split, 1.0),
+        normalize(y23, y_split, 1.0),
+    ]);
+    (before, after)
+}
+
+pub(crate) struct BezierCurveTable {
+    curves: Vec<BezierCurve>,
+    curve_indices: HashMap<u32, u32>,
+}
+
+impl BezierCurveTable {
+    pub(crate) fn new() -> Self {
+        Self {
+            curves: Vec::new(),
+            curve_indices: HashMap::new(),
+        }
+    }
+
+    // control points are the 7-bit values stored in vmd interpolations
+    pub(crate) fn curve_index(&mut self, x1: u8, x2: u8, y1: u8, y2: u8) -> u32 {
+        if x1 == y1 && x2 == y2 {
+            return LINEAR_CURVE;
+        }
+
+        let key = u32::from_le_bytes([x1, x2, y1, y2]);
+        if let Some(index) = self.curve_indices.get(&key) {
+            return *index;
+        }
+
+        let index = self.curves.len() as u32;
+        self.curves.push(BezierCurve::new(
+            x1 as f32 / 127.0,
+            x2 as f32 / 127.0,
+            y1 as f32 / 127.0,
+            y2 as f32 / 127.0,
+        ));
+        self.curve_indices.insert(key, index);
+        index
+    }
+
+    #[inline]
+    pub(crate) fn evaluate(&self, curve_index: u32, x: f32) -> f32 {
+        if curve_index == LINEAR_CURVE {
+            return x;
+        }
+        self.curves[curve_index as usize].evaluate(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use test::Bencher;
+
+    use super::{bezier_interpolation, BezierCurveTable};
+
+    // every 7-bit control point on a coarse grid, linear curves included
+    fn control_points() -> Vec<[u8; 4]> {
+        let values = [0, 10, 20, 45, 64, 90, 107, 120, 127];
+        let mut control_points = Vec::new();
+        for x1 in values {
+            for x2 in values {
+                for y1 in values {
+                    for y2 in values {
+                        control_points.push([x1, x2, y1, y2]);
+                    }
+                }
+            }
+        }
+        control_points
+    }
+
+    fn reference(x1: f64, x2: f64, y1: f64, y2: f64, x: f64) -> f64 {
+        let cubic = |p1: f64, p2: f64, t: f64| {
+            let s = 1.0 - t;
+            3.0 * s * s * t * p1 + 3.0 * s * t * t * p2 + t * t * t
+        };
+        let mut low = 0.0;
+        let mut high = 1.0;
+        for _ in 0..60 {
+            let t = (low + high) * 0.5;
+            if cubic(x1, x2, t) < x {
+                low = t;
+            } else {
+                high = t;
+            }
+        }
+        cubic(y1, y2, (low + high) * 0.5)
+    }
+
+    #[test]
+    fn curve_table_is_at_least_as_accurate_as_bisection() {
+        const SAMPLE_COUNT: u32 = 256;
+
+        let mut curve_table = BezierCurveTable::new();
+        let mut max_table_error: f64 = 0.0;
+        let mut max_bisection_error: f64 = 0.0;
+        let mut max_interior_table_error: f64 = 0.0;
+        for [x1, x2, y1, y2] in control_points() {
+            let curve = curve_table.curve_index(x1, x2, y1, y2);
+            // control points on x = 0 or x = 1 make the curve near vertical somewhere, which bounds the f32 accuracy of both paths
+            let interior = 0 < x1 && x1 < 127 && 0 < x2 && x2 < 127;
+            let (x1, x2, y1, y2) = (x1 as f32 / 127.0, x2 as f32 / 127.0, y1 as f32 / 127.0, y2 as f32 / 127.0);
+            for i in 0..=SAMPLE_COUNT {
+                let x = i as f32 / SAMPLE_COUNT as f32;
+                let expected = reference(x1 as f64, x2 as f64, y1 as f64, y2 as f64, x as f64);
+                let table_error = (curve_table.evaluate(curve, x) as f64 - expected).abs();
+                max_table_error = max_table_error.max(table_error);
+                max_bisection_error = max_bisection_error.max((bezier_interpolation(x1, x2, y1, y2, x) as f64 - expected).abs());
+                if interior {
+                    max_interior_table_error = max_interior_table_error.max(table_error);
+                }
+            }
+        }
+
+        assert!(max_interior_table_error < 2.0e-4, "table error {max_interior_table_error}");
+        assert!(max_table_error < 1.0e-2, "table error {max_table_error}");
+        assert!(max_table_error <= max_bisection_error, "table error {max_table_error}, bisection error {max_bisection_error}");
+    }
+
+    fn bench_samples() -> (Vec<[u8; 4]>, Vec<f32>) {
+        let control_points = control_points().into_iter().filter(|[x1, x2, y1, y2]| x1 != y1 || x2 != y2).collect();
+        let samples = (0..64).map(|i| i as f32 / 63.0).collect();
+        (control_points, samples)
+    }
+
+    #[bench]
+    fn bench_curve_table(bencher: &mut Bencher) {
+        let (control_points, samples) = bench_samples();
+        let mut curve_table = BezierCurveTable::new();
+        let curves: Vec<u32> = control_points.iter().map(|[x1, x2, y1, y2]| curve_table.curve_index(*x1, *x2, *y1, *y2)).collect();
+        bencher.iter(|| {
+            let mut sum = 0.0;
+            for curve in curves.iter() {
+                for x in samples.iter() {
+                    sum += curve_table.evaluate(test::black_box(*curve), test::black_box(*x));
+                }
+            }
+            sum
+        });
+    }
+
+    #[bench]
+    fn bench_bisection(bencher: &mut Bencher) {
+        let (control_points, samples) = bench_samples();
+        bencher.iter(|| {
+            let mut sum = 0.0;
+            for [x1, x2, y1, y2] in control_points.iter() {
+                let (x1, x2, y1, y2) = (*x1 as f32 / 127.0, *x2 as f32 / 127.0, *y1 as f32 / 127.0, *y2 as f32 / 127.0);
+                for x in samples.iter() {
+                    sum += bezier_interpolation(test::black_box(x1), test::black_box(x2), y1, y2, test::black_box(*x));
+                }
+            }
+            sum
+        });
+    }
+}
@@ -0,0 +1,394 @@
+use glam::{Quat, Vec3, Vec4};
+
+use super::bezier_interpolation::{BezierCurveTable, LINEAR_CURVE};
+use super::keyframe_reduction::quat_angle;
+use super::mmd_animation_track::{MmdBoneAnimationTrack, MmdMovableBoneAnimationTrack};
+
+const QUAT_COMPONENT_BITS: u32 = 15;
+const QUAT_COMPONENT_MAX: f32 = ((1 << QUAT_COMPONENT_BITS) - 1) as f32;
+const QUAT_COMPONENT_RANGE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+const COMPRESSED_LINEAR_CURVE: u16 = u16::MAX;
+
+// smallest three packing, 2 bits for the dropped component index and 15 bits for each remaining component
+fn pack_quat(rotation: Quat) -> [u16; 3] {
+    let rotation = Vec4::from(rotation.normalize());
+    let abs = rotation.abs();
+    let mut largest = 0;
+    for i in 1..4 {
+        if abs[largest] < abs[i] {
+            largest = i;
+        }
+    }
+    let rotation = if rotation[largest] < 0.0 { -rotation } else { rotation };
+
+    let mut packed: u64 = largest as u64;
+    for i in (0..4).filter(|i| *i != largest) {
+        let normalized = (rotation[i] / QUAT_COMPONENT_RANGE * 0.5 + 0.5).clamp(0.0, 1.0);
+        packed = (packed << QUAT_COMPONENT_BITS) | (normalized * QUAT_COMPONENT_MAX).round() as u64;
+    }
+
+    [packed as u16, (packed >> 16) as u16, (packed >> 32) as u16]
+}
+
+#[inline]
+fn unpack_quat(packed: [u16; 3]) -> Quat {
+    let packed = packed[0] as u64 | (packed[1] as u64) << 16 | (packed[2] as u64) << 32;
+    let largest = (packed >> (QUAT_COMPONENT_BITS * 3)) as usize & 3;
+
+    let mut rotation = Vec4::ZERO;
+    let mut sum = 0.0;
+    let mut shift = QUAT_COMPONENT_BITS * 2;
+    for i in (0..4).filter(|i| *i != largest) {
+        let component = ((packed >> shift) & ((1 << QUAT_COMPONENT_BITS) - 1)) as f32 / QUAT_COMPONENT_MAX;
+        let component = (component - 0.5) * 2.0 * QUAT_COMPONENT_RANGE;
+        rotation[i] = component;
+        sum += component * component;
+        shift = shift.wrapping_sub(QUAT_COMPONENT_BITS);
+    }
+    rotation[largest] = (1.0 - sum).max(0.0).sqrt();
+
+    Quat::from_vec4(rotation)
+}
+
+#[inline]
+fn compress_curve(curve: u32) -> Option<u16> {
+    if curve == LINEAR_CURVE {
+        Some(COMPRESSED_LINEAR_CURVE)
+    } else if curve < COMPRESSED_LINEAR_CURVE as u32 {
+        Some(curve as u16)
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn decompress_curve(curve: u16) -> u32 {
+    if curve == COMPRESSED_LINEAR_CURVE { LINEAR_CURVE } else { curve as u32 }
+}
+
+// frame numbers stored as u16 offsets from the first keyframe
+struct CompressedFrameNumbers {
+    start_frame: u32,
+    offsets: Box<[u16]>,
+}
+
+impl CompressedFrameNumbers {
+    fn new(frame_numbers: &[u32]) -> Option<Self> {
+        let start_frame = frame_numbers.first().copied().unwrap_or(0);
+        let mut offsets = Vec::with_capacity(frame_numbers.len());
+        for frame_number in frame_numbers {
+            offsets.push(u16::try_from(frame_number - start_frame).ok()?);
+        }
+
+        Some(Self {
+            start_frame,
+            offsets: offsets.into_boxed_slice(),
+        })
+    }
+
+    #[inline]
+    fn end_frame(&self) -> u32 {
+        self.start_frame + self.offsets.last().copied().unwrap_or(0) as u32
+    }
+
+    // returns (frame_index_a, frame_index_b, gradient) of the clamped frame time
+    fn find_frame_interval(&self, frame_time: f32) -> (usize, usize, f32) {
+        let offset = frame_time - self.start_frame as f32;
+        let upper_bound = self.offsets.partition_point(|frame_offset| *frame_offset as f32 <= offset);
+        if upper_bound == 0 {
+            return (0, 0, 0.0);
+        }
+        if upper_bound == self.offsets.len() {
+            return (upper_bound - 1, upper_bound - 1, 0.0);
+        }
+
+        let offset_a = self.offsets[upper_bound - 1] as f32;
+        let offset_b = self.offsets[upper_bound] as f32;
+        (upper_bound - 1, upper_bound, (offset - offset_a) / (offset_b - offset_a))
+    }
+}
+
+pub(crate) struct CompressionReport {
+    pub(crate) max_rotation_error: f32,
+    pub(crate) max_position_error: f32,
+    pub(crate) compressed_size: usize,
+    pub(crate) uncompressed_size: usize,
+}
+
+impl CompressionReport {
+    pub(crate) fn new() -> Self {
+        Self {
+            max_rotation_error: 0.0,
+            max_position_error: 0.0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+        }
+    }
+
+    fn measure_rotation(&mut self, rotation: Quat, packed: [u16; 3]) {
+        let error = quat_angle(rotation, unpack_quat(packed));
+        self.max_rotation_error = self.max_rotation_error.max(error);
+    }
+}
+
+pub(crate) struct CompressedBoneAnimationTrack {
+    frame_numbers: CompressedFrameNumbers,
+    rotations: Box<[[u16; 3]]>,
+    rotation_curves: Box<[u16]>,
+}
+
+impl CompressedBoneAnimationTrack {
+    pub(crate) fn new(track: &MmdBoneAnimationTrack, report: &mut CompressionReport) -> Option<Self> {
+        let frame_numbers = CompressedFrameNumbers::new(&track.frame_numbers)?;
+
+        let mut rotations = Vec::with_capacity(track.rotations().len());
+        for rotation in track.rotations().iter() {
+            let packed = pack_quat(*rotation);
+            report.measure_rotation(*rotation, packed);
+            rotations.push(packed);
+        }
+
+        let mut rotation_curves = Vec::with_capacity(track.rotation_curves().len());
+        for curve in track.rotation_curves().iter() {
+            rotation_curves.push(compress_curve(*curve)?);
+        }
+
+        let frame_count = track.frame_numbers.len();
+        report.uncompressed_size += frame_count * (4 + 16 + 4 + 4);
+        report.compressed_size += frame_count * (2 + 6 + 2);
+
+        Some(Self {
+            frame_numbers,
+            rotations: rotations.into_boxed_slice(),
+            rotation_curves: rotation_curves.into_boxed_slice(),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn end_frame(&self) -> u32 {
+        self.frame_numbers.end_frame()
+    }
+
+    pub(crate) fn sample_rotation(&self, curve_table: &BezierCurveTable, frame_time: f32) -> Quat {
+        if self.rotations.is_empty() {
+            return Quat::IDENTITY;
+        }
+
+        let (frame_index_a, frame_index_b, gradient) = self.frame_numbers.find_frame_interval(frame_time);
+        let rotation_a = unpack_quat(self.rotations[frame_index_a]);
+        if frame_index_a == frame_index_b {
+            return rotation_a;
+        }
+        let weight = curve_table.evaluate(decompress_curve(self.rotation_curves[frame_index_b]), gradient);
+        rotation_a.slerp(unpack_quat(self.rotations[frame_index_b]), weight)
+    }
+}
+
+pub(crate) struct CompressedMovableBoneAnimationTrack {
+    frame_numbers: CompressedFrameNumbers,
+    position_min: Vec3,
+    position_scale: Vec3,
+    positions: Box<[[u16; 3]]>,
+    position_curves: Box<[[u16; 3]]>,
+    rotations: Box<[[u16; 3]]>,
+    rotation_curves: Box<[u16]>,
+}
+
+impl CompressedMovableBoneAnimationTrack {
+    pub(crate) fn new(track: &MmdMovableBoneAnimationTrack, report: &mut CompressionReport) -> Option<Self> {
+        let frame_numbers = CompressedFrameNumbers::new(&track.frame_numbers)?;
+
+        let mut position_min = Vec3::splat(f32::MAX);
+        let mut position_max = Vec3::splat(f32::MIN);
+        for position in track.positions().iter() {
+            position_min = position_min.min(*position);
+            position_max = position_max.max(*position);
+        }
+        let position_scale = if track.positions().is_empty() {
+            position_min = Vec3::ZERO;
+            Vec3::ZERO
+        } else {
+            (position_max - position_min) / u16::MAX as f32
+        };
+
+        let mut positions = Vec::with_capacity(track.positions().len());
+        for position in track.positions().iter() {
+            let quantized = ((*position - position_min) / position_scale.max(Vec3::splat(f32::MIN_POSITIVE))).round();
+            let packed = [quantized.x as u16, quantized.y as u16, quantized.z as u16];
+            let error = (Self::unpack_position(position_min, position_scale, packed) - *position).length();
+            report.max_position_error = report.max_position_error.max(error);
+            positions.push(packed);
+        }
+
+        let mut position_curves = Vec::with_capacity(track.position_curves().len());
+        for [x, y, z] in track.position_curves().iter() {
+            position_curves.push([compress_curve(*x)?, compress_curve(*y)?, compress_curve(*z)?]);
+        }
+
+        let mut rotations = Vec::with_capacity(track.rotations().len());
+        for rotation in track.rotations().iter() {
+            let packed = pack_quat(*rotation);
+            report.measure_rotation(*rotation, packed);
+            rotations.push(packed);
+        }
+
+        let mut rotation_curves = Vec::with_capacity(track.rotation_curves().len());
+        for curve in track.rotation_curves().iter() {
+            rotation_curves.push(compress_curve(*curve)?);
+        }
+
+        let frame_count = track.frame_numbers.len();
+        report.uncompressed_size += frame_count * (4 + 12 + 12 + 16 + 4 + 12 + 4);
+        report.compressed_size += frame_count * (2 + 6 + 6 + 6 + 2) + 24;
+
+        Some(Self {
+            frame_numbers,
+            position_min,
+            position_scale,
+            positions: positions.into_boxed_slice(),
+            position_curves: position_curves.into_boxed_slice(),
+            rotations: rotations.into_boxed_slice(),
+            rotation_curves: rotation_curves.into_boxed_slice(),
+        })
+    }
+
+    #[inline]
+    fn unpack_position(position_min: Vec3, position_scale: Vec3, packed: [u16; 3]) -> Vec3 {
+        position_min + Vec3::new(packed[0] as f32, packed[1] as f32, packed[2] as f32) * position_scale
+    }
+
+    #[inline]
+    pub(crate) fn end_frame(&self) -> u32 {
+        self.frame_numbers.end_frame()
+    }
+
+    // position is the offset from the bone rest position
+    pub(crate) fn sample(&self, curve_table: &BezierCurveTable, frame_time: f32) -> (Vec3, Quat) {
+        if self.rotations.is_empty() {
+            return (Vec3::ZERO, Quat::IDENTITY);
+        }
+
+        let (frame_index_a, frame_index_b, gradient) = self.frame_numbers.find_frame_interval(frame_time);
+        let position_a = Self::unpack_position(self.position_min, self.position_scale, self.positions[frame_index_a]);
+        let rotation_a = unpack_quat(self.rotations[frame_index_a]);
+        if frame_index_a == frame_index_b {
+            return (position_a, rotation_a);
+        }
+
+        let position_b = Self::unpack_position(self.position_min, self.position_scale, self.positions[frame_index_b]);
+        let [x, y, z] = self.position_curves[frame_index_b];
+        let position = Vec3::new(
+            position_a.x + (position_b.x - position_a.x) * curve_table.evaluate(decompress_curve(x), gradient),
+            position_a.y + (position_b.y - position_a.y) * curve_table.evaluate(decompress_curve(y), gradient),
+            position_a.z + (position_b.z - position_a.z) * curve_table.evaluate(decompress_curve(z), gradient),
+        );
+
+        let weight = curve_table.evaluate(decompress_curve(self.rotation_curves[frame_index_b]), gradient);
+        let rotation = rotation_a.slerp(unpack_quat(self.rotations[frame_index_b]), weight);
+        (position, rotation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{EulerRot, Quat, Vec3};
+
+    use super::super::bezier_interpolation::BezierCurveTable;
+    use super::super::keyframe_reduction::quat_angle;
+    use super::super::mmd_animation::MmdAnimation;
+    use super::super::mmd_animation_track::{InterpolationScalar, MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
+    use super::{pack_quat, unpack_quat, CompressedMovableBoneAnimationTrack, CompressionReport};
+
+    fn rotation(i: usize) -> Quat {
+        let i = i as f32;
+        Quat::from_euler(EulerRot::YXZ, (i * 0.37).sin() * 3.0, (i * 0.23).cos() * 1.5, (i * 0.71).sin() * 3.0)
+    }
+
+    fn movable_bone_track() -> MmdMovableBoneAnimationTrack {
+        let frame_count = 16;
+        let mut track = MmdMovableBoneAnimationTrack::new(frame_count);
+        for (i, frame_number) in track.frame_numbers.iter_mut().enumerate() {
+            *frame_number = i as u32 * 7;
+        }
+        {
+            let mut positions = track.positions_mut();
+            for i in 0..frame_count {
+                let x = i as f32;
+                positions[i as u32] = Vec3::new((x * 0.5).sin() * 12.0, x * 0.8 - 3.0, (x * 0.3).cos() * 40.0);
+            }
+        }
+        {
+            let mut rotations = track.rotations_mut();
+            for i in 0..frame_count {
+                rotations[i as u32] = rotation(i);
+            }
+        }
+        {
+            let mut rotation_interpolations = track.rotation_interpolations_mut();
+            for i in 0..frame_count {
+                rotation_interpolations[i as u32] = InterpolationScalar { x1: 64, x2: 64, y1: 0, y2: 127 };
+            }
+        }
+        track
+    }
+
+    #[test]
+    fn packed_quats_round_trip() {
+        for i in 0..4096 {
+            let rotation = rotation(i);
+            let unpacked = unpack_quat(pack_quat(rotation));
+            assert!((unpacked.length() - 1.0).abs() < 1.0e-4);
+            assert!(quat_angle(rotation, unpacked) < 2.0e-4, "rotation {i} is off by {}", quat_angle(rotation, unpacked));
+        }
+        for rotation in [Quat::IDENTITY, -Quat::IDENTITY, Quat::from_rotation_x(std::f32::consts::PI), Quat::from_xyzw(0.5, -0.5, 0.5, -0.5)] {
+            assert!(quat_angle(rotation, unpack_quat(pack_quat(rotation))) < 2.0e-4);
+        }
+    }
+
+    #[test]
+    fn compressed_track_stays_within_reported_error() {
+        let mut curve_table = BezierCurveTable::new();
+        let mut track = movable_bone_track();
+        track.build_curves(&mut curve_table);
+
+        let mut report = CompressionReport::new();
+        let compressed_track = CompressedMovableBoneAnimationTrack::new(&track, &mut report).unwrap();
+        assert!(0.0 < report.max_position_error && report.max_position_error < 1.0e-3);
+        assert!(0.0 < report.max_rotation_error && report.max_rotation_error < 2.0e-4);
+        assert!(report.compressed_size < report.uncompressed_size);
+
+        // the keyframes reach the reported errors, interpolation between them must not add to it
+        let mut max_position_error: f32 = 0.0;
+        let mut max_rotation_error: f32 = 0.0;
+        for i in 0..=track.end_frame() * 4 {
+            let frame_time = i as f32 * 0.25;
+            let (position, rotation) = track.sample(&curve_table, frame_time);
+            let (compressed_position, compressed_rotation) = compressed_track.sample(&curve_table, frame_time);
+            let position_error = (position - compressed_position).length();
+            let rotation_error = quat_angle(rotation, compressed_rotation);
+            assert!(position_error <= report.max_position_error + 1.0e-5, "position error {position_error} at frame {frame_time}");
+            assert!(rotation_error <= report.max_rotation_error + 1.0e-4, "rotation error {rotation_error} at frame {frame_time}");
+            if i % 4 == 0 && (i / 4) % 7 == 0 {
+                max_position_error = max_position_error.max(position_error);
+                max_rotation_error = max_rotation_error.max(rotation_error);
+            }
+        }
+        assert!((max_position_error - report.max_position_error).abs() < 1.0e-5);
+        assert!((max_rotation_error - report.max_rotation_error).abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn compressed_animation_rejects_reduction_and_additive() {
+        let mut animation = MmdAnimation::new(
+            Box::new([]),
+            vec![movable_bone_track()].into_boxed_slice(),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        assert!(animation.compress().is_some());
+        assert!(animation.compress().is_none());
+        assert!(animation.reduce_keyframes(0.01, 0.01).is_none());
+        assert!(!animation.make_additive(None));
+        assert!(!animation.is_additive());
+    }
+}
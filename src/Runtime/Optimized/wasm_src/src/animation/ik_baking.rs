@@ -0,0 +1,343 @@
+use glam::{Quat, Vec3};
+
+use crate::mmd_model::MmdModel;
+
+use super::keyframe_reduction::quat_angle;
+use super::mmd_animation::MmdAnimation;
+use super::mmd_animation_track::{MmdBoneAnimationTrack, MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
+use super::mmd_runtime_animation::{BoundAnimation, MmdRuntimeAnimation};
+
+#[inline]
+fn gradient(a: u32, b: u32, frame: u32) -> f32 {
+    if a == b { 0.0 } else { (frame - a) as f32 / (b - a) as f32 }
+}
+
+// inserts the worst frame of each segment until interpolating between the keyframes stays within tolerance,
+// frame_error(a, b, frame) is the error at frame between the keyframes a and b relative to the tolerance.
+// segments are split with an explicit stack since long animations would overflow the wasm stack
+fn refine_segment(frame_error: &impl Fn(u32, u32, u32) -> f32, a: u32, b: u32, keyframes: &mut Vec<u32>) {
+    let inserted_start = keyframes.len();
+    let mut segments = vec![(a, b)];
+    while let Some((a, b)) = segments.pop() {
+        let mut max_error = 1.0;
+        let mut max_error_frame = None;
+        for frame in a + 1..b {
+            let error = frame_error(a, b, frame);
+            if max_error < error {
+                max_error = error;
+                max_error_frame = Some(frame);
+            }
+        }
+
+        if let Some(frame) = max_error_frame {
+            keyframes.push(frame);
+            segments.push((a, frame));
+            segments.push((frame, b));
+        }
+    }
+    keyframes[inserted_start..].sort_unstable();
+}
+
+// keyframes must be sorted, frame_error(a, a, frame) is the error of holding the pose of a at frame
+fn refine_keyframes(frame_count: usize, keyframes: &[u32], frame_error: impl Fn(u32, u32, u32) -> f32) -> Vec<u32> {
+    let last_frame = frame_count as u32 - 1;
+    let mut keyframes: Vec<u32> = keyframes.iter().copied().filter(|frame| *frame <= last_frame).collect();
+    keyframes.dedup();
+
+    // the runtime clamps outside the keyframe range, add the range ends if that would not hold the pose
+    let first_keyframe = keyframes.first().copied().unwrap_or(0);
+    if keyframes.is_empty() || (0..first_keyframe).any(|frame| 1.0 < frame_error(first_keyframe, first_keyframe, frame)) {
+        keyframes.insert(0, 0);
+    }
+    let last_keyframe = *keyframes.last().unwrap();
+    if (last_keyframe + 1..=last_frame).any(|frame| 1.0 < frame_error(last_keyframe, last_keyframe, frame)) {
+        keyframes.push(last_frame);
+    }
+
+    let mut refined_keyframes = Vec::with_capacity(keyframes.len());
+    refined_keyframes.push(keyframes[0]);
+    for segment in keyframes.windows(2) {
+        refine_segment(&frame_error, segment[0], segment[1], &mut refined_keyframes);
+        refined_keyframes.push(segment[1]);
+    }
+    refined_keyframes
+}
+
+fn rotation_error(rotations: &[Quat], a: u32, b: u32, frame: u32) -> f32 {
+    let rotation = rotations[a as usize].slerp(rotations[b as usize], gradient(a, b, frame));
+    quat_angle(rotation, rotations[frame as usize])
+}
+
+// evaluates runtime_animation with ik on model and returns an animation that reproduces it with ik disabled,
+// the source bone tracks keep their slots and ik chain bones without a track are appended after them,
+// movable tracks of ik chain bones are rebaked and the other tracks are copied
+pub(crate) fn bake_ik_to_fk(model: &MmdModel, runtime_animation: &MmdRuntimeAnimation, rotation_tolerance: f32, position_tolerance: f32) -> Option<BoundAnimation> {
+    let animation = runtime_animation.animation();
+    // retargeted values can not be mapped back to the tracks
+    if animation.compressed_tracks().is_some() || animation.is_additive() || runtime_animation.retargeting().is_some() {
+        return None;
+    }
+
+    let bones = model.bone_arena().arena();
+    let mut bone_bind_index_map = runtime_animation.bone_bind_index_map().to_vec();
+    let movable_bone_bind_index_map = runtime_animation.movable_bone_bind_index_map();
+    let mut is_bound = vec![false; bones.len()];
+    for bone in bone_bind_index_map.iter().chain(movable_bone_bind_index_map.iter()) {
+        if let Some(is_bound) = is_bound.get_mut(*bone as usize) {
+            *is_bound = true;
+        }
+    }
+    for (i, bone) in bones.iter().enumerate() {
+        if bone.ik_rotation.is_some() && !is_bound[i] {
+            bone_bind_index_map.push(i as i32);
+        }
+    }
+
+    // baked values are stored track major, ik_rotation is combined with the animated rotation
+    // and movable positions are offsets from the rest position
+    let frame_count = animation.end_frame() as usize + 1;
+    let mut baked_rotations = vec![Quat::IDENTITY; bone_bind_index_map.len() * frame_count];
+    let mut baked_movable_rotations = vec![Quat::IDENTITY; movable_bone_bind_index_map.len() * frame_count];
+    let mut baked_movable_positions = vec![Vec3::ZERO; movable_bone_bind_index_map.len() * frame_count];
+    let mut is_ik_movable_track = vec![false; movable_bone_bind_index_map.len()];
+    {
+        let mut bake_animation = MmdRuntimeAnimation::new(
+            animation,
+            runtime_animation.bone_bind_index_map().into(),
+            movable_bone_bind_index_map.into(),
+            runtime_animation.morph_bind_index_map().into(),
+            runtime_animation.ik_solver_bind_index_map().into(),
+        );
+
+        let mut frame = 0;
+        model.evaluate_runtime_animation(&mut bake_animation, (0..frame_count).map(|frame| frame as f32), |animation_arena, bone_arena| {
+            let animated_bones = animation_arena.bone_arena();
+            let bones = bone_arena.arena();
+            for (track_index, bone_index) in bone_bind_index_map.iter().enumerate() {
+                let bone = match bones.get(*bone_index as u32) {
+                    Some(bone) => bone,
+                    None => continue,
+                };
+                let rotation = animated_bones[*bone_index as u32].rotation;
+                baked_rotations[track_index * frame_count + frame] = match bone.ik_rotation {
+                    Some(ik_rotation) => ik_rotation * rotation,
+                    None => rotation,
+                };
+            }
+            for (track_index, bone_index) in movable_bone_bind_index_map.iter().enumerate() {
+                let bone = match bones.get(*bone_index as u32) {
+                    Some(bone) => bone,
+                    None => continue,
+                };
+                let ik_rotation = match bone.ik_rotation {
+                    Some(ik_rotation) => ik_rotation,
+                    None => continue,
+                };
+                let animated_bone = &animated_bones[*bone_index as u32];
+                is_ik_movable_track[track_index] = true;
+                baked_movable_rotations[track_index * frame_count + frame] = ik_rotation * animated_bone.rotation;
+                baked_movable_positions[track_index * frame_count + frame] = (animated_bone.position - bone.rest_position).into();
+            }
+            frame += 1;
+        });
+    }
+
+    let mut bone_tracks = Vec::with_capacity(bone_bind_index_map.len());
+    for (track_index, bone_index) in bone_bind_index_map.iter().enumerate() {
+        let source_track = animation.bone_tracks().get(track_index);
+        if bones.get(*bone_index as u32).is_none() {
+            // unbound tracks do not affect this model
+            if let Some(source_track) = source_track {
+                bone_tracks.push(source_track.clone());
+            }
+            continue;
+        }
+
+        let rotations = &baked_rotations[track_index * frame_count..(track_index + 1) * frame_count];
+        let source_keyframes = source_track.map(|source_track| &*source_track.frame_numbers).unwrap_or(&[]);
+        let keyframes = refine_keyframes(frame_count, source_keyframes, |a, b, frame| {
+            rotation_error(rotations, a, b, frame) / rotation_tolerance
+        });
+
+        let mut track = MmdBoneAnimationTrack::new(keyframes.len());
+        track.frame_numbers.copy_from_slice(&keyframes);
+        let mut track_rotations = track.rotations_mut();
+        for (i, keyframe) in keyframes.iter().enumerate() {
+            track_rotations[i as u32] = rotations[*keyframe as usize];
+        }
+        bone_tracks.push(track);
+    }
+
+    let mut movable_bone_tracks = Vec::with_capacity(movable_bone_bind_index_map.len());
+    for (track_index, source_track) in animation.movable_bone_tracks().iter().enumerate() {
+        if !is_ik_movable_track[track_index] {
+            movable_bone_tracks.push(source_track.clone());
+            continue;
+        }
+
+        let rotations = &baked_movable_rotations[track_index * frame_count..(track_index + 1) * frame_count];
+        let positions = &baked_movable_positions[track_index * frame_count..(track_index + 1) * frame_count];
+        let keyframes = refine_keyframes(frame_count, &source_track.frame_numbers, |a, b, frame| {
+            let position = positions[a as usize].lerp(positions[b as usize], gradient(a, b, frame));
+            let position_error = position.distance(positions[frame as usize]) / position_tolerance;
+            position_error.max(rotation_error(rotations, a, b, frame) / rotation_tolerance)
+        });
+
+        let mut track = MmdMovableBoneAnimationTrack::new(keyframes.len());
+        track.frame_numbers.copy_from_slice(&keyframes);
+        {
+            let mut track_positions = track.positions_mut();
+            for (i, keyframe) in keyframes.iter().enumerate() {
+                track_positions[i as u32] = positions[*keyframe as usize];
+            }
+        }
+        {
+            let mut track_rotations = track.rotations_mut();
+            for (i, keyframe) in keyframes.iter().enumerate() {
+                track_rotations[i as u32] = rotations[*keyframe as usize];
+            }
+        }
+        movable_bone_tracks.push(track);
+    }
+
+    // property tracks list the ik states in bone order, which is not necessarily the solver order
+    let ik_solver_bind_index_map: Box<[i32]> = bones.iter()
+        .filter_map(|bone| bone.ik_solver.map(|ik_solver| ik_solver as i32))
+        .collect();
+    let ik_count = ik_solver_bind_index_map.len();
+    let source_property_track = animation.property_track();
+    let mut property_track = MmdPropertyAnimationTrack::new(source_property_track.frame_numbers.len().max(1), ik_count);
+    if !source_property_track.frame_numbers.is_empty() {
+        property_track.frame_numbers.copy_from_slice(&source_property_track.frame_numbers);
+        property_track.visibilities_mut().copy_from_slice(&source_property_track.visibilities());
+    }
+    for i in 0..ik_count {
+        property_track.ik_states_mut(i).fill(0);
+    }
+
+    Some(BoundAnimation {
+        animation: MmdAnimation::new(
+            bone_tracks.into_boxed_slice(),
+            movable_bone_tracks.into_boxed_slice(),
+            animation.morph_tracks().into(),
+            property_track,
+        ),
+        bone_bind_index_map: bone_bind_index_map.into_boxed_slice(),
+        movable_bone_bind_index_map: movable_bone_bind_index_map.into(),
+        morph_bind_index_map: runtime_animation.morph_bind_index_map().into(),
+        ik_solver_bind_index_map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec3A};
+
+    use super::{bake_ik_to_fk, refine_keyframes};
+    use crate::animation::mmd_animation::MmdAnimation;
+    use crate::animation::mmd_animation_track::{MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
+    use crate::animation::mmd_runtime_animation::MmdRuntimeAnimation;
+    use crate::mmd_model::MmdModel;
+    use crate::mmd_model_metadata::{BoneFlag, MetadataBuffer};
+
+    const END_EFFECTOR: u32 = 3;
+
+    fn push_bone(bytes: &mut Vec<u8>, rest_position: Vec3, parent: i32, flag: u16) {
+        for value in rest_position.to_array() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&parent.to_le_bytes());
+        bytes.extend_from_slice(&0_i32.to_le_bytes());
+        bytes.extend_from_slice(&flag.to_le_bytes());
+        bytes.extend_from_slice(&[0; 2]);
+    }
+
+    // root, two unit links along y ending at the end effector, and an ik bone under the root targeting it
+    fn leg_metadata() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for count in [5_u32, 0, 1] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        push_bone(&mut bytes, Vec3::ZERO, -1, 0);
+        push_bone(&mut bytes, Vec3::ZERO, 0, 0);
+        push_bone(&mut bytes, Vec3::Y, 1, 0);
+        push_bone(&mut bytes, Vec3::Y, 2, 0);
+        push_bone(&mut bytes, Vec3::new(0.6, 1.4, 0.0), 0, BoneFlag::IsIkEnabled as u16);
+        bytes.extend_from_slice(&(END_EFFECTOR as i32).to_le_bytes());
+        bytes.extend_from_slice(&40_i32.to_le_bytes());
+        bytes.extend_from_slice(&1.0_f32.to_le_bytes());
+        bytes.extend_from_slice(&2_u32.to_le_bytes());
+        for link in [2_i32, 1] {
+            bytes.extend_from_slice(&link.to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+        }
+        for count in [0_u32, 0, 0] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn end_effector_positions(model: &MmdModel, runtime_animation: &mut MmdRuntimeAnimation, frame_count: u32) -> Vec<Vec3A> {
+        let mut positions = Vec::new();
+        model.evaluate_runtime_animation(runtime_animation, (0..frame_count).map(|frame| frame as f32), |_, bone_arena| {
+            positions.push(Vec3A::from(bone_arena.world_matrices()[END_EFFECTOR].w_axis));
+        });
+        positions
+    }
+
+    #[test]
+    fn baked_fk_reproduces_the_ik_pose() {
+        let metadata = leg_metadata();
+        let model = MmdModel::new(MetadataBuffer::new(&metadata));
+
+        // the ik bone sweeps in front of the leg over 30 frames
+        let mut track = MmdMovableBoneAnimationTrack::new(3);
+        track.frame_numbers.copy_from_slice(&[0, 15, 30]);
+        track.positions_mut()[1] = Vec3::new(-0.4, -0.2, 0.5);
+        track.positions_mut()[2] = Vec3::new(-1.0, 0.2, 0.0);
+        let animation = MmdAnimation::new(
+            Box::new([]),
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+        let mut runtime_animation = MmdRuntimeAnimation::new(animation, Box::new([]), Box::new([4]), Box::new([]), Box::new([]));
+
+        let bound_animation = bake_ik_to_fk(&model, &runtime_animation, 1.0e-3, 1.0e-3).unwrap();
+        assert_eq!(&*bound_animation.bone_bind_index_map, &[1, 2]);
+        assert_eq!(&*bound_animation.ik_solver_bind_index_map, &[0]);
+        let baked_animation: &'static MmdAnimation = Box::leak(Box::new(bound_animation.animation));
+        let mut baked_runtime_animation = MmdRuntimeAnimation::new(
+            baked_animation,
+            bound_animation.bone_bind_index_map,
+            bound_animation.movable_bone_bind_index_map,
+            bound_animation.morph_bind_index_map,
+            bound_animation.ik_solver_bind_index_map,
+        );
+
+        let ik_positions = end_effector_positions(&model, &mut runtime_animation, 31);
+        let fk_positions = end_effector_positions(&model, &mut baked_runtime_animation, 31);
+        // the end effector actually follows the ik bone
+        assert!(0.5 < ik_positions[0].distance(ik_positions[30]));
+        for (frame, (ik_position, fk_position)) in ik_positions.iter().zip(fk_positions.iter()).enumerate() {
+            assert!(ik_position.distance(*fk_position) < 5.0e-3, "frame {frame}: {ik_position} != {fk_position}");
+        }
+    }
+
+    #[test]
+    fn refined_keyframes_are_sorted_and_bounded() {
+        // a sawtooth needs a keyframe at every corner
+        let values: Vec<f32> = (0..2000).map(|frame| (frame % 7) as f32).collect();
+        let keyframes = refine_keyframes(values.len(), &[0, 1999], |a, b, frame| {
+            let t = if a == b { 0.0 } else { (frame - a) as f32 / (b - a) as f32 };
+            (values[a as usize] + (values[b as usize] - values[a as usize]) * t - values[frame as usize]).abs() / 0.01
+        });
+        assert!(keyframes.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keyframes.first(), Some(&0));
+        assert_eq!(keyframes.last(), Some(&1999));
+        for corner in (6..1999).step_by(7) {
+            assert!(keyframes.binary_search(&corner).is_ok(), "missing corner {corner}");
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use glam::{Quat, Vec3};
+
+use super::bezier_interpolation::BezierCurveTable;
+use super::mmd_animation_track::{InterpolationScalar, MmdBoneAnimationTrack, MmdMovableBoneAnimationTrack};
+
+const MAX_SEGMENT_LENGTH: usize = 64;
+const CONTROL_POINT_STEP: usize = 8;
+const SOLVE_ITERATIONS: i32 = 24;
+const PARAMETER_TABLE_SIZE: usize = 256;
+const SAMPLES_PER_FRAME: u32 = 2;
+
+pub(crate) struct KeyframeReductionReport {
+    pub(crate) max_rotation_error: f32,
+    pub(crate) max_position_error: f32,
+    pub(crate) removed_keyframe_count: u32,
+}
+
+impl KeyframeReductionReport {
+    pub(crate) fn new() -> Self {
+        Self {
+            max_rotation_error: 0.0,
+            max_position_error: 0.0,
+            removed_keyframe_count: 0,
+        }
+    }
+}
+
+#[inline]
+fn cubic(p1: f32, p2: f32, t: f32) -> f32 {
+    let s = 1.0 - t;
+    3.0 * s * s * t * p1 + 3.0 * s * t * t * p2 + t * t * t
+}
+
+fn solve_t(x1: f32, x2: f32, x: f32) -> f32 {
+    let mut low = 0.0;
+    let mut high = 1.0;
+    for _ in 0..SOLVE_ITERATIONS {
+        let t = (low + high) * 0.5;
+        if cubic(x1, x2, t) < x {
+            low = t;
+        } else {
+            high = t;
+        }
+    }
+    (low + high) * 0.5
+}
+
+// acos of the dot product can not resolve angles below about 1e-3 in f32
+#[inline]
+pub(crate) fn quat_angle(a: Quat, b: Quat) -> f32 {
+    let delta = a.conjugate() * b;
+    2.0 * delta.xyz().length().atan2(delta.w.abs())
+}
+
+// fits mmd bezier curves, the curve parameters of every candidate (x1, x2) are solved once at uniform x
+// and the errors of the picked curves are measured with the curves the runtime evaluates
+pub(crate) struct CurveFitter {
+    control_points: Box<[u8]>,
+    parameter_tables: Box<[[f32; PARAMETER_TABLE_SIZE + 1]]>,
+    curve_table: BezierCurveTable,
+}
+
+impl CurveFitter {
+    pub(crate) fn new() -> Self {
+        let control_points: Box<[u8]> = (0..128).step_by(CONTROL_POINT_STEP).chain(std::iter::once(127)).map(|x| x as u8).collect();
+        let mut parameter_tables = Vec::with_capacity(control_points.len() * control_points.len());
+        for x1 in control_points.iter() {
+            for x2 in control_points.iter() {
+                let mut parameter_table = [0.0; PARAMETER_TABLE_SIZE + 1];
+                for (i, t) in parameter_table.iter_mut().enumerate() {
+                    *t = solve_t(*x1 as f32 / 127.0, *x2 as f32 / 127.0, i as f32 / PARAMETER_TABLE_SIZE as f32);
+                }
+                parameter_tables.push(parameter_table);
+            }
+        }
+
+        Self {
+            control_points,
+            parameter_tables: parameter_tables.into_boxed_slice(),
+            curve_table: BezierCurveTable::new(),
+        }
+    }
+
+    #[inline]
+    fn parameter(&self, table: usize, x: f32) -> f32 {
+        let parameter_table = &self.parameter_tables[table];
+        let position = x.clamp(0.0, 1.0) * PARAMETER_TABLE_SIZE as f32;
+        let index = (position as usize).min(PARAMETER_TABLE_SIZE - 1);
+        let fraction = position - index as f32;
+        parameter_table[index] + (parameter_table[index + 1] - parameter_table[index]) * fraction
+    }
+
+    fn max_error(&mut self, curve: &InterpolationScalar, gradients: &[f32], error: &impl Fn(usize, f32) -> f32) -> f32 {
+        let curve = curve.curve_index(&mut self.curve_table);
+        gradients.iter().enumerate().map(|(i, gradient)| error(i, self.curve_table.evaluate(curve, *gradient))).fold(0.0, f32::max)
+    }
+
+    // fits a curve through (gradient, weight) samples, error(index, weight) measures each sample in value space
+    // returns the curve with the smallest maximum error
+    fn fit(&mut self, gradients: &[f32], weights: &[f32], error: impl Fn(usize, f32) -> f32) -> (InterpolationScalar, f32) {
+        let linear = InterpolationScalar::new();
+        let mut best_error = self.max_error(&linear, gradients, &error);
+        let mut best_curve = linear;
+
+        let mut parameters = vec![0.0; gradients.len()];
+        for table in 0..self.parameter_tables.len() {
+            // y is linear in (y1, y2) once t is known, so solve them by least squares
+            let mut aa = 0.0;
+            let mut ab = 0.0;
+            let mut bb = 0.0;
+            let mut ar = 0.0;
+            let mut br = 0.0;
+            for ((parameter, gradient), weight) in parameters.iter_mut().zip(gradients).zip(weights) {
+                let t = self.parameter(table, *gradient);
+                *parameter = t;
+                let s = 1.0 - t;
+                let a = 3.0 * s * s * t;
+                let b = 3.0 * s * t * t;
+                let r = weight - t * t * t;
+                aa += a * a;
+                ab += a * b;
+                bb += b * b;
+                ar += a * r;
+                br += b * r;
+            }
+            let determinant = aa * bb - ab * ab;
+            if determinant.abs() < 1.0e-9 {
+                continue;
+            }
+            let y1 = ((ar * bb - br * ab) / determinant).clamp(0.0, 1.0);
+            let y2 = ((aa * br - ab * ar) / determinant).clamp(0.0, 1.0);
+            let y1 = (y1 * 127.0).round() as u8;
+            let y2 = (y2 * 127.0).round() as u8;
+
+            // the tabulated parameters only screen the candidates
+            let y1_f = y1 as f32 / 127.0;
+            let y2_f = y2 as f32 / 127.0;
+            let mut max_error: f32 = 0.0;
+            for (i, parameter) in parameters.iter().enumerate() {
+                max_error = max_error.max(error(i, cubic(y1_f, y2_f, *parameter)));
+                if best_error <= max_error {
+                    break;
+                }
+            }
+            if best_error <= max_error {
+                continue;
+            }
+
+            let control_point_count = self.control_points.len();
+            let curve = InterpolationScalar {
+                x1: self.control_points[table / control_point_count],
+                x2: self.control_points[table % control_point_count],
+                y1,
+                y2,
+            };
+            let max_error = self.max_error(&curve, gradients, &error);
+            if max_error < best_error {
+                best_error = max_error;
+                best_curve = curve;
+            }
+        }
+
+        (best_curve, best_error)
+    }
+}
+
+// the source track evaluated every 1 / SAMPLES_PER_FRAME frames from its first keyframe,
+// segments are checked against these so that the refitted curves also hold between the keyframes
+struct TrackSamples<T> {
+    start_frame: u32,
+    values: Vec<T>,
+}
+
+impl<T: Copy> TrackSamples<T> {
+    fn new(frame_numbers: &[u32], sample: impl Fn(f32) -> T) -> Self {
+        let start_frame = frame_numbers.first().copied().unwrap_or(0);
+        let end_frame = frame_numbers.last().copied().unwrap_or(0);
+        let sample_count = ((end_frame - start_frame) * SAMPLES_PER_FRAME) as usize + 1;
+        let values = (0..sample_count)
+            .map(|i| sample(start_frame as f32 + i as f32 / SAMPLES_PER_FRAME as f32))
+            .collect();
+
+        Self {
+            start_frame,
+            values,
+        }
+    }
+
+    // (gradient, value) of the samples strictly between frame_a and frame_b
+    fn between(&self, frame_a: u32, frame_b: u32) -> (Vec<f32>, Vec<T>) {
+        let first = ((frame_a - self.start_frame) * SAMPLES_PER_FRAME) as usize;
+        let last = ((frame_b - self.start_frame) * SAMPLES_PER_FRAME) as usize;
+        let length = (last - first) as f32;
+        (first + 1..last).map(|i| ((i - first) as f32 / length, self.values[i])).unzip()
+    }
+}
+
+// picks the kept keyframe indices greedily, fit(a, b) returns the fitted segment or None when it exceeds the tolerance,
+// the longest segment is found by doubling its length until it fails and then bisecting
+fn reduce_keyframes<T>(frame_count: usize, mut fit: impl FnMut(usize, usize) -> Option<T>) -> Vec<(usize, Option<T>)> {
+    let mut keyframes = Vec::new();
+    if frame_count == 0 {
+        return keyframes;
+    }
+
+    let mut a = 0;
+    keyframes.push((0, None));
+    while a + 1 < frame_count {
+        let max_b = (a + MAX_SEGMENT_LENGTH).min(frame_count - 1);
+        let mut b = a + 1;
+        let mut segment = fit(a, b);
+
+        let mut failed_b = None;
+        let mut length = 2;
+        while b < max_b {
+            let next_b = (a + length).min(max_b);
+            match fit(a, next_b) {
+                Some(next_segment) => {
+                    b = next_b;
+                    segment = Some(next_segment);
+                    length *= 2;
+                }
+                None => {
+                    failed_b = Some(next_b);
+                    break;
+                }
+            }
+        }
+        if let Some(mut failed_b) = failed_b {
+            while b + 1 < failed_b {
+                let next_b = (b + failed_b) / 2;
+                match fit(a, next_b) {
+                    Some(next_segment) => {
+                        b = next_b;
+                        segment = Some(next_segment);
+                    }
+                    None => failed_b = next_b,
+                }
+            }
+        }
+
+        keyframes.push((b, segment));
+        a = b;
+    }
+    keyframes
+}
+
+fn rotation_segment(
+    fitter: &mut CurveFitter,
+    samples: &TrackSamples<Quat>,
+    frame_numbers: &[u32],
+    rotations: &[Quat],
+    a: usize,
+    b: usize,
+    tolerance: f32,
+) -> Option<(InterpolationScalar, f32)> {
+    let rotation_a = rotations[a];
+    let rotation_b = rotations[b];
+    let total_angle = quat_angle(rotation_a, rotation_b);
+
+    let (gradients, targets) = samples.between(frame_numbers[a], frame_numbers[b]);
+    let weights: Vec<f32> = targets.iter()
+        .map(|target| if total_angle < 1.0e-6 { 0.0 } else { quat_angle(rotation_a, *target) / total_angle })
+        .collect();
+
+    let (curve, error) = fitter.fit(&gradients, &weights, |i, weight| {
+        quat_angle(rotation_a.slerp(rotation_b, weight), targets[i])
+    });
+    if error <= tolerance { Some((curve, error)) } else { None }
+}
+
+fn position_segment(
+    fitter: &mut CurveFitter,
+    samples: &TrackSamples<Vec3>,
+    frame_numbers: &[u32],
+    positions: &[Vec3],
+    a: usize,
+    b: usize,
+    tolerance: f32,
+) -> Option<([InterpolationScalar; 3], f32)> {
+    let (gradients, targets) = samples.between(frame_numbers[a], frame_numbers[b]);
+
+    let mut curves = [InterpolationScalar::new(), InterpolationScalar::new(), InterpolationScalar::new()];
+    let mut max_error: f32 = 0.0;
+    for (axis, curve) in curves.iter_mut().enumerate() {
+        let value_a = positions[a][axis];
+        let value_b = positions[b][axis];
+        let delta = value_b - value_a;
+        let weights: Vec<f32> = targets.iter()
+            .map(|target| if delta.abs() < 1.0e-6 { 0.0 } else { (target[axis] - value_a) / delta })
+            .collect();
+
+        let (axis_curve, error) = fitter.fit(&gradients, &weights, |i, weight| {
+            (value_a + delta * weight - targets[i][axis]).abs()
+        });
+        if tolerance < error {
+            return None;
+        }
+        *curve = axis_curve;
+        max_error = max_error.max(error);
+    }
+    Some((curves, max_error))
+}
+
+// the curves of track must be built with curve_table
+pub(crate) fn reduce_bone_track(
+    fitter: &mut CurveFitter,
+    track: &MmdBoneAnimationTrack,
+    curve_table: &BezierCurveTable,
+    rotation_tolerance: f32,
+    report: &mut KeyframeReductionReport,
+) -> MmdBoneAnimationTrack {
+    let frame_numbers = &track.frame_numbers;
+    let rotations = &*track.rotations();
+    let interpolations = &*track.rotation_interpolations();
+    let samples = TrackSamples::new(frame_numbers, |frame_time| track.sample_rotation(curve_table, frame_time));
+
+    let keyframes = reduce_keyframes(frame_numbers.len(), |a, b| {
+        if b == a + 1 {
+            return Some((interpolations[b].clone(), 0.0));
+        }
+        rotation_segment(fitter, &samples, frame_numbers, rotations, a, b, rotation_tolerance)
+    });
+
+    let mut reduced_track = MmdBoneAnimationTrack::new(keyframes.len());
+    {
+        let mut reduced_rotations = reduced_track.rotations_mut();
+        for (i, (keyframe, _)) in keyframes.iter().enumerate() {
+            reduced_rotations[i as u32] = rotations[*keyframe];
+        }
+    }
+    {
+        let mut reduced_interpolations = reduced_track.rotation_interpolations_mut();
+        for (i, (keyframe, segment)) in keyframes.iter().enumerate() {
+            reduced_interpolations[i as u32] = match segment {
+                Some((curve, error)) => {
+                    report.max_rotation_error = report.max_rotation_error.max(*error);
+                    curve.clone()
+                }
+                None => interpolations[*keyframe].clone(),
+            };
+        }
+    }
+    for (reduced_frame_number, (keyframe, _)) in reduced_track.frame_numbers.iter_mut().zip(keyframes.iter()) {
+        *reduced_frame_number = frame_numbers[*keyframe];
+    }
+
+    report.removed_keyframe_count += (frame_numbers.len() - keyframes.len()) as u32;
+    reduced_track
+}
+
+// the curves of track must be built with curve_table
+pub(crate) fn reduce_movable_bone_track(
+    fitter: &mut CurveFitter,
+    track: &MmdMovableBoneAnimationTrack,
+    curve_table: &BezierCurveTable,
+    rotation_tolerance: f32,
+    position_tolerance: f32,
+    report: &mut KeyframeReductionReport,
+) -> MmdMovableBoneAnimationTrack {
+    let frame_numbers = &track.frame_numbers;
+    let positions = &*track.positions();
+    let position_interpolations = &*track.position_interpolations();
+    let rotations = &*track.rotations();
+    let rotation_interpolations = &*track.rotation_interpolations();
+    let position_samples = TrackSamples::new(frame_numbers, |frame_time| track.sample(curve_table, frame_time).0);
+    let rotation_samples = TrackSamples::new(frame_numbers, |frame_time| track.sample(curve_table, frame_time).1);
+
+    let keyframes = reduce_keyframes(frame_numbers.len(), |a, b| {
+        if b == a + 1 {
+            let position_interpolation = &position_interpolations[b];
+            let position_curves = [position_interpolation.x.clone(), position_interpolation.y.clone(), position_interpolation.z.clone()];
+            return Some((position_curves, 0.0, rotation_interpolations[b].clone(), 0.0));
+        }
+        let (position_curves, position_error) = position_segment(fitter, &position_samples, frame_numbers, positions, a, b, position_tolerance)?;
+        let (rotation_curve, rotation_error) = rotation_segment(fitter, &rotation_samples, frame_numbers, rotations, a, b, rotation_tolerance)?;
+        Some((position_curves, position_error, rotation_curve, rotation_error))
+    });
+
+    let mut reduced_track = MmdMovableBoneAnimationTrack::new(keyframes.len());
+    {
+        let mut reduced_positions = reduced_track.positions_mut();
+        for (i, (keyframe, _)) in keyframes.iter().enumerate() {
+            reduced_positions[i as u32] = positions[*keyframe];
+        }
+    }
+    {
+        let mut reduced_rotations = reduced_track.rotations_mut();
+        for (i, (keyframe, _)) in keyframes.iter().enumerate() {
+            reduced_rotations[i as u32] = rotations[*keyframe];
+        }
+    }
+    {
+        let mut reduced_position_interpolations = reduced_track.position_interpolations_mut();
+        for (i, (keyframe, segment)) in keyframes.iter().enumerate() {
+            let reduced_position_interpolation = &mut reduced_position_interpolations[i as u32];
+            match segment {
+                Some(([x, y, z], position_error, _, _)) => {
+                    report.max_position_error = report.max_position_error.max(*position_error);
+                    reduced_position_interpolation.x = x.clone();
+                    reduced_position_interpolation.y = y.clone();
+                    reduced_position_interpolation.z = z.clone();
+                }
+                None => *reduced_position_interpolation = position_interpolations[*keyframe].clone(),
+            }
+        }
+    }
+    {
+        let mut reduced_rotation_interpolations = reduced_track.rotation_interpolations_mut();
+        for (i, (keyframe, segment)) in keyframes.iter().enumerate() {
+            reduced_rotation_interpolations[i as u32] = match segment {
+                Some((_, _, rotation_curve, rotation_error)) => {
+                    report.max_rotation_error = report.max_rotation_error.max(*rotation_error);
+                    rotation_curve.clone()
+                }
+                None => rotation_interpolations[*keyframe].clone(),
+            };
+        }
+    }
+    for (reduced_frame_number, (keyframe, _)) in reduced_track.frame_numbers.iter_mut().zip(keyframes.iter()) {
+        *reduced_frame_number = frame_numbers[*keyframe];
+    }
+
+    report.removed_keyframe_count += (frame_numbers.len() - keyframes.len()) as u32;
+    reduced_track
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3};
+
+    use super::super::bezier_interpolation::BezierCurveTable;
+    use super::super::mmd_animation_track::{InterpolationScalar, MmdBoneAnimationTrack};
+    use super::{quat_angle, reduce_bone_track, CurveFitter, KeyframeReductionReport};
+
+    #[test]
+    fn reduced_track_stays_within_tolerance_between_keyframes() {
+        let mut curve_table = BezierCurveTable::new();
+        let frame_count = 25;
+        let mut track = MmdBoneAnimationTrack::new(frame_count);
+        for (i, frame_number) in track.frame_numbers.iter_mut().enumerate() {
+            *frame_number = i as u32 * 5;
+        }
+        {
+            let mut rotations = track.rotations_mut();
+            for i in 0..frame_count {
+                rotations[i as u32] = Quat::from_axis_angle(Vec3::Y, (i as f32 * 0.25).sin());
+            }
+        }
+        {
+            // eased segments leave the source far from linear between its keyframes
+            let mut interpolations = track.rotation_interpolations_mut();
+            for i in 0..frame_count {
+                interpolations[i as u32] = InterpolationScalar { x1: 64, x2: 64, y1: 0, y2: 127 };
+            }
+        }
+        track.build_curves(&mut curve_table);
+
+        let tolerance = 0.02;
+        let mut report = KeyframeReductionReport::new();
+        let mut reduced_track = reduce_bone_track(&mut CurveFitter::new(), &track, &curve_table, tolerance, &mut report);
+        reduced_track.build_curves(&mut curve_table);
+
+        assert!(0 < report.removed_keyframe_count);
+        assert!(report.max_rotation_error <= tolerance);
+        let end_frame = track.end_frame();
+        for i in 0..=end_frame * 2 {
+            let frame_time = i as f32 * 0.5;
+            let error = quat_angle(track.sample_rotation(&curve_table, frame_time), reduced_track.sample_rotation(&curve_table, frame_time));
+            assert!(error <= tolerance + 1.0e-4, "error {error} at frame {frame_time}");
+        }
+    }
+}
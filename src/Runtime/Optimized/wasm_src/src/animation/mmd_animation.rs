@@ -1,3 +1,6 @@
+use super::bezier_interpolation::BezierCurveTable;
+use super::compressed_animation_track::{CompressedBoneAnimationTrack, CompressedMovableBoneAnimationTrack, CompressionReport};
+use super::keyframe_reduction::{reduce_bone_track, reduce_movable_bone_track, CurveFitter, KeyframeReductionReport};
 use super::mmd_animation_track::{MmdBoneAnimationTrack, MmdMorphAnimationTrack, MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
 
 pub(crate) struct MmdAnimation {
@@ -5,6 +8,35 @@ pub(crate) struct MmdAnimation {
     movable_bone_tracks: Box<[MmdMovableBoneAnimationTrack]>,
     morph_tracks: Box<[MmdMorphAnimationTrack]>,
     property_track: MmdPropertyAnimationTrack,
+    curve_table: BezierCurveTable,
+    compressed_tracks: Option<CompressedTracks>,
+    additive: bool,
+}
+
+// replaces bone_tracks and movable_bone_tracks once the animation is compressed
+pub(crate) struct CompressedTracks {
+    pub(crate) bone_tracks: Box<[CompressedBoneAnimationTrack]>,
+    pub(crate) movable_bone_tracks: Box<[CompressedMovableBoneAnimationTrack]>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AnimationTrackKind {
+    Bone = 0,
+    MovableBone = 1,
+    Morph = 2,
+    Property = 3,
+}
+
+impl AnimationTrackKind {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(AnimationTrackKind::Bone),
+            1 => Some(AnimationTrackKind::MovableBone),
+            2 => Some(AnimationTrackKind::Morph),
+            3 => Some(AnimationTrackKind::Property),
+            _ => None,
+        }
+    }
 }
 
 impl MmdAnimation {
@@ -14,12 +46,225 @@ impl MmdAnimation {
         morph_tracks: Box<[MmdMorphAnimationTrack]>,
         property_track: MmdPropertyAnimationTrack,
     ) -> Self {
-        Self {
+        let mut animation = Self {
             bone_tracks,
             movable_bone_tracks,
             morph_tracks,
             property_track,
+            curve_table: BezierCurveTable::new(),
+            compressed_tracks: None,
+            additive: false,
+        };
+        animation.build_curves();
+        animation
+    }
+
+    // interpolations are expected to be filled before the animation is created
+    fn build_curves(&mut self) {
+        self.curve_table = BezierCurveTable::new();
+        let curve_table = &mut self.curve_table;
+        for track in self.bone_tracks.iter_mut() {
+            track.build_curves(curve_table);
+        }
+        for track in self.movable_bone_tracks.iter_mut() {
+            track.build_curves(curve_table);
+        }
+        for track in self.morph_tracks.iter_mut() {
+            track.build_curves(curve_table);
+        }
+    }
+
+    // removes keyframes that can be reproduced by a refitted bezier curve within the tolerances (radians, model units),
+    // returns None if the animation is compressed
+    pub(crate) fn reduce_keyframes(&mut self, rotation_tolerance: f32, position_tolerance: f32) -> Option<KeyframeReductionReport> {
+        if self.compressed_tracks.is_some() {
+            return None;
+        }
+
+        let mut report = KeyframeReductionReport::new();
+        let mut fitter = CurveFitter::new();
+        for track in self.bone_tracks.iter_mut() {
+            *track = reduce_bone_track(&mut fitter, track, &self.curve_table, rotation_tolerance, &mut report);
+        }
+        for track in self.movable_bone_tracks.iter_mut() {
+            *track = reduce_movable_bone_track(&mut fitter, track, &self.curve_table, rotation_tolerance, position_tolerance, &mut report);
+        }
+        self.build_curves();
+        Some(report)
+    }
+
+    // quantizes bone and movable bone tracks and releases the full precision keyframes,
+    // returns None and leaves the animation untouched if some track can not be represented
+    pub(crate) fn compress(&mut self) -> Option<CompressionReport> {
+        if self.compressed_tracks.is_some() {
+            return None;
+        }
+
+        let mut report = CompressionReport::new();
+        let mut bone_tracks = Vec::with_capacity(self.bone_tracks.len());
+        for track in self.bone_tracks.iter() {
+            bone_tracks.push(CompressedBoneAnimationTrack::new(track, &mut report)?);
+        }
+        let mut movable_bone_tracks = Vec::with_capacity(self.movable_bone_tracks.len());
+        for track in self.movable_bone_tracks.iter() {
+            movable_bone_tracks.push(CompressedMovableBoneAnimationTrack::new(track, &mut report)?);
+        }
+
+        for track in self.bone_tracks.iter_mut() {
+            *track = MmdBoneAnimationTrack::new(0);
+        }
+        for track in self.movable_bone_tracks.iter_mut() {
+            *track = MmdMovableBoneAnimationTrack::new(0);
+        }
+        self.compressed_tracks = Some(CompressedTracks {
+            bone_tracks: bone_tracks.into_boxed_slice(),
+            movable_bone_tracks: movable_bone_tracks.into_boxed_slice(),
+        });
+        Some(report)
+    }
+
+    // runs edit on a track and rebuilds its curves, None if the track does not exist or is compressed,
+    // the curve table passed to edit samples the track as it was before the edit
+    pub(crate) fn edit_bone_track<R>(&mut self, track_index: usize, edit: impl FnOnce(&mut MmdBoneAnimationTrack, &BezierCurveTable) -> R) -> Option<R> {
+        if self.compressed_tracks.is_some() {
+            return None;
+        }
+        let track = self.bone_tracks.get_mut(track_index)?;
+        let result = edit(track, &self.curve_table);
+        track.build_curves(&mut self.curve_table);
+        Some(result)
+    }
+
+    pub(crate) fn edit_movable_bone_track<R>(&mut self, track_index: usize, edit: impl FnOnce(&mut MmdMovableBoneAnimationTrack, &BezierCurveTable) -> R) -> Option<R> {
+        if self.compressed_tracks.is_some() {
+            return None;
+        }
+        let track = self.movable_bone_tracks.get_mut(track_index)?;
+        let result = edit(track, &self.curve_table);
+        track.build_curves(&mut self.curve_table);
+        Some(result)
+    }
+
+    pub(crate) fn edit_morph_track<R>(&mut self, track_index: usize, edit: impl FnOnce(&mut MmdMorphAnimationTrack, &BezierCurveTable) -> R) -> Option<R> {
+        let track = self.morph_tracks.get_mut(track_index)?;
+        let result = edit(track, &self.curve_table);
+        track.build_curves(&mut self.curve_table);
+        Some(result)
+    }
+
+    // appended tracks must be bound on every runtime animation of this animation, returns the track index
+    pub(crate) fn push_bone_track(&mut self, mut track: MmdBoneAnimationTrack) -> usize {
+        track.build_curves(&mut self.curve_table);
+        let mut bone_tracks = std::mem::take(&mut self.bone_tracks).into_vec();
+        bone_tracks.push(track);
+        self.bone_tracks = bone_tracks.into_boxed_slice();
+        self.bone_tracks.len() - 1
+    }
+
+    pub(crate) fn push_movable_bone_track(&mut self, mut track: MmdMovableBoneAnimationTrack) -> usize {
+        track.build_curves(&mut self.curve_table);
+        let mut movable_bone_tracks = std::mem::take(&mut self.movable_bone_tracks).into_vec();
+        movable_bone_tracks.push(track);
+        self.movable_bone_tracks = movable_bone_tracks.into_boxed_slice();
+        self.movable_bone_tracks.len() - 1
+    }
+
+    pub(crate) fn push_morph_track(&mut self, mut track: MmdMorphAnimationTrack) -> usize {
+        track.build_curves(&mut self.curve_table);
+        let mut morph_tracks = std::mem::take(&mut self.morph_tracks).into_vec();
+        morph_tracks.push(track);
+        self.morph_tracks = morph_tracks.into_boxed_slice();
+        self.morph_tracks.len() - 1
+    }
+
+    // track_index is ignored for the property track, returns the index of the keyframe at frame_number
+    pub(crate) fn insert_keyframe(&mut self, kind: AnimationTrackKind, track_index: usize, frame_number: u32) -> Option<usize> {
+        match kind {
+            AnimationTrackKind::Bone => self.edit_bone_track(track_index, |track, curve_table| track.insert_keyframe(curve_table, frame_number)),
+            AnimationTrackKind::MovableBone => self.edit_movable_bone_track(track_index, |track, curve_table| track.insert_keyframe(curve_table, frame_number)),
+            AnimationTrackKind::Morph => self.edit_morph_track(track_index, |track, curve_table| track.insert_keyframe(curve_table, frame_number)),
+            AnimationTrackKind::Property => Some(self.property_track.insert_keyframe(frame_number)),
+        }
+    }
+
+    pub(crate) fn remove_keyframe(&mut self, kind: AnimationTrackKind, track_index: usize, index: usize) -> bool {
+        match kind {
+            AnimationTrackKind::Bone => self.edit_bone_track(track_index, |track, _| track.remove_keyframe(index)),
+            AnimationTrackKind::MovableBone => self.edit_movable_bone_track(track_index, |track, _| track.remove_keyframe(index)),
+            AnimationTrackKind::Morph => self.edit_morph_track(track_index, |track, _| track.remove_keyframe(index)),
+            AnimationTrackKind::Property => Some(self.property_track.remove_keyframe(index)),
+        }.unwrap_or(false)
+    }
+
+    // returns the new index of the keyframe, None if another keyframe is at frame_number
+    pub(crate) fn move_keyframe(&mut self, kind: AnimationTrackKind, track_index: usize, index: usize, frame_number: u32) -> Option<usize> {
+        match kind {
+            AnimationTrackKind::Bone => self.edit_bone_track(track_index, |track, _| track.move_keyframe(index, frame_number)),
+            AnimationTrackKind::MovableBone => self.edit_movable_bone_track(track_index, |track, _| track.move_keyframe(index, frame_number)),
+            AnimationTrackKind::Morph => self.edit_morph_track(track_index, |track, _| track.move_keyframe(index, frame_number)),
+            AnimationTrackKind::Property => Some(self.property_track.move_keyframe(index, frame_number)),
+        }.flatten()
+    }
+
+    #[inline]
+    pub(crate) fn compressed_tracks(&self) -> Option<&CompressedTracks> {
+        self.compressed_tracks.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn curve_table(&self) -> &BezierCurveTable {
+        &self.curve_table
+    }
+
+    #[inline]
+    pub(crate) fn is_additive(&self) -> bool {
+        self.additive
+    }
+
+    // converts every track to deltas from the pose at reference_frame, or from the rest pose if none,
+    // returns false if the animation is already additive or compressed
+    pub(crate) fn make_additive(&mut self, reference_frame: Option<f32>) -> bool {
+        if self.additive || self.compressed_tracks.is_some() {
+            return false;
         }
+        self.additive = true;
+
+        for track in self.bone_tracks.iter_mut() {
+            let inverse_reference = match reference_frame {
+                Some(reference_frame) => track.sample_rotation(&self.curve_table, reference_frame).inverse(),
+                None => continue,
+            };
+            for rotation in track.rotations_mut().iter_mut() {
+                *rotation = (inverse_reference * *rotation).normalize();
+            }
+        }
+
+        for track in self.movable_bone_tracks.iter_mut() {
+            let (reference_position, inverse_reference) = match reference_frame {
+                Some(reference_frame) => {
+                    let (position, rotation) = track.sample(&self.curve_table, reference_frame);
+                    (position, rotation.inverse())
+                }
+                None => continue,
+            };
+            for position in track.positions_mut().iter_mut() {
+                *position -= reference_position;
+            }
+            for rotation in track.rotations_mut().iter_mut() {
+                *rotation = (inverse_reference * *rotation).normalize();
+            }
+        }
+
+        for track in self.morph_tracks.iter_mut() {
+            let reference_weight = match reference_frame {
+                Some(reference_frame) => track.sample(&self.curve_table, reference_frame),
+                None => continue,
+            };
+            for weight in track.weights_mut().iter_mut() {
+                *weight -= reference_weight;
+            }
+        }
+        true
     }
 
     #[inline]
@@ -46,4 +291,22 @@ impl MmdAnimation {
     pub(crate) fn property_track_mut(&mut self) -> &mut MmdPropertyAnimationTrack {
         &mut self.property_track
     }
+
+    pub(crate) fn end_frame(&self) -> u32 {
+        let (bone_end_frame, movable_bone_end_frame) = match &self.compressed_tracks {
+            Some(compressed_tracks) => (
+                compressed_tracks.bone_tracks.iter().map(|track| track.end_frame()).max().unwrap_or(0),
+                compressed_tracks.movable_bone_tracks.iter().map(|track| track.end_frame()).max().unwrap_or(0),
+            ),
+            None => (
+                self.bone_tracks.iter().map(|track| track.end_frame()).max().unwrap_or(0),
+                self.movable_bone_tracks.iter().map(|track| track.end_frame()).max().unwrap_or(0),
+            ),
+        };
+        let morph_end_frame = self.morph_tracks.iter().map(|track| track.end_frame()).max().unwrap_or(0);
+        bone_end_frame
+            .max(movable_bone_end_frame)
+            .max(morph_end_frame)
+            .max(self.property_track.end_frame())
+    }
 }
@@ -2,6 +2,8 @@ use glam::{Quat, Vec3};
 
 use crate::unchecked_slice::{UncheckedSlice, UncheckedSliceMut};
 
+use super::bezier_interpolation::{split_bezier, BezierCurveTable, LINEAR_CURVE};
+
 #[repr(C)]
 #[derive(Clone)]
 pub(crate) struct InterpolationScalar {
@@ -20,6 +22,112 @@ impl InterpolationScalar {
             y2: 107,
         }
     }
+
+    // [x1, x2, y1, y2] in the memory order of the struct
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [x1, x2, y1, y2] => Some(Self {
+                x1: *x1,
+                x2: *x2,
+                y1: *y1,
+                y2: *y2,
+            }),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn curve_index(&self, curve_table: &mut BezierCurveTable) -> u32 {
+        curve_table.curve_index(self.x1, self.x2, self.y1, self.y2)
+    }
+
+    // returns the curve of the segment before gradient and keeps the curve after it,
+    // so a keyframe inserted at gradient does not change the motion beyond 7-bit precision
+    pub(crate) fn split_at(&mut self, gradient: f32) -> Self {
+        if self.x1 == self.y1 && self.x2 == self.y2 {
+            return self.clone();
+        }
+
+        let (before, after) = split_bezier(
+            self.x1 as f32 / 127.0,
+            self.x2 as f32 / 127.0,
+            self.y1 as f32 / 127.0,
+            self.y2 as f32 / 127.0,
+            gradient,
+        );
+        let quantize = |[x1, x2, y1, y2]: [f32; 4]| {
+            let quantize = |value: f32| (value * 127.0).round() as u8;
+            Self {
+                x1: quantize(x1),
+                x2: quantize(x2),
+                y1: quantize(y1),
+                y2: quantize(y2),
+            }
+        };
+        *self = after.map(quantize).unwrap_or_else(Self::new);
+        before.map(quantize).unwrap_or_else(Self::new)
+    }
+}
+
+// gradient of frame_number in the segment ending at the keyframe at index, None outside of the keyframes
+fn segment_gradient(frame_numbers: &[u32], index: usize, frame_number: u32) -> Option<f32> {
+    if index == 0 || frame_numbers.len() <= index {
+        return None;
+    }
+    let frame_number_a = frame_numbers[index - 1] as f32;
+    let frame_number_b = frame_numbers[index] as f32;
+    Some((frame_number as f32 - frame_number_a) / (frame_number_b - frame_number_a))
+}
+
+// returns (frame_index_a, frame_index_b, gradient) for sampling without a cached track state
+pub(crate) fn find_frame_interval(frame_numbers: &[u32], frame_time: f32) -> (usize, usize, f32) {
+    let upper_bound = frame_numbers.partition_point(|frame_number| *frame_number as f32 <= frame_time);
+    if upper_bound == 0 {
+        return (0, 0, 0.0);
+    }
+    if upper_bound == frame_numbers.len() {
+        return (upper_bound - 1, upper_bound - 1, 0.0);
+    }
+
+    let frame_number_a = frame_numbers[upper_bound - 1] as f32;
+    let frame_number_b = frame_numbers[upper_bound] as f32;
+    (upper_bound - 1, upper_bound, (frame_time - frame_number_a) / (frame_number_b - frame_number_a))
+}
+
+// index of the keyframe at frame_number, or the index it would be inserted at
+#[inline]
+fn keyframe_index(frame_numbers: &[u32], frame_number: u32) -> Result<usize, usize> {
+    let index = frame_numbers.partition_point(|keyframe_number| *keyframe_number < frame_number);
+    if frame_numbers.get(index) == Some(&frame_number) { Ok(index) } else { Err(index) }
+}
+
+// index the keyframe at index takes once it is moved to frame_number, None if another keyframe is there
+fn moved_keyframe_index(frame_numbers: &[u32], index: usize, frame_number: u32) -> Option<usize> {
+    match keyframe_index(frame_numbers, frame_number) {
+        Ok(target) => if target == index { Some(index) } else { None },
+        Err(target) => Some(if index < target { target - 1 } else { target }),
+    }
+}
+
+fn insert_value<T>(values: &mut Box<[T]>, index: usize, value: T) {
+    let mut vec = std::mem::take(values).into_vec();
+    vec.insert(index, value);
+    *values = vec.into_boxed_slice();
+}
+
+fn remove_value<T>(values: &mut Box<[T]>, index: usize) {
+    let mut vec = std::mem::take(values).into_vec();
+    vec.remove(index);
+    *values = vec.into_boxed_slice();
+}
+
+#[inline]
+fn move_value<T>(values: &mut [T], from: usize, to: usize) {
+    if from < to {
+        values[from..=to].rotate_left(1);
+    } else {
+        values[to..=from].rotate_right(1);
+    }
 }
 
 #[repr(C)]
@@ -38,12 +146,46 @@ impl InterpolationVector3 {
             z: InterpolationScalar::new(),
         }
     }
+
+    pub(crate) fn split_at(&mut self, gradient: f32) -> Self {
+        Self {
+            x: self.x.split_at(gradient),
+            y: self.y.split_at(gradient),
+            z: self.z.split_at(gradient),
+        }
+    }
 }
 
+#[repr(C)]
+#[derive(Clone)]
+pub(crate) struct InterpolationCamera {
+    pub(crate) x: InterpolationScalar,
+    pub(crate) y: InterpolationScalar,
+    pub(crate) z: InterpolationScalar,
+    pub(crate) rotation: InterpolationScalar,
+    pub(crate) distance: InterpolationScalar,
+    pub(crate) fov: InterpolationScalar,
+}
+
+impl InterpolationCamera {
+    pub(crate) fn new() -> Self {
+        Self {
+            x: InterpolationScalar::new(),
+            y: InterpolationScalar::new(),
+            z: InterpolationScalar::new(),
+            rotation: InterpolationScalar::new(),
+            distance: InterpolationScalar::new(),
+            fov: InterpolationScalar::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct MmdBoneAnimationTrack {
     pub(crate) frame_numbers: Box<[u32]>,
     rotations: Box<[Quat]>,
     rotation_interpolations: Box<[InterpolationScalar]>,
+    rotation_curves: Box<[u32]>,
 }
 
 impl MmdBoneAnimationTrack {
@@ -52,7 +194,74 @@ impl MmdBoneAnimationTrack {
             frame_numbers: vec![0; frame_count].into_boxed_slice(),
             rotations: vec![Quat::IDENTITY; frame_count].into_boxed_slice(),
             rotation_interpolations: vec![InterpolationScalar::new(); frame_count].into_boxed_slice(),
+            rotation_curves: vec![LINEAR_CURVE; frame_count].into_boxed_slice(),
+        }
+    }
+
+    pub(crate) fn build_curves(&mut self, curve_table: &mut BezierCurveTable) {
+        for (curve, interpolation) in self.rotation_curves.iter_mut().zip(self.rotation_interpolations.iter()) {
+            *curve = interpolation.curve_index(curve_table);
+        }
+    }
+
+    // keyframes are (source track, source keyframe index, new frame number) in frame order
+    pub(crate) fn from_keyframes(keyframes: &[(&Self, usize, u32)]) -> Self {
+        let mut track = Self::new(keyframes.len());
+        for (i, (source, index, frame_number)) in keyframes.iter().enumerate() {
+            track.frame_numbers[i] = *frame_number;
+            track.rotations[i] = source.rotations[*index];
+            track.rotation_interpolations[i] = source.rotation_interpolations[*index].clone();
         }
+        track
+    }
+
+    // inserts a keyframe holding the sampled rotation and splits the segment curve at it, returns the index of the keyframe at frame_number
+    pub(crate) fn insert_keyframe(&mut self, curve_table: &BezierCurveTable, frame_number: u32) -> usize {
+        let index = match keyframe_index(&self.frame_numbers, frame_number) {
+            Ok(index) => return index,
+            Err(index) => index,
+        };
+        let rotation = self.sample_rotation(curve_table, frame_number as f32);
+        let rotation_interpolation = match segment_gradient(&self.frame_numbers, index, frame_number) {
+            Some(gradient) => self.rotation_interpolations[index].split_at(gradient),
+            None => InterpolationScalar::new(),
+        };
+        insert_value(&mut self.frame_numbers, index, frame_number);
+        insert_value(&mut self.rotations, index, rotation);
+        insert_value(&mut self.rotation_interpolations, index, rotation_interpolation);
+        insert_value(&mut self.rotation_curves, index, LINEAR_CURVE);
+        index
+    }
+
+    // the runtime expects at least one keyframe, so the last keyframe is kept
+    pub(crate) fn remove_keyframe(&mut self, index: usize) -> bool {
+        if self.frame_numbers.len() <= index || self.frame_numbers.len() == 1 {
+            return false;
+        }
+        remove_value(&mut self.frame_numbers, index);
+        remove_value(&mut self.rotations, index);
+        remove_value(&mut self.rotation_interpolations, index);
+        remove_value(&mut self.rotation_curves, index);
+        true
+    }
+
+    // returns the new index of the keyframe
+    pub(crate) fn move_keyframe(&mut self, index: usize, frame_number: u32) -> Option<usize> {
+        if self.frame_numbers.len() <= index {
+            return None;
+        }
+        let new_index = moved_keyframe_index(&self.frame_numbers, index, frame_number)?;
+        move_value(&mut self.frame_numbers, index, new_index);
+        self.frame_numbers[new_index] = frame_number;
+        move_value(&mut self.rotations, index, new_index);
+        move_value(&mut self.rotation_interpolations, index, new_index);
+        move_value(&mut self.rotation_curves, index, new_index);
+        Some(new_index)
+    }
+
+    #[inline]
+    pub(crate) fn rotation_curves(&self) -> UncheckedSlice<'_, u32> {
+        UncheckedSlice::new(&self.rotation_curves)
     }
 
     #[inline]
@@ -84,14 +293,28 @@ impl MmdBoneAnimationTrack {
     pub(crate) fn end_frame(&self) -> u32 {
         self.frame_numbers.last().copied().unwrap_or(0)
     }
+
+    // the curves must be built with curve_table
+    pub(crate) fn sample_rotation(&self, curve_table: &BezierCurveTable, frame_time: f32) -> Quat {
+        if self.frame_numbers.is_empty() {
+            return Quat::IDENTITY;
+        }
+
+        let (frame_index_a, frame_index_b, gradient) = find_frame_interval(&self.frame_numbers, frame_time);
+        let weight = curve_table.evaluate(self.rotation_curves[frame_index_b], gradient);
+        self.rotations[frame_index_a].slerp(self.rotations[frame_index_b], weight)
+    }
 }
 
+#[derive(Clone)]
 pub(crate) struct MmdMovableBoneAnimationTrack {
     pub(crate) frame_numbers: Box<[u32]>,
     positions: Box<[Vec3]>,
     position_interpolations: Box<[InterpolationVector3]>,
     rotations: Box<[Quat]>,
     rotation_interpolations: Box<[InterpolationScalar]>,
+    position_curves: Box<[[u32; 3]]>,
+    rotation_curves: Box<[u32]>,
 }
 
 impl MmdMovableBoneAnimationTrack {
@@ -102,9 +325,112 @@ impl MmdMovableBoneAnimationTrack {
             position_interpolations: vec![InterpolationVector3::new(); frame_count].into_boxed_slice(),
             rotations: vec![Quat::IDENTITY; frame_count].into_boxed_slice(),
             rotation_interpolations: vec![InterpolationScalar::new(); frame_count].into_boxed_slice(),
+            position_curves: vec![[LINEAR_CURVE; 3]; frame_count].into_boxed_slice(),
+            rotation_curves: vec![LINEAR_CURVE; frame_count].into_boxed_slice(),
         }
     }
 
+    pub(crate) fn build_curves(&mut self, curve_table: &mut BezierCurveTable) {
+        for (curves, interpolation) in self.position_curves.iter_mut().zip(self.position_interpolations.iter()) {
+            *curves = [
+                interpolation.x.curve_index(curve_table),
+                interpolation.y.curve_index(curve_table),
+                interpolation.z.curve_index(curve_table),
+            ];
+        }
+        for (curve, interpolation) in self.rotation_curves.iter_mut().zip(self.rotation_interpolations.iter()) {
+            *curve = interpolation.curve_index(curve_table);
+        }
+    }
+
+    // keyframes are (source track, source keyframe index, new frame number) in frame order
+    pub(crate) fn from_keyframes(keyframes: &[(&Self, usize, u32)]) -> Self {
+        let mut track = Self::new(keyframes.len());
+        for (i, (source, index, frame_number)) in keyframes.iter().enumerate() {
+            track.frame_numbers[i] = *frame_number;
+            track.positions[i] = source.positions[*index];
+            track.position_interpolations[i] = source.position_interpolations[*index].clone();
+            track.rotations[i] = source.rotations[*index];
+            track.rotation_interpolations[i] = source.rotation_interpolations[*index].clone();
+        }
+        track
+    }
+
+    // the rotation keyframes of a bone track, holding the rest position
+    pub(crate) fn from_bone_track(bone_track: &MmdBoneAnimationTrack) -> Self {
+        let mut track = Self::new(bone_track.frame_numbers.len());
+        track.frame_numbers.copy_from_slice(&bone_track.frame_numbers);
+        track.rotations.copy_from_slice(&bone_track.rotations);
+        track.rotation_interpolations.clone_from_slice(&bone_track.rotation_interpolations);
+        track
+    }
+
+    // inserts a keyframe holding the sampled position and rotation and splits the segment curve at it, returns the index of the keyframe at frame_number
+    pub(crate) fn insert_keyframe(&mut self, curve_table: &BezierCurveTable, frame_number: u32) -> usize {
+        let index = match keyframe_index(&self.frame_numbers, frame_number) {
+            Ok(index) => return index,
+            Err(index) => index,
+        };
+        let (position, rotation) = self.sample(curve_table, frame_number as f32);
+        let (position_interpolation, rotation_interpolation) = match segment_gradient(&self.frame_numbers, index, frame_number) {
+            Some(gradient) => (
+                self.position_interpolations[index].split_at(gradient),
+                self.rotation_interpolations[index].split_at(gradient),
+            ),
+            None => (InterpolationVector3::new(), InterpolationScalar::new()),
+        };
+        insert_value(&mut self.frame_numbers, index, frame_number);
+        insert_value(&mut self.positions, index, position);
+        insert_value(&mut self.position_interpolations, index, position_interpolation);
+        insert_value(&mut self.rotations, index, rotation);
+        insert_value(&mut self.rotation_interpolations, index, rotation_interpolation);
+        insert_value(&mut self.position_curves, index, [LINEAR_CURVE; 3]);
+        insert_value(&mut self.rotation_curves, index, LINEAR_CURVE);
+        index
+    }
+
+    // the runtime expects at least one keyframe, so the last keyframe is kept
+    pub(crate) fn remove_keyframe(&mut self, index: usize) -> bool {
+        if self.frame_numbers.len() <= index || self.frame_numbers.len() == 1 {
+            return false;
+        }
+        remove_value(&mut self.frame_numbers, index);
+        remove_value(&mut self.positions, index);
+        remove_value(&mut self.position_interpolations, index);
+        remove_value(&mut self.rotations, index);
+        remove_value(&mut self.rotation_interpolations, index);
+        remove_value(&mut self.position_curves, index);
+        remove_value(&mut self.rotation_curves, index);
+        true
+    }
+
+    // returns the new index of the keyframe
+    pub(crate) fn move_keyframe(&mut self, index: usize, frame_number: u32) -> Option<usize> {
+        if self.frame_numbers.len() <= index {
+            return None;
+        }
+        let new_index = moved_keyframe_index(&self.frame_numbers, index, frame_number)?;
+        move_value(&mut self.frame_numbers, index, new_index);
+        self.frame_numbers[new_index] = frame_number;
+        move_value(&mut self.positions, index, new_index);
+        move_value(&mut self.position_interpolations, index, new_index);
+        move_value(&mut self.rotations, index, new_index);
+        move_value(&mut self.rotation_interpolations, index, new_index);
+        move_value(&mut self.position_curves, index, new_index);
+        move_value(&mut self.rotation_curves, index, new_index);
+        Some(new_index)
+    }
+
+    #[inline]
+    pub(crate) fn position_curves(&self) -> UncheckedSlice<'_, [u32; 3]> {
+        UncheckedSlice::new(&self.position_curves)
+    }
+
+    #[inline]
+    pub(crate) fn rotation_curves(&self) -> UncheckedSlice<'_, u32> {
+        UncheckedSlice::new(&self.rotation_curves)
+    }
+
     #[inline]
     pub(crate) fn positions(&self) -> UncheckedSlice<Vec3> {
         UncheckedSlice::new(&self.positions)
@@ -154,11 +480,35 @@ impl MmdMovableBoneAnimationTrack {
     pub(crate) fn end_frame(&self) -> u32 {
         self.frame_numbers.last().copied().unwrap_or(0)
     }
+
+    // position is the offset from the bone rest position, the curves must be built with curve_table
+    pub(crate) fn sample(&self, curve_table: &BezierCurveTable, frame_time: f32) -> (Vec3, Quat) {
+        if self.frame_numbers.is_empty() {
+            return (Vec3::ZERO, Quat::IDENTITY);
+        }
+
+        let (frame_index_a, frame_index_b, gradient) = find_frame_interval(&self.frame_numbers, frame_time);
+        let [x, y, z] = self.position_curves[frame_index_b];
+        let position_a = self.positions[frame_index_a];
+        let position_b = self.positions[frame_index_b];
+        let position = Vec3::new(
+            position_a.x + (position_b.x - position_a.x) * curve_table.evaluate(x, gradient),
+            position_a.y + (position_b.y - position_a.y) * curve_table.evaluate(y, gradient),
+            position_a.z + (position_b.z - position_a.z) * curve_table.evaluate(z, gradient),
+        );
+
+        let weight = curve_table.evaluate(self.rotation_curves[frame_index_b], gradient);
+        let rotation = self.rotations[frame_index_a].slerp(self.rotations[frame_index_b], weight);
+        (position, rotation)
+    }
 }
 
+#[derive(Clone)]
 pub(crate) struct MmdMorphAnimationTrack {
     pub(crate) frame_numbers: Box<[u32]>,
     weights: Box<[f32]>,
+    weight_interpolations: Option<Box<[InterpolationScalar]>>, // linear when absent
+    weight_curves: Option<Box<[u32]>>,
 }
 
 impl MmdMorphAnimationTrack {
@@ -166,7 +516,101 @@ impl MmdMorphAnimationTrack {
         Self {
             frame_numbers: vec![0; frame_count].into_boxed_slice(),
             weights: vec![0.0; frame_count].into_boxed_slice(),
+            weight_interpolations: None,
+            weight_curves: None,
+        }
+    }
+
+    pub(crate) fn build_curves(&mut self, curve_table: &mut BezierCurveTable) {
+        self.weight_curves = self.weight_interpolations.as_ref().map(|weight_interpolations| {
+            weight_interpolations.iter().map(|interpolation| interpolation.curve_index(curve_table)).collect()
+        });
+    }
+
+    // keyframes are (source track, source keyframe index, new frame number) in frame order
+    pub(crate) fn from_keyframes(keyframes: &[(&Self, usize, u32)]) -> Self {
+        let mut track = Self::new(keyframes.len());
+        for (i, (source, index, frame_number)) in keyframes.iter().enumerate() {
+            track.frame_numbers[i] = *frame_number;
+            track.weights[i] = source.weights[*index];
+        }
+        if keyframes.iter().any(|(source, _, _)| source.weight_interpolations.is_some()) {
+            let mut weight_interpolations = track.weight_interpolations_mut();
+            for (i, (source, index, _)) in keyframes.iter().enumerate() {
+                if let Some(source_weight_interpolations) = &source.weight_interpolations {
+                    weight_interpolations[i as u32] = source_weight_interpolations[*index].clone();
+                }
+            }
+        }
+        track
+    }
+
+    // inserts a keyframe holding the sampled weight and splits the segment curve at it, returns the index of the keyframe at frame_number
+    pub(crate) fn insert_keyframe(&mut self, curve_table: &BezierCurveTable, frame_number: u32) -> usize {
+        let index = match keyframe_index(&self.frame_numbers, frame_number) {
+            Ok(index) => return index,
+            Err(index) => index,
+        };
+        let weight = self.sample(curve_table, frame_number as f32);
+        let gradient = segment_gradient(&self.frame_numbers, index, frame_number);
+        insert_value(&mut self.frame_numbers, index, frame_number);
+        insert_value(&mut self.weights, index, weight);
+        if let Some(weight_interpolations) = &mut self.weight_interpolations {
+            let weight_interpolation = match gradient {
+                Some(gradient) => weight_interpolations[index].split_at(gradient),
+                None => InterpolationScalar::new(),
+            };
+            insert_value(weight_interpolations, index, weight_interpolation);
+        }
+        if let Some(weight_curves) = &mut self.weight_curves {
+            insert_value(weight_curves, index, LINEAR_CURVE);
+        }
+        index
+    }
+
+    // the runtime expects at least one keyframe, so the last keyframe is kept
+    pub(crate) fn remove_keyframe(&mut self, index: usize) -> bool {
+        if self.frame_numbers.len() <= index || self.frame_numbers.len() == 1 {
+            return false;
+        }
+        remove_value(&mut self.frame_numbers, index);
+        remove_value(&mut self.weights, index);
+        if let Some(weight_interpolations) = &mut self.weight_interpolations {
+            remove_value(weight_interpolations, index);
+        }
+        if let Some(weight_curves) = &mut self.weight_curves {
+            remove_value(weight_curves, index);
+        }
+        true
+    }
+
+    // returns the new index of the keyframe
+    pub(crate) fn move_keyframe(&mut self, index: usize, frame_number: u32) -> Option<usize> {
+        if self.frame_numbers.len() <= index {
+            return None;
+        }
+        let new_index = moved_keyframe_index(&self.frame_numbers, index, frame_number)?;
+        move_value(&mut self.frame_numbers, index, new_index);
+        self.frame_numbers[new_index] = frame_number;
+        move_value(&mut self.weights, index, new_index);
+        if let Some(weight_interpolations) = &mut self.weight_interpolations {
+            move_value(weight_interpolations, index, new_index);
+        }
+        if let Some(weight_curves) = &mut self.weight_curves {
+            move_value(weight_curves, index, new_index);
         }
+        Some(new_index)
+    }
+
+    // linear without curves, the curves must be built with curve_table
+    #[inline]
+    pub(crate) fn interpolate_weight_with_curves(&self, curve_table: &BezierCurveTable, frame_index_a: u32, frame_index_b: u32, gradient: f32) -> f32 {
+        let weight = match &self.weight_curves {
+            Some(weight_curves) => curve_table.evaluate(weight_curves[frame_index_b as usize], gradient),
+            None => gradient,
+        };
+        let weights = self.weights();
+        weights[frame_index_a] + (weights[frame_index_b] - weights[frame_index_a]) * weight
     }
 
     #[inline]
@@ -179,6 +623,14 @@ impl MmdMorphAnimationTrack {
         UncheckedSliceMut::new(&mut self.weights)
     }
 
+    // allocates linear interpolations on first use
+    pub(crate) fn weight_interpolations_mut(&mut self) -> UncheckedSliceMut<'_, InterpolationScalar> {
+        let frame_count = self.frame_numbers.len();
+        let weight_interpolations = self.weight_interpolations
+            .get_or_insert_with(|| vec![InterpolationScalar::new(); frame_count].into_boxed_slice());
+        UncheckedSliceMut::new(weight_interpolations)
+    }
+
     #[inline]
     pub(crate) fn start_frame(&self) -> u32 {
         self.frame_numbers.first().copied().unwrap_or(0)
@@ -188,10 +640,20 @@ impl MmdMorphAnimationTrack {
     pub(crate) fn end_frame(&self) -> u32 {
         self.frame_numbers.last().copied().unwrap_or(0)
     }
+
+    pub(crate) fn sample(&self, curve_table: &BezierCurveTable, frame_time: f32) -> f32 {
+        if self.frame_numbers.is_empty() {
+            return 0.0;
+        }
+
+        let (frame_index_a, frame_index_b, gradient) = find_frame_interval(&self.frame_numbers, frame_time);
+        self.interpolate_weight_with_curves(curve_table, frame_index_a as u32, frame_index_b as u32, gradient)
+    }
 }
 
 pub(crate) struct MmdPropertyAnimationTrack {
     pub(crate) frame_numbers: Box<[u32]>,
+    visibilities: Box<[u8]>,
     ik_states: Box<[Box<[u8]>]>,
 }
 
@@ -199,10 +661,65 @@ impl MmdPropertyAnimationTrack {
     pub(crate) fn new(frame_count: usize, ik_count: usize) -> Self {
         Self {
             frame_numbers: vec![0; frame_count].into_boxed_slice(),
+            visibilities: vec![1; frame_count].into_boxed_slice(),
             ik_states: vec![vec![1; frame_count].into_boxed_slice(); ik_count].into_boxed_slice(),
         }
     }
 
+    // inserts a keyframe holding the previous visibility and ik states, returns the index of the keyframe at frame_number
+    pub(crate) fn insert_keyframe(&mut self, frame_number: u32) -> usize {
+        let index = match keyframe_index(&self.frame_numbers, frame_number) {
+            Ok(index) => return index,
+            Err(index) => index,
+        };
+        let previous = index.checked_sub(1);
+        insert_value(&mut self.frame_numbers, index, frame_number);
+        let visibility = previous.map(|previous| self.visibilities[previous]).unwrap_or(1);
+        insert_value(&mut self.visibilities, index, visibility);
+        for ik_states in self.ik_states.iter_mut() {
+            let ik_state = previous.map(|previous| ik_states[previous]).unwrap_or(1);
+            insert_value(ik_states, index, ik_state);
+        }
+        index
+    }
+
+    pub(crate) fn remove_keyframe(&mut self, index: usize) -> bool {
+        if self.frame_numbers.len() <= index {
+            return false;
+        }
+        remove_value(&mut self.frame_numbers, index);
+        remove_value(&mut self.visibilities, index);
+        for ik_states in self.ik_states.iter_mut() {
+            remove_value(ik_states, index);
+        }
+        true
+    }
+
+    // returns the new index of the keyframe
+    pub(crate) fn move_keyframe(&mut self, index: usize, frame_number: u32) -> Option<usize> {
+        if self.frame_numbers.len() <= index {
+            return None;
+        }
+        let new_index = moved_keyframe_index(&self.frame_numbers, index, frame_number)?;
+        move_value(&mut self.frame_numbers, index, new_index);
+        self.frame_numbers[new_index] = frame_number;
+        move_value(&mut self.visibilities, index, new_index);
+        for ik_states in self.ik_states.iter_mut() {
+            move_value(ik_states, index, new_index);
+        }
+        Some(new_index)
+    }
+
+    #[inline]
+    pub(crate) fn visibilities(&self) -> UncheckedSlice<'_, u8> {
+        UncheckedSlice::new(&self.visibilities)
+    }
+
+    #[inline]
+    pub(crate) fn visibilities_mut(&mut self) -> UncheckedSliceMut<'_, u8> {
+        UncheckedSliceMut::new(&mut self.visibilities)
+    }
+
     #[inline]
     pub(crate) fn ik_count(&self) -> usize {
         self.ik_states.len()
@@ -228,3 +745,320 @@ impl MmdPropertyAnimationTrack {
         self.frame_numbers.last().copied().unwrap_or(0)
     }
 }
+
+pub(crate) struct MmdCameraAnimationTrack {
+    pub(crate) frame_numbers: Box<[u32]>,
+    distances: Box<[f32]>,
+    positions: Box<[Vec3]>,
+    rotations: Box<[Vec3]>, // euler angles
+    interpolations: Box<[InterpolationCamera]>,
+    fovs: Box<[f32]>,
+    is_perspectives: Box<[u8]>,
+    curves: Box<[[u32; 6]]>, // x, y, z, rotation, distance, fov
+}
+
+impl MmdCameraAnimationTrack {
+    pub(crate) fn new(frame_count: usize) -> Self {
+        Self {
+            frame_numbers: vec![0; frame_count].into_boxed_slice(),
+            distances: vec![0.0; frame_count].into_boxed_slice(),
+            positions: vec![Vec3::ZERO; frame_count].into_boxed_slice(),
+            rotations: vec![Vec3::ZERO; frame_count].into_boxed_slice(),
+            interpolations: vec![InterpolationCamera::new(); frame_count].into_boxed_slice(),
+            fovs: vec![0.0; frame_count].into_boxed_slice(),
+            is_perspectives: vec![1; frame_count].into_boxed_slice(),
+            curves: vec![[LINEAR_CURVE; 6]; frame_count].into_boxed_slice(),
+        }
+    }
+
+    pub(crate) fn build_curves(&mut self, curve_table: &mut BezierCurveTable) {
+        for (curves, interpolation) in self.curves.iter_mut().zip(self.interpolations.iter()) {
+            let InterpolationCamera {x, y, z, rotation, distance, fov} = interpolation;
+            *curves = [
+                x.curve_index(curve_table),
+                y.curve_index(curve_table),
+                z.curve_index(curve_table),
+                rotation.curve_index(curve_table),
+                distance.curve_index(curve_table),
+                fov.curve_index(curve_table),
+            ];
+        }
+    }
+
+    #[inline]
+    pub(crate) fn distances(&self) -> UncheckedSlice<'_, f32> {
+        UncheckedSlice::new(&self.distances)
+    }
+
+    #[inline]
+    pub(crate) fn distances_mut(&mut self) -> UncheckedSliceMut<'_, f32> {
+        UncheckedSliceMut::new(&mut self.distances)
+    }
+
+    #[inline]
+    pub(crate) fn positions(&self) -> UncheckedSlice<'_, Vec3> {
+        UncheckedSlice::new(&self.positions)
+    }
+
+    #[inline]
+    pub(crate) fn positions_mut(&mut self) -> UncheckedSliceMut<'_, Vec3> {
+        UncheckedSliceMut::new(&mut self.positions)
+    }
+
+    #[inline]
+    pub(crate) fn rotations(&self) -> UncheckedSlice<'_, Vec3> {
+        UncheckedSlice::new(&self.rotations)
+    }
+
+    #[inline]
+    pub(crate) fn rotations_mut(&mut self) -> UncheckedSliceMut<'_, Vec3> {
+        UncheckedSliceMut::new(&mut self.rotations)
+    }
+
+    #[inline]
+    pub(crate) fn interpolations_mut(&mut self) -> UncheckedSliceMut<'_, InterpolationCamera> {
+        UncheckedSliceMut::new(&mut self.interpolations)
+    }
+
+    #[inline]
+    pub(crate) fn curves(&self) -> UncheckedSlice<'_, [u32; 6]> {
+        UncheckedSlice::new(&self.curves)
+    }
+
+    #[inline]
+    pub(crate) fn fovs(&self) -> UncheckedSlice<'_, f32> {
+        UncheckedSlice::new(&self.fovs)
+    }
+
+    #[inline]
+    pub(crate) fn fovs_mut(&mut self) -> UncheckedSliceMut<'_, f32> {
+        UncheckedSliceMut::new(&mut self.fovs)
+    }
+
+    #[inline]
+    pub(crate) fn is_perspectives(&self) -> UncheckedSlice<'_, u8> {
+        UncheckedSlice::new(&self.is_perspectives)
+    }
+
+    #[inline]
+    pub(crate) fn is_perspectives_mut(&mut self) -> UncheckedSliceMut<'_, u8> {
+        UncheckedSliceMut::new(&mut self.is_perspectives)
+    }
+
+    #[inline]
+    pub(crate) fn start_frame(&self) -> u32 {
+        self.frame_numbers.first().copied().unwrap_or(0)
+    }
+
+    #[inline]
+    pub(crate) fn end_frame(&self) -> u32 {
+        self.frame_numbers.last().copied().unwrap_or(0)
+    }
+}
+
+pub(crate) struct MmdLightAnimationTrack {
+    pub(crate) frame_numbers: Box<[u32]>,
+    colors: Box<[Vec3]>,
+    directions: Box<[Vec3]>,
+}
+
+impl MmdLightAnimationTrack {
+    pub(crate) fn new(frame_count: usize) -> Self {
+        Self {
+            frame_numbers: vec![0; frame_count].into_boxed_slice(),
+            colors: vec![Vec3::ZERO; frame_count].into_boxed_slice(),
+            directions: vec![Vec3::ZERO; frame_count].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn colors(&self) -> UncheckedSlice<'_, Vec3> {
+        UncheckedSlice::new(&self.colors)
+    }
+
+    #[inline]
+    pub(crate) fn colors_mut(&mut self) -> UncheckedSliceMut<'_, Vec3> {
+        UncheckedSliceMut::new(&mut self.colors)
+    }
+
+    #[inline]
+    pub(crate) fn directions(&self) -> UncheckedSlice<'_, Vec3> {
+        UncheckedSlice::new(&self.directions)
+    }
+
+    #[inline]
+    pub(crate) fn directions_mut(&mut self) -> UncheckedSliceMut<'_, Vec3> {
+        UncheckedSliceMut::new(&mut self.directions)
+    }
+
+    #[inline]
+    pub(crate) fn start_frame(&self) -> u32 {
+        self.frame_numbers.first().copied().unwrap_or(0)
+    }
+
+    #[inline]
+    pub(crate) fn end_frame(&self) -> u32 {
+        self.frame_numbers.last().copied().unwrap_or(0)
+    }
+}
+
+pub(crate) struct MmdSelfShadowAnimationTrack {
+    pub(crate) frame_numbers: Box<[u32]>,
+    modes: Box<[u8]>,
+    distances: Box<[f32]>,
+}
+
+impl MmdSelfShadowAnimationTrack {
+    pub(crate) fn new(frame_count: usize) -> Self {
+        Self {
+            frame_numbers: vec![0; frame_count].into_boxed_slice(),
+            modes: vec![0; frame_count].into_boxed_slice(),
+            distances: vec![0.0; frame_count].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn modes(&self) -> UncheckedSlice<'_, u8> {
+        UncheckedSlice::new(&self.modes)
+    }
+
+    #[inline]
+    pub(crate) fn modes_mut(&mut self) -> UncheckedSliceMut<'_, u8> {
+        UncheckedSliceMut::new(&mut self.modes)
+    }
+
+    #[inline]
+    pub(crate) fn distances(&self) -> UncheckedSlice<'_, f32> {
+        UncheckedSlice::new(&self.distances)
+    }
+
+    #[inline]
+    pub(crate) fn distances_mut(&mut self) -> UncheckedSliceMut<'_, f32> {
+        UncheckedSliceMut::new(&mut self.distances)
+    }
+
+    #[inline]
+    pub(crate) fn start_frame(&self) -> u32 {
+        self.frame_numbers.first().copied().unwrap_or(0)
+    }
+
+    #[inline]
+    pub(crate) fn end_frame(&self) -> u32 {
+        self.frame_numbers.last().copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3};
+
+    use super::{InterpolationScalar, MmdBoneAnimationTrack, MmdMorphAnimationTrack, MmdMovableBoneAnimationTrack};
+    use crate::animation::bezier_interpolation::BezierCurveTable;
+
+    fn ease(x1: u8, x2: u8, y1: u8, y2: u8) -> InterpolationScalar {
+        InterpolationScalar { x1, x2, y1, y2 }
+    }
+
+    fn sample_frames() -> impl Iterator<Item = f32> {
+        (0..=70).map(|i| i as f32 * 0.5)
+    }
+
+    #[test]
+    fn inserted_keyframes_keep_the_motion() {
+        let mut curve_table = BezierCurveTable::new();
+
+        let mut bone_track = MmdBoneAnimationTrack::new(2);
+        bone_track.frame_numbers[1] = 30;
+        bone_track.rotations_mut()[1] = Quat::from_rotation_y(1.5);
+        bone_track.rotation_interpolations_mut()[1] = ease(90, 40, 10, 120);
+        bone_track.build_curves(&mut curve_table);
+
+        let mut movable_bone_track = MmdMovableBoneAnimationTrack::new(2);
+        movable_bone_track.frame_numbers[1] = 30;
+        movable_bone_track.positions_mut()[1] = Vec3::new(4.0, -2.0, 1.0);
+        movable_bone_track.position_interpolations_mut()[1].x = ease(100, 30, 0, 127);
+        movable_bone_track.position_interpolations_mut()[1].y = ease(10, 30, 100, 120);
+        movable_bone_track.rotations_mut()[1] = Quat::from_rotation_x(-1.0);
+        movable_bone_track.rotation_interpolations_mut()[1] = ease(64, 64, 0, 127);
+        movable_bone_track.build_curves(&mut curve_table);
+
+        let mut morph_track = MmdMorphAnimationTrack::new(2);
+        morph_track.frame_numbers[1] = 30;
+        morph_track.weights_mut()[1] = 1.0;
+        morph_track.weight_interpolations_mut()[1] = ease(20, 20, 107, 107);
+        morph_track.build_curves(&mut curve_table);
+
+        let bone_rotations: Vec<_> = sample_frames().map(|frame| bone_track.sample_rotation(&curve_table, frame)).collect();
+        let movable_bone_samples: Vec<_> = sample_frames().map(|frame| movable_bone_track.sample(&curve_table, frame)).collect();
+        let morph_weights: Vec<_> = sample_frames().map(|frame| morph_track.sample(&curve_table, frame)).collect();
+
+        // inside the keyframes, then before the first and past the last one, which hold the end values
+        for frame_number in [7, 20, 19, 33] {
+            bone_track.insert_keyframe(&curve_table, frame_number);
+            bone_track.build_curves(&mut curve_table);
+            movable_bone_track.insert_keyframe(&curve_table, frame_number);
+            movable_bone_track.build_curves(&mut curve_table);
+            morph_track.insert_keyframe(&curve_table, frame_number);
+            morph_track.build_curves(&mut curve_table);
+        }
+        assert_eq!(&*bone_track.frame_numbers, &[0, 7, 19, 20, 30, 33]);
+
+        // the split curves are stored with 7-bit control points
+        for (i, frame) in sample_frames().enumerate() {
+            let rotation = bone_track.sample_rotation(&curve_table, frame);
+            assert!(rotation.angle_between(bone_rotations[i]) < 1e-2, "frame {frame}: {rotation:?}, expected {:?}", bone_rotations[i]);
+
+            let (position, rotation) = movable_bone_track.sample(&curve_table, frame);
+            let (expected_position, expected_rotation) = movable_bone_samples[i];
+            assert!(position.distance(expected_position) < 2e-2, "frame {frame}: {position:?}, expected {expected_position:?}");
+            assert!(rotation.angle_between(expected_rotation) < 1e-2, "frame {frame}: {rotation:?}, expected {expected_rotation:?}");
+
+            let weight = morph_track.sample(&curve_table, frame);
+            assert!((weight - morph_weights[i]).abs() < 1e-2, "frame {frame}: {weight}, expected {}", morph_weights[i]);
+        }
+    }
+
+    #[test]
+    fn inserting_at_a_keyframe_returns_it() {
+        let curve_table = BezierCurveTable::new();
+        let mut track = MmdBoneAnimationTrack::new(2);
+        track.frame_numbers[1] = 10;
+        track.rotations_mut()[1] = Quat::from_rotation_z(1.0);
+
+        assert_eq!(track.insert_keyframe(&curve_table, 10), 1);
+        assert_eq!(&*track.frame_numbers, &[0, 10]);
+        assert_eq!(track.rotations()[1], Quat::from_rotation_z(1.0));
+    }
+
+    #[test]
+    fn moved_and_removed_keyframes_carry_their_values() {
+        let rotations = [Quat::from_rotation_x(0.1), Quat::from_rotation_x(0.2), Quat::from_rotation_x(0.3)];
+        let mut track = MmdBoneAnimationTrack::new(3);
+        for (i, rotation) in rotations.into_iter().enumerate() {
+            track.frame_numbers[i] = i as u32 * 10;
+            track.rotations_mut()[i as u32] = rotation;
+            track.rotation_interpolations_mut()[i as u32] = ease(i as u8, 127, i as u8, 127);
+        }
+
+        // moving past other keyframes reorders them, moving onto an occupied frame fails
+        assert_eq!(track.move_keyframe(2, 5), Some(1));
+        assert_eq!(&*track.frame_numbers, &[0, 5, 10]);
+        assert_eq!(track.rotations()[1], rotations[2]);
+        assert_eq!(track.rotation_interpolations()[1].x1, 2);
+        assert_eq!(track.rotations()[2], rotations[1]);
+        assert_eq!(track.move_keyframe(0, 10), None);
+        assert_eq!(track.move_keyframe(1, 5), Some(1));
+        assert_eq!(track.move_keyframe(3, 20), None);
+
+        assert!(track.remove_keyframe(1));
+        assert_eq!(&*track.frame_numbers, &[0, 10]);
+        assert_eq!(track.rotations()[1], rotations[1]);
+        assert_eq!(track.rotation_interpolations()[1].x1, 1);
+        assert!(!track.remove_keyframe(2));
+
+        // the last keyframe is kept
+        assert!(track.remove_keyframe(0));
+        assert!(!track.remove_keyframe(0));
+        assert_eq!(&*track.frame_numbers, &[10]);
+    }
+}
@@ -0,0 +1,95 @@
+use glam::Vec3;
+
+use super::bezier_interpolation::BezierCurveTable;
+use super::mmd_animation_track::{find_frame_interval, MmdCameraAnimationTrack};
+
+#[repr(C)]
+pub(crate) struct MmdCameraState {
+    pub(crate) position: Vec3,
+    pub(crate) rotation: Vec3,
+    pub(crate) distance: f32,
+    pub(crate) fov: f32,
+    pub(crate) is_perspective: u8,
+    _padding: [u8; 3],
+}
+
+impl MmdCameraState {
+    pub(crate) fn new() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            distance: -45.0,
+            fov: 30.0_f32.to_radians(),
+            is_perspective: 1,
+            _padding: [0; 3],
+        }
+    }
+}
+
+pub(crate) struct MmdCameraAnimation {
+    track: MmdCameraAnimationTrack,
+    curve_table: BezierCurveTable,
+}
+
+impl MmdCameraAnimation {
+    pub(crate) fn new(track: MmdCameraAnimationTrack) -> Self {
+        Self {
+            track,
+            curve_table: BezierCurveTable::new(),
+        }
+    }
+
+    // the track is written after creation, so the curves are built once it is bound to the runtime
+    pub(crate) fn build_curves(&mut self) {
+        self.curve_table = BezierCurveTable::new();
+        self.track.build_curves(&mut self.curve_table);
+    }
+
+    #[inline]
+    pub(crate) fn track_mut(&mut self) -> &mut MmdCameraAnimationTrack {
+        &mut self.track
+    }
+
+    pub(crate) fn animate(&self, frame_time: f32, state: &mut MmdCameraState) {
+        let track = &self.track;
+        if track.frame_numbers.is_empty() {
+            return;
+        }
+
+        let clamped_frame_time = frame_time.clamp(track.start_frame() as f32, track.end_frame() as f32);
+        let (frame_index_a, frame_index_b, gradient) = find_frame_interval(&track.frame_numbers, clamped_frame_time);
+        let frame_index_a = frame_index_a as u32;
+        let frame_index_b = frame_index_b as u32;
+
+        // keyframes on consecutive frames are a camera cut, so there is nothing to interpolate
+        if frame_index_a == frame_index_b || track.frame_numbers[frame_index_b as usize] - track.frame_numbers[frame_index_a as usize] <= 1 {
+            state.position = track.positions()[frame_index_a];
+            state.rotation = track.rotations()[frame_index_a];
+            state.distance = track.distances()[frame_index_a];
+            state.fov = track.fovs()[frame_index_a];
+            state.is_perspective = track.is_perspectives()[frame_index_a];
+            return;
+        }
+
+        let curve_table = &self.curve_table;
+        let [x, y, z, rotation, distance, fov] = track.curves()[frame_index_b];
+
+        let position_a = track.positions()[frame_index_a];
+        let position_b = track.positions()[frame_index_b];
+        state.position = Vec3::new(
+            position_a.x + (position_b.x - position_a.x) * curve_table.evaluate(x, gradient),
+            position_a.y + (position_b.y - position_a.y) * curve_table.evaluate(y, gradient),
+            position_a.z + (position_b.z - position_a.z) * curve_table.evaluate(z, gradient),
+        );
+
+        state.rotation = track.rotations()[frame_index_a].lerp(track.rotations()[frame_index_b], curve_table.evaluate(rotation, gradient));
+
+        let distance_a = track.distances()[frame_index_a];
+        state.distance = distance_a + (track.distances()[frame_index_b] - distance_a) * curve_table.evaluate(distance, gradient);
+
+        let fov_a = track.fovs()[frame_index_a];
+        state.fov = fov_a + (track.fovs()[frame_index_b] - fov_a) * curve_table.evaluate(fov, gradient);
+
+        state.is_perspective = track.is_perspectives()[frame_index_a];
+    }
+}
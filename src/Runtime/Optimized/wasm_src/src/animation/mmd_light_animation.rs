@@ -0,0 +1,72 @@
+use glam::Vec3;
+
+use super::mmd_animation_track::{find_frame_interval, MmdLightAnimationTrack, MmdSelfShadowAnimationTrack};
+
+#[repr(C)]
+pub(crate) struct MmdLightState {
+    pub(crate) color: Vec3,
+    pub(crate) direction: Vec3,
+    pub(crate) shadow_distance: f32,
+    pub(crate) shadow_mode: u8,
+    _padding: [u8; 3],
+}
+
+impl MmdLightState {
+    pub(crate) fn new() -> Self {
+        Self {
+            color: Vec3::splat(154.0 / 255.0),
+            direction: Vec3::new(-0.5, -1.0, 0.5),
+            shadow_distance: 0.0,
+            shadow_mode: 0,
+            _padding: [0; 3],
+        }
+    }
+}
+
+pub(crate) struct MmdLightAnimation {
+    light_track: MmdLightAnimationTrack,
+    self_shadow_track: MmdSelfShadowAnimationTrack,
+}
+
+impl MmdLightAnimation {
+    pub(crate) fn new(light_track: MmdLightAnimationTrack, self_shadow_track: MmdSelfShadowAnimationTrack) -> Self {
+        Self {
+            light_track,
+            self_shadow_track,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn light_track_mut(&mut self) -> &mut MmdLightAnimationTrack {
+        &mut self.light_track
+    }
+
+    #[inline]
+    pub(crate) fn self_shadow_track_mut(&mut self) -> &mut MmdSelfShadowAnimationTrack {
+        &mut self.self_shadow_track
+    }
+
+    // light keyframes are interpolated linearly, self shadow keyframes are stepped
+    pub(crate) fn animate(&self, frame_time: f32, state: &mut MmdLightState) {
+        let light_track = &self.light_track;
+        if !light_track.frame_numbers.is_empty() {
+            let clamped_frame_time = frame_time.clamp(light_track.start_frame() as f32, light_track.end_frame() as f32);
+            let (frame_index_a, frame_index_b, gradient) = find_frame_interval(&light_track.frame_numbers, clamped_frame_time);
+            let frame_index_a = frame_index_a as u32;
+            let frame_index_b = frame_index_b as u32;
+
+            state.color = light_track.colors()[frame_index_a].lerp(light_track.colors()[frame_index_b], gradient);
+            state.direction = light_track.directions()[frame_index_a].lerp(light_track.directions()[frame_index_b], gradient);
+        }
+
+        let self_shadow_track = &self.self_shadow_track;
+        if !self_shadow_track.frame_numbers.is_empty() {
+            let clamped_frame_time = frame_time.clamp(self_shadow_track.start_frame() as f32, self_shadow_track.end_frame() as f32);
+            let (frame_index, _, _) = find_frame_interval(&self_shadow_track.frame_numbers, clamped_frame_time);
+            let frame_index = frame_index as u32;
+
+            state.shadow_mode = self_shadow_track.modes()[frame_index];
+            state.shadow_distance = self_shadow_track.distances()[frame_index];
+        }
+    }
+}
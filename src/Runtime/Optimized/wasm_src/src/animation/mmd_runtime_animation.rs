@@ -1,17 +1,105 @@
-use glam::Vec3A;
+use glam::{Quat, Vec3, Vec3A};
 
+use crate::animation_arena::AnimationArena;
 use crate::mmd_model::MmdModel;
+use crate::mmd_runtime_bone::MmdRuntimeBoneArena;
 use crate::unchecked_slice::UncheckedSlice;
 
 use super::mmd_animation::MmdAnimation;
-use super::bezier_interpolation::bezier_interpolation;
-use super::mmd_animation_track::{InterpolationVector3, InterpolationScalar};
+use super::retargeting::Retargeting;
+use super::root_motion::{RootMotion, RootTransform, ROOT_MOTION_MAX_FRAME_STEP};
 
+#[derive(Clone)]
 struct AnimationTrackState {
     frame_time: f32,
     frame_index: u32,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PlaybackLoopMode {
+    None = 0, // each track clamps to its own range
+    Clamp = 1,
+    Loop = 2,
+    PingPong = 3,
+}
+
+impl PlaybackLoopMode {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PlaybackLoopMode::None),
+            1 => Some(PlaybackLoopMode::Clamp),
+            2 => Some(PlaybackLoopMode::Loop),
+            3 => Some(PlaybackLoopMode::PingPong),
+            _ => None,
+        }
+    }
+}
+
+struct PlaybackState {
+    time_offset: f32,
+    speed: f32,
+    loop_mode: PlaybackLoopMode,
+    loop_start: f32,
+    loop_end: f32,
+    // the loop range follows the end frame of the animation
+    whole_animation_loop: bool,
+    seamless: bool,
+}
+
+impl PlaybackState {
+    #[inline]
+    fn local_time(&self, frame_time: f32) -> f32 {
+        (frame_time - self.time_offset) * self.speed
+    }
+
+    // returns the local frame time and, when looping seamlessly, the loop range to interpolate across
+    #[inline]
+    fn resolve(&self, frame_time: f32) -> (f32, Option<(f32, f32)>) {
+        self.resolve_local(self.local_time(frame_time))
+    }
+
+    fn resolve_local(&self, frame_time: f32) -> (f32, Option<(f32, f32)>) {
+        let loop_length = self.loop_end - self.loop_start;
+        if loop_length <= 0.0 {
+            return (frame_time, None);
+        }
+
+        match self.loop_mode {
+            PlaybackLoopMode::None => (frame_time, None),
+            PlaybackLoopMode::Clamp => (frame_time.clamp(self.loop_start, self.loop_end), None),
+            PlaybackLoopMode::Loop => {
+                let frame_time = self.loop_start + (frame_time - self.loop_start).rem_euclid(loop_length);
+                (frame_time, if self.seamless { Some((self.loop_start, self.loop_end)) } else { None })
+            }
+            PlaybackLoopMode::PingPong => {
+                let phase = (frame_time - self.loop_start).rem_euclid(loop_length * 2.0);
+                let phase = if loop_length < phase { loop_length * 2.0 - phase } else { phase };
+                (self.loop_start + phase, None)
+            }
+        }
+    }
+
+    // index of the loop iteration that contains local_time, None unless looping
+    fn loop_index(&self, local_time: f32) -> Option<i32> {
+        let loop_length = self.loop_end - self.loop_start;
+        if self.loop_mode != PlaybackLoopMode::Loop || loop_length <= 0.0 {
+            return None;
+        }
+        Some(((local_time - self.loop_start) / loop_length).floor() as i32)
+    }
+
+    // weight toward the loop start value when frame_time is past the last keyframe of a track
+    #[inline]
+    fn seam_weight(seam: Option<(f32, f32)>, track_end_frame: u32, frame_time: f32) -> f32 {
+        match seam {
+            Some((_, loop_end)) if (track_end_frame as f32) < frame_time && (track_end_frame as f32) < loop_end => {
+                (frame_time - track_end_frame as f32) / (loop_end - track_end_frame as f32)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
 struct AnimationState {
     bone_track_states: Box<[AnimationTrackState]>,
     movable_bone_track_states: Box<[AnimationTrackState]>,
@@ -19,23 +107,50 @@ struct AnimationState {
     property_track_state: AnimationTrackState,
 }
 
+// a generated animation together with the bind index maps of the model it was generated for
+pub(crate) struct BoundAnimation {
+    pub(crate) animation: MmdAnimation,
+    pub(crate) bone_bind_index_map: Box<[i32]>,
+    pub(crate) movable_bone_bind_index_map: Box<[i32]>,
+    pub(crate) morph_bind_index_map: Box<[Box<[i32]>]>,
+    pub(crate) ik_solver_bind_index_map: Box<[i32]>,
+}
+
+// bindings of the tracks appended to an animation after its runtime animations were created, in track order
+pub(crate) struct AppendedTracks {
+    pub(crate) bone_bind_index_map: Vec<i32>,
+    pub(crate) movable_bone_bind_index_map: Vec<i32>,
+    pub(crate) morph_bind_index_map: Vec<Box<[i32]>>,
+}
+
 pub(crate) struct MmdRuntimeAnimation {
-    animation: &'static MmdAnimation,
+    // owned by the animation pool, which edits it in place, so no reference is kept across calls
+    animation: *const MmdAnimation,
     state: AnimationState,
     bone_bind_index_map: Box<[i32]>,
     movable_bone_bind_index_map: Box<[i32]>,
     morph_bind_index_map: Box<[Box<[i32]>]>,
     ik_solver_bind_index_map: Box<[i32]>,
+    bone_mask: Option<Box<[f32]>>,
+    morph_mask: Option<Box<[f32]>>,
+    playback: PlaybackState,
+    retargeting: Option<Retargeting>,
+    root_motion: Option<RootMotion>,
 }
 
 impl MmdRuntimeAnimation {
+    // animation must outlive the runtime animation
     pub(crate) fn new(
-        animation: &'static MmdAnimation,
+        animation_ptr: *const MmdAnimation,
         bone_bind_index_map: Box<[i32]>,
         movable_bone_bind_index_map: Box<[i32]>,
         morph_bind_index_map: Box<[Box<[i32]>]>,
         ik_solver_bind_index_map: Box<[i32]>,
     ) -> Self {
+        let animation = unsafe {
+            &*animation_ptr
+        };
+
         let mut bone_track_states = Vec::with_capacity(animation.bone_tracks().len());
         for _ in 0..animation.bone_tracks().len() {
             bone_track_states.push(AnimationTrackState {
@@ -73,20 +188,253 @@ impl MmdRuntimeAnimation {
         };
 
         Self {
-            animation,
+            animation: animation_ptr,
             state,
             bone_bind_index_map,
             movable_bone_bind_index_map,
             morph_bind_index_map,
             ik_solver_bind_index_map,
+            bone_mask: None,
+            morph_mask: None,
+            playback: PlaybackState {
+                time_offset: 0.0,
+                speed: 1.0,
+                loop_mode: PlaybackLoopMode::None,
+                loop_start: 0.0,
+                loop_end: 0.0,
+                whole_animation_loop: false,
+                seamless: false,
+            },
+            retargeting: None,
+            root_motion: None,
+        }
+    }
+
+    // stateless sampling, used away from the current frame time
+    fn sample_bone_track_rotation(&self, track_index: usize, frame_time: f32) -> Quat {
+        match self.animation().compressed_tracks() {
+            Some(compressed_tracks) => compressed_tracks.bone_tracks[track_index].sample_rotation(self.animation().curve_table(), frame_time),
+            None => self.animation().bone_tracks()[track_index].sample_rotation(self.animation().curve_table(), frame_time),
+        }
+    }
+
+    fn sample_movable_bone_track(&self, track_index: usize, frame_time: f32) -> (Vec3, Quat) {
+        match self.animation().compressed_tracks() {
+            Some(compressed_tracks) => compressed_tracks.movable_bone_tracks[track_index].sample(self.animation().curve_table(), frame_time),
+            None => self.animation().movable_bone_tracks()[track_index].sample(self.animation().curve_table(), frame_time),
+        }
+    }
+
+    // cached frame indices are invalid once the tracks of the animation are rebuilt
+    pub(crate) fn reset_state(&mut self) {
+        let state = &mut self.state;
+        for track_state in state.bone_track_states.iter_mut()
+            .chain(state.movable_bone_track_states.iter_mut())
+            .chain(state.morph_track_states.iter_mut())
+            .chain(std::iter::once(&mut state.property_track_state))
+        {
+            track_state.frame_time = f32::NEG_INFINITY;
+            track_state.frame_index = 0;
+        }
+        if let Some(root_motion) = &mut self.root_motion {
+            root_motion.reset();
+        }
+        if self.playback.whole_animation_loop {
+            self.playback.loop_end = self.animation().end_frame() as f32;
+        }
+    }
+
+    // runtime animations other than the one the tracks were appended for leave them unbound
+    pub(crate) fn bind_appended_tracks(&mut self, appended_tracks: &AppendedTracks, bound: bool) {
+        fn append<T: Clone>(values: &mut Box<[T]>, appended: &[T]) {
+            let mut vec = std::mem::take(values).into_vec();
+            vec.extend_from_slice(appended);
+            *values = vec.into_boxed_slice();
+        }
+        let new_track_states = |count: usize| vec![AnimationTrackState {
+            frame_time: f32::NEG_INFINITY,
+            frame_index: 0,
+        }; count];
+
+        let AppendedTracks { bone_bind_index_map, movable_bone_bind_index_map, morph_bind_index_map } = appended_tracks;
+        if bound {
+            append(&mut self.bone_bind_index_map, bone_bind_index_map);
+            append(&mut self.movable_bone_bind_index_map, movable_bone_bind_index_map);
+            append(&mut self.morph_bind_index_map, morph_bind_index_map);
+        } else {
+            append(&mut self.bone_bind_index_map, &vec![-1; bone_bind_index_map.len()]);
+            append(&mut self.movable_bone_bind_index_map, &vec![-1; movable_bone_bind_index_map.len()]);
+            append(&mut self.morph_bind_index_map, &vec![Box::default(); morph_bind_index_map.len()]);
+        }
+        append(&mut self.state.bone_track_states, &new_track_states(bone_bind_index_map.len()));
+        append(&mut self.state.movable_bone_track_states, &new_track_states(movable_bone_bind_index_map.len()));
+        append(&mut self.state.morph_track_states, &new_track_states(morph_bind_index_map.len()));
+    }
+
+    // extracts the horizontal translation and yaw of the movable track bound to bone, None disables extraction
+    pub(crate) fn set_root_motion_bone(&mut self, bone: Option<i32>) {
+        self.root_motion = bone
+            .and_then(|bone| self.movable_bone_bind_index_map.iter().position(|bound_bone| *bound_bone == bone))
+            .map(RootMotion::new);
+    }
+
+    // (delta position, delta rotation) of the last update, the position is in the heading of the previous update
+    #[inline]
+    pub(crate) fn root_motion_delta(&self) -> Option<(Vec3, Quat)> {
+        self.root_motion.as_ref().map(|root_motion| root_motion.delta.to_position_rotation())
+    }
+
+    fn sample_root_transform(&self, track_index: usize, frame_time: f32) -> RootTransform {
+        let (position_offset, rotation) = self.sample_movable_bone_track(track_index, frame_time);
+        let position_offset = match &self.retargeting {
+            Some(retargeting) => Vec3::from(retargeting.retarget_position_offset(position_offset.into())),
+            None => position_offset,
+        };
+        RootTransform::from_sample(position_offset, rotation)
+    }
+
+    // root motion between two local times, wrapped loop iterations are accumulated through the loop ends
+    fn root_motion_between(&self, track_index: usize, from: f32, to: f32) -> RootTransform {
+        let sample = |local_time: f32| self.sample_root_transform(track_index, self.playback.resolve_local(local_time).0);
+        match self.playback.loop_index(from).zip(self.playback.loop_index(to)) {
+            Some((from_loop, to_loop)) if from_loop != to_loop => {
+                let start = self.sample_root_transform(track_index, self.playback.loop_start);
+                let end = self.sample_root_transform(track_index, self.playback.loop_end);
+                if from_loop < to_loop {
+                    let mut delta = sample(from).delta_to(&end);
+                    for _ in from_loop + 1..to_loop {
+                        delta = delta.then(&start.delta_to(&end));
+                    }
+                    delta.then(&start.delta_to(&sample(to)))
+                } else {
+                    let mut delta = sample(from).delta_to(&start);
+                    for _ in to_loop + 1..from_loop {
+                        delta = delta.then(&end.delta_to(&start));
+                    }
+                    delta.then(&end.delta_to(&sample(to)))
+                }
+            }
+            _ => sample(from).delta_to(&sample(to)),
+        }
+    }
+
+    // advances root motion to frame_time, called once per displayed frame
+    pub(crate) fn update_root_motion(&mut self, frame_time: f32) {
+        let (track_index, last_local_time) = match &self.root_motion {
+            Some(root_motion) => (root_motion.track_index, root_motion.last_local_time),
+            None => return,
+        };
+
+        let local_time = self.playback.local_time(frame_time);
+        let delta = match last_local_time {
+            Some(last_local_time) if (local_time - last_local_time).abs() <= ROOT_MOTION_MAX_FRAME_STEP => {
+                self.root_motion_between(track_index, last_local_time, local_time)
+            }
+            _ => RootTransform::IDENTITY,
+        };
+
+        let root_motion = self.root_motion.as_mut().unwrap();
+        root_motion.last_local_time = Some(local_time);
+        root_motion.delta = delta;
+    }
+
+    #[inline]
+    pub(crate) fn retargeting(&self) -> Option<&Retargeting> {
+        self.retargeting.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn set_retargeting(&mut self, retargeting: Option<Retargeting>) {
+        self.retargeting = retargeting;
+    }
+
+    #[inline]
+    pub(crate) fn set_playback(&mut self, time_offset: f32, speed: f32) {
+        self.playback.time_offset = time_offset;
+        self.playback.speed = speed;
+    }
+
+    // an empty loop range uses the whole animation, its end is refreshed by reset_state once the tracks change
+    pub(crate) fn set_loop(&mut self, loop_mode: PlaybackLoopMode, loop_start: f32, loop_end: f32, seamless: bool) {
+        let whole_animation_loop = loop_end <= loop_start;
+        let (loop_start, loop_end) = if whole_animation_loop {
+            (0.0, self.animation().end_frame() as f32)
+        } else {
+            (loop_start, loop_end)
+        };
+        self.playback.loop_mode = loop_mode;
+        self.playback.loop_start = loop_start;
+        self.playback.loop_end = loop_end;
+        self.playback.whole_animation_loop = whole_animation_loop;
+        self.playback.seamless = seamless;
+    }
+
+    #[inline]
+    pub(crate) fn animation(&self) -> &MmdAnimation {
+        unsafe {
+            &*self.animation
         }
     }
 
     #[inline]
-    pub(crate) fn animation(&self) -> &'static MmdAnimation {
+    pub(crate) fn animation_ptr(&self) -> *const MmdAnimation {
         self.animation
     }
 
+    #[inline]
+    pub(crate) fn bone_bind_index_map(&self) -> &[i32] {
+        &self.bone_bind_index_map
+    }
+
+    #[inline]
+    pub(crate) fn movable_bone_bind_index_map(&self) -> &[i32] {
+        &self.movable_bone_bind_index_map
+    }
+
+    #[inline]
+    pub(crate) fn morph_bind_index_map(&self) -> &[Box<[i32]>] {
+        &self.morph_bind_index_map
+    }
+
+    #[inline]
+    pub(crate) fn ik_solver_bind_index_map(&self) -> &[i32] {
+        &self.ik_solver_bind_index_map
+    }
+
+    #[inline]
+    pub(crate) fn is_masked(&self) -> bool {
+        self.bone_mask.is_some() || self.morph_mask.is_some()
+    }
+
+    // overlay animations are applied on top of the base layers instead of being blended with them
+    #[inline]
+    pub(crate) fn is_overlay(&self) -> bool {
+        self.is_masked() || self.animation().is_additive()
+    }
+
+    #[inline]
+    pub(crate) fn set_bone_mask(&mut self, bone_mask: Option<Box<[f32]>>) {
+        self.bone_mask = bone_mask;
+    }
+
+    #[inline]
+    pub(crate) fn bone_mask_mut(&mut self) -> &mut Option<Box<[f32]>> {
+        &mut self.bone_mask
+    }
+
+    #[inline]
+    pub(crate) fn set_morph_mask(&mut self, morph_mask: Option<Box<[f32]>>) {
+        self.morph_mask = morph_mask;
+    }
+
+    #[inline]
+    fn mask_weight(mask: &Option<Box<[f32]>>, index: i32, layer_weight: f32) -> f32 {
+        match mask {
+            Some(mask) => mask.get(index as usize).copied().unwrap_or(0.0) * layer_weight,
+            None => layer_weight,
+        }
+    }
+
     fn upper_bound_frame_index(frame_time: f32, frame_numbers: &[u32], track_state: &mut AnimationTrackState) -> u32 {
         let frame_numbers = UncheckedSlice::new(frame_numbers);
 
@@ -126,138 +474,191 @@ impl MmdRuntimeAnimation {
     }
 
     pub(crate) fn animate(&mut self, frame_time: f32, mmd_model: &mut MmdModel) {
-        if !self.animation.bone_tracks().is_empty() {
-            let animation_arena = mmd_model.animation_arena_mut();
+        let (animation_arena, bone_arena) = mmd_model.animation_and_bone_arena_mut();
+        self.animate_into(frame_time, animation_arena, bone_arena, 1.0);
+    }
 
-            assert!(self.animation.bone_tracks().len() == self.bone_bind_index_map.len()
-                && self.animation.bone_tracks().len() == self.state.bone_track_states.len());
-            for i in 0..self.animation.bone_tracks().len() {
-                let bone = self.bone_bind_index_map[i];
+    // bones and morphs are written with (mask weight * layer_weight), blending over the values already in animation_arena
+    // additive animations are accumulated onto the values already in animation_arena instead
+    pub(crate) fn animate_into(&mut self, frame_time: f32, animation_arena: &mut AnimationArena, bone_arena: &MmdRuntimeBoneArena, layer_weight: f32) {
+        // detached from self so the track states can be updated while sampling, the pool does not edit during animation
+        let animation = unsafe {
+            &*self.animation
+        };
+        let additive = animation.is_additive();
+        let (frame_time, seam) = self.playback.resolve(frame_time);
+        let curve_table = animation.curve_table();
+
+        if !animation.bone_tracks().is_empty() {
+            assert!(animation.bone_tracks().len() == self.bone_bind_index_map.len()
+                && animation.bone_tracks().len() == self.state.bone_track_states.len());
+            for i in 0..animation.bone_tracks().len() {
+                let bone_index = self.bone_bind_index_map[i];
+                let mask_weight = Self::mask_weight(&self.bone_mask, bone_index, layer_weight);
+                if mask_weight <= 0.0 {
+                    continue;
+                }
                 let mut animation_bone_arena = animation_arena.bone_arena_mut();
-                let bone = match animation_bone_arena.get_mut(bone as u32) {
+                let bone = match animation_bone_arena.get_mut(bone_index as u32) {
                     Some(bone) => bone,
                     None => continue,
                 };
 
-                let track = &self.animation.bone_tracks()[i];
+                let (rotation, track_end_frame) = if let Some(compressed_tracks) = animation.compressed_tracks() {
+                    let track = &compressed_tracks.bone_tracks[i];
+                    (track.sample_rotation(curve_table, frame_time), track.end_frame())
+                } else {
+                    let track = &animation.bone_tracks()[i];
 
-                let clamped_frame_time = frame_time.clamp(track.start_frame() as f32, track.end_frame() as f32);
-                let frame_index_b = Self::upper_bound_frame_index(
-                    clamped_frame_time,
-                    &track.frame_numbers,
-                    &mut self.state.bone_track_states[i],
-                );
-                let frame_index_a = frame_index_b - 1;
+                    let clamped_frame_time = frame_time.clamp(track.start_frame() as f32, track.end_frame() as f32);
+                    let frame_index_b = Self::upper_bound_frame_index(
+                        clamped_frame_time,
+                        &track.frame_numbers,
+                        &mut self.state.bone_track_states[i],
+                    );
+                    let frame_index_a = frame_index_b - 1;
 
-                if let Some(frame_number_b) = track.frame_numbers.get(frame_index_b as usize) {
-                    let frame_number_a = track.frame_numbers[frame_index_a as usize] as f32;
-                    let frame_number_b = *frame_number_b as f32;
-                    let gradient = (clamped_frame_time - frame_number_a) / (frame_number_b - frame_number_a);
+                    let rotation = if let Some(frame_number_b) = track.frame_numbers.get(frame_index_b as usize) {
+                        let frame_number_a = track.frame_numbers[frame_index_a as usize] as f32;
+                        let frame_number_b = *frame_number_b as f32;
+                        let gradient = (clamped_frame_time - frame_number_a) / (frame_number_b - frame_number_a);
 
-                    let weight = {
-                        let InterpolationScalar {x1, x2, y1, y2} = &track.rotation_interpolations()[frame_index_b];
-                        bezier_interpolation(
-                            *x1 as f32 / 127.0,
-                            *x2 as f32 / 127.0,
-                            *y1 as f32 / 127.0,
-                            *y2 as f32 / 127.0,
-                            gradient,
-                        )
+                        let weight = curve_table.evaluate(track.rotation_curves()[frame_index_b], gradient);
+                        track.rotations()[frame_index_a].slerp(track.rotations()[frame_index_b], weight)
+                    } else {
+                        track.rotations()[frame_index_a]
                     };
-                    bone.rotation = track.rotations()[frame_index_a].slerp(track.rotations()[frame_index_b], weight);
+                    (rotation, track.end_frame())
+                };
+                let seam_weight = PlaybackState::seam_weight(seam, track_end_frame, frame_time);
+                let rotation = if 0.0 < seam_weight {
+                    rotation.slerp(self.sample_bone_track_rotation(i, seam.unwrap().0), seam_weight)
                 } else {
-                    bone.rotation = track.rotations()[frame_index_a];
-                }
+                    rotation
+                };
+                let rotation = match &self.retargeting {
+                    Some(retargeting) => retargeting.retarget_rotation(bone_index as u32, rotation),
+                    None => rotation,
+                };
+
+                bone.rotation = if additive {
+                    bone.rotation * Quat::IDENTITY.slerp(rotation, mask_weight.min(1.0))
+                } else if mask_weight < 1.0 {
+                    bone.rotation.slerp(rotation, mask_weight)
+                } else {
+                    rotation
+                };
             }
         }
 
-        if !self.animation.movable_bone_tracks().is_empty() {
-            assert!(self.animation.movable_bone_tracks().len() == self.movable_bone_bind_index_map.len()
-                && self.animation.movable_bone_tracks().len() == self.state.movable_bone_track_states.len());
-            for i in 0..self.animation.movable_bone_tracks().len() {
+        if !animation.movable_bone_tracks().is_empty() {
+            assert!(animation.movable_bone_tracks().len() == self.movable_bone_bind_index_map.len()
+                && animation.movable_bone_tracks().len() == self.state.movable_bone_track_states.len());
+            for i in 0..animation.movable_bone_tracks().len() {
                 let bone_index = self.movable_bone_bind_index_map[i];
-                let bone_rest_position = match mmd_model.bone_arena_mut().arena_mut().get(bone_index as u32) {
+                let mask_weight = Self::mask_weight(&self.bone_mask, bone_index, layer_weight);
+                if mask_weight <= 0.0 {
+                    continue;
+                }
+                let bone_rest_position = match bone_arena.arena().get(bone_index as u32) {
                     Some(bone) => bone.rest_position,
                     None => continue,
                 };
-                let bone = &mut mmd_model.animation_arena_mut().bone_arena_mut()[bone_index as u32];
-
-                let track = &self.animation.movable_bone_tracks()[i];
-
-                let clamped_frame_time = frame_time.clamp(track.start_frame() as f32, track.end_frame() as f32);
-                let frame_index_b = Self::upper_bound_frame_index(
-                    clamped_frame_time,
-                    &track.frame_numbers,
-                    &mut self.state.movable_bone_track_states[i],
-                );
-                let frame_index_a = frame_index_b - 1;
+                let bone = &mut animation_arena.bone_arena_mut()[bone_index as u32];
 
-                if let Some(frame_number_b) = track.frame_numbers.get(frame_index_b as usize) {
-                    let frame_number_a = track.frame_numbers[frame_index_a as usize] as f32;
-                    let frame_number_b = *frame_number_b as f32;
-                    let gradient = (clamped_frame_time - frame_number_a) / (frame_number_b - frame_number_a);
+                let (position, rotation, track_end_frame) = if let Some(compressed_tracks) = animation.compressed_tracks() {
+                    let track = &compressed_tracks.movable_bone_tracks[i];
+                    let (position, rotation) = track.sample(curve_table, frame_time);
+                    (bone_rest_position + Vec3A::from(position), rotation, track.end_frame())
+                } else {
+                    let track = &animation.movable_bone_tracks()[i];
 
-                    let (x_weight, y_weight, z_weight) = {
-                        let InterpolationVector3 {x, y, z} = &track.position_interpolations()[frame_index_b];
+                    let clamped_frame_time = frame_time.clamp(track.start_frame() as f32, track.end_frame() as f32);
+                    let frame_index_b = Self::upper_bound_frame_index(
+                        clamped_frame_time,
+                        &track.frame_numbers,
+                        &mut self.state.movable_bone_track_states[i],
+                    );
+                    let frame_index_a = frame_index_b - 1;
+
+                    let (position, rotation) = if let Some(frame_number_b) = track.frame_numbers.get(frame_index_b as usize) {
+                        let frame_number_a = track.frame_numbers[frame_index_a as usize] as f32;
+                        let frame_number_b = *frame_number_b as f32;
+                        let gradient = (clamped_frame_time - frame_number_a) / (frame_number_b - frame_number_a);
+
+                        let (x_weight, y_weight, z_weight) = {
+                            let [x, y, z] = track.position_curves()[frame_index_b];
+                            (
+                                curve_table.evaluate(x, gradient),
+                                curve_table.evaluate(y, gradient),
+                                curve_table.evaluate(z, gradient),
+                            )
+                        };
+                        let position_a = track.positions()[frame_index_a];
+                        let position_b = track.positions()[frame_index_b];
+                        let position = bone_rest_position + Vec3A::new(
+                            position_a.x + (position_b.x - position_a.x) * x_weight,
+                            position_a.y + (position_b.y - position_a.y) * y_weight,
+                            position_a.z + (position_b.z - position_a.z) * z_weight,
+                        );
+
+                        let rotation_weight = curve_table.evaluate(track.rotation_curves()[frame_index_b], gradient);
+                        let rotation = track.rotations()[frame_index_a].slerp(track.rotations()[frame_index_b], rotation_weight);
+                        (position, rotation)
+                    } else {
                         (
-                            bezier_interpolation(
-                                x.x1 as f32 / 127.0,
-                                x.x2 as f32 / 127.0,
-                                x.y1 as f32 / 127.0,
-                                x.y2 as f32 / 127.0,
-                                gradient,
-                            ),
-                            bezier_interpolation(
-                                y.x1 as f32 / 127.0,
-                                y.x2 as f32 / 127.0,
-                                y.y1 as f32 / 127.0,
-                                y.y2 as f32 / 127.0,
-                                gradient,
-                            ),
-                            bezier_interpolation(
-                                z.x1 as f32 / 127.0,
-                                z.x2 as f32 / 127.0,
-                                z.y1 as f32 / 127.0,
-                                z.y2 as f32 / 127.0,
-                                gradient,
-                            ),
+                            bone_rest_position + Vec3A::from(track.positions()[frame_index_a]),
+                            track.rotations()[frame_index_a],
                         )
                     };
-                    let position_a = track.positions()[frame_index_a];
-                    let position_b = track.positions()[frame_index_b];
-                    bone.position = bone_rest_position + Vec3A::new(
-                        position_a.x + (position_b.x - position_a.x) * x_weight,
-                        position_a.y + (position_b.y - position_a.y) * y_weight,
-                        position_a.z + (position_b.z - position_a.z) * z_weight,
-                    );
+                    (position, rotation, track.end_frame())
+                };
+                let seam_weight = PlaybackState::seam_weight(seam, track_end_frame, frame_time);
+                let (position, rotation) = if 0.0 < seam_weight {
+                    let (start_position, start_rotation) = self.sample_movable_bone_track(i, seam.unwrap().0);
+                    (
+                        position.lerp(bone_rest_position + Vec3A::from(start_position), seam_weight),
+                        rotation.slerp(start_rotation, seam_weight),
+                    )
+                } else {
+                    (position, rotation)
+                };
+                let (position, rotation) = match &self.retargeting {
+                    Some(retargeting) => (
+                        bone_rest_position + retargeting.retarget_position_offset(position - bone_rest_position),
+                        retargeting.retarget_rotation(bone_index as u32, rotation),
+                    ),
+                    None => (position, rotation),
+                };
+                let (position, rotation) = match &self.root_motion {
+                    Some(root_motion) if root_motion.track_index == i => {
+                        let (position_offset, rotation) = RootMotion::strip(position - bone_rest_position, rotation);
+                        (bone_rest_position + position_offset, rotation)
+                    }
+                    _ => (position, rotation),
+                };
 
-                    let rotation_weight = {
-                        let InterpolationScalar {x1, x2, y1, y2} = &track.rotation_interpolations()[frame_index_b];
-                        bezier_interpolation(
-                            *x1 as f32 / 127.0,
-                            *x2 as f32 / 127.0,
-                            *y1 as f32 / 127.0,
-                            *y2 as f32 / 127.0,
-                            gradient,
-                        )
-                    };
-                    bone.rotation = track.rotations()[frame_index_a].slerp(track.rotations()[frame_index_b], rotation_weight);
+                if additive {
+                    let mask_weight = mask_weight.min(1.0);
+                    bone.position += (position - bone_rest_position) * mask_weight;
+                    bone.rotation *= Quat::IDENTITY.slerp(rotation, mask_weight);
+                } else if mask_weight < 1.0 {
+                    bone.position = bone.position.lerp(position, mask_weight);
+                    bone.rotation = bone.rotation.slerp(rotation, mask_weight);
                 } else {
-                    bone.position = bone_rest_position + Vec3A::from(track.positions()[frame_index_a]);
-                    bone.rotation = track.rotations()[frame_index_a];
+                    bone.position = position;
+                    bone.rotation = rotation;
                 }
             }
         }
 
-        if !self.animation.morph_tracks().is_empty() {
-            let animation_arena = mmd_model.animation_arena_mut();
-
-            assert!(self.animation.morph_tracks().len() == self.morph_bind_index_map.len()
-                && self.animation.morph_tracks().len() == self.state.morph_track_states.len());
-            for i in 0..self.animation.morph_tracks().len() {
+        if !animation.morph_tracks().is_empty() {
+            assert!(animation.morph_tracks().len() == self.morph_bind_index_map.len()
+                && animation.morph_tracks().len() == self.state.morph_track_states.len());
+            for i in 0..animation.morph_tracks().len() {
                 let morph_indices = &self.morph_bind_index_map[i];
 
-                let track = &self.animation.morph_tracks()[i];
+                let track = &animation.morph_tracks()[i];
 
                 let clamped_frame_time = frame_time.clamp(track.start_frame() as f32, track.end_frame() as f32);
                 let frame_index_b = Self::upper_bound_frame_index(
@@ -267,38 +668,47 @@ impl MmdRuntimeAnimation {
                 );
                 let frame_index_a = frame_index_b - 1;
 
-                if let Some(frame_number_b) = track.frame_numbers.get(frame_index_b as usize) {
+                let weight = if let Some(frame_number_b) = track.frame_numbers.get(frame_index_b as usize) {
                     let frame_number_a = track.frame_numbers[frame_index_a as usize] as f32;
                     let frame_number_b = *frame_number_b as f32;
                     let gradient = (clamped_frame_time - frame_number_a) / (frame_number_b - frame_number_a);
 
-                    let weight = track.weights()[frame_index_a] + (track.weights()[frame_index_b] - track.weights()[frame_index_a]) * gradient;
+                    track.interpolate_weight_with_curves(curve_table, frame_index_a, frame_index_b, gradient)
+                } else {
+                    track.weights()[frame_index_a]
+                };
+                let seam_weight = PlaybackState::seam_weight(seam, track.end_frame(), frame_time);
+                let weight = if 0.0 < seam_weight {
+                    weight + (track.sample(curve_table, seam.unwrap().0) - weight) * seam_weight
+                } else {
+                    weight
+                };
 
-                    for morph_index in morph_indices.iter() {
-                        let mut animation_morph_arena = animation_arena.morph_arena_mut();
-                        let morph = match animation_morph_arena.get_mut(*morph_index as u32) {
-                            Some(morph) => morph,
-                            None => continue,
-                        };
-                        *morph = weight;
+                for morph_index in morph_indices.iter() {
+                    let mask_weight = Self::mask_weight(&self.morph_mask, *morph_index, layer_weight);
+                    if mask_weight <= 0.0 {
+                        continue;
                     }
-                } else {
-                    for morph_index in morph_indices.iter() {
-                        let mut animation_morph_arena = animation_arena.morph_arena_mut();
-                        let morph = match animation_morph_arena.get_mut(*morph_index as u32) {
-                            Some(morph) => morph,
-                            None => continue,
-                        };
-                        *morph = track.weights()[frame_index_a];
+
+                    let mut animation_morph_arena = animation_arena.morph_arena_mut();
+                    let morph = match animation_morph_arena.get_mut(*morph_index as u32) {
+                        Some(morph) => morph,
+                        None => continue,
+                    };
+                    if additive {
+                        *morph += weight * mask_weight.min(1.0);
+                    } else if mask_weight < 1.0 {
+                        *morph += (weight - *morph) * mask_weight;
+                    } else {
+                        *morph = weight;
                     }
                 }
             }
         }
 
-        let property_track = self.animation.property_track();
-        if !property_track.frame_numbers.is_empty() {
-            let animation_arena = mmd_model.animation_arena_mut();
-            
+        // ik states and visibility are not maskable, overlay layers leave them to the base layers
+        let property_track = animation.property_track();
+        if !property_track.frame_numbers.is_empty() && !self.is_overlay() {
             let clamp_frame_time = frame_time.clamp(
                 property_track.start_frame() as f32,
                 property_track.end_frame() as f32,
@@ -308,7 +718,9 @@ impl MmdRuntimeAnimation {
                 &property_track.frame_numbers,
                 &mut self.state.property_track_state,
             ) - 1;
-            
+
+            animation_arena.set_visibility(property_track.visibilities()[step_index]);
+
             assert!(property_track.ik_count() == self.ik_solver_bind_index_map.len());
             for i in 0..property_track.ik_count() {
                 let ik_solver_index = self.ik_solver_bind_index_map[i];
@@ -324,3 +736,247 @@ impl MmdRuntimeAnimation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::NonNull;
+
+    use glam::Quat;
+
+    use super::{MmdRuntimeAnimation, PlaybackLoopMode};
+    use crate::animation::mmd_animation::MmdAnimation;
+    use crate::animation::mmd_animation_track::{MmdBoneAnimationTrack, MmdPropertyAnimationTrack};
+    use crate::foot_grounding::Ground;
+    use crate::mmd_model::MmdModel;
+    use crate::mmd_model_metadata::MetadataBuffer;
+
+    // root bones without morphs, rigidbodies and joints
+    fn bone_metadata(bone_count: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for count in [bone_count, 0, 0] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        for _ in 0..bone_count {
+            for value in [0.0_f32; 3] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&(-1_i32).to_le_bytes());
+            bytes.extend_from_slice(&0_i32.to_le_bytes());
+            bytes.extend_from_slice(&0_u16.to_le_bytes());
+            bytes.extend_from_slice(&[0; 2]);
+        }
+        for count in [0_u32, 0, 0] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn single_bone_metadata() -> Vec<u8> {
+        bone_metadata(1)
+    }
+
+    // rotates the bone from the rest pose by 0.5 radians about y over 10 frames, as deltas from frame 0
+    fn additive_runtime_animation() -> Box<MmdRuntimeAnimation> {
+        let mut track = MmdBoneAnimationTrack::new(2);
+        track.frame_numbers[1] = 10;
+        track.rotations_mut()[1] = Quat::from_rotation_y(0.5);
+
+        let mut animation = MmdAnimation::new(
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        assert!(animation.make_additive(Some(0.0)));
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+
+        Box::new(MmdRuntimeAnimation::new(animation, Box::new([0]), Box::new([]), Box::new([]), Box::new([])))
+    }
+
+    fn play_additive(frame_count: u32) -> Quat {
+        let metadata = single_bone_metadata();
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut runtime_animation = additive_runtime_animation();
+        model.add_runtime_animation(NonNull::from(runtime_animation.as_mut()), 1.0);
+
+        let ground = Ground::new();
+        for i in 1..=frame_count {
+            model.before_physics(Some(10.0 * i as f32 / frame_count as f32), 1.0, &ground);
+        }
+        model.animation_arena().bone_arena()[0].rotation
+    }
+
+    // holds the bone at rotation for the whole animation
+    fn constant_runtime_animation(rotation: Quat) -> Box<MmdRuntimeAnimation> {
+        let mut track = MmdBoneAnimationTrack::new(1);
+        track.rotations_mut()[0] = rotation;
+
+        let animation = MmdAnimation::new(
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+
+        Box::new(MmdRuntimeAnimation::new(animation, Box::new([0]), Box::new([]), Box::new([]), Box::new([])))
+    }
+
+    #[test]
+    fn cross_fade_weights_follow_elapsed_time() {
+        let metadata = single_bone_metadata();
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut from = constant_runtime_animation(Quat::IDENTITY);
+        let mut to = constant_runtime_animation(Quat::from_rotation_y(1.0));
+        model.add_runtime_animation(NonNull::from(from.as_mut()), 1.0);
+        model.cross_fade_runtime_animation(NonNull::from(to.as_mut()), 4.0);
+
+        // the faded in layer has weight elapsed / duration, the rest pose takes no weight while the layers sum to one
+        let ground = Ground::new();
+        for (step, expected_weight) in [0.25_f32, 0.5, 0.75, 1.0, 1.0].into_iter().enumerate() {
+            model.before_physics(Some(step as f32), 1.0, &ground);
+            let rotation = model.animation_arena().bone_arena()[0].rotation;
+            let expected = Quat::from_rotation_y(expected_weight);
+            assert!(rotation.angle_between(expected) < 1e-4, "step {step}: {rotation:?}");
+        }
+    }
+
+    #[test]
+    fn additive_pose_is_independent_of_played_frames() {
+        let expected = Quat::from_rotation_y(0.5);
+        for frame_count in [1, 2, 10, 60] {
+            let rotation = play_additive(frame_count);
+            assert!(rotation.angle_between(expected) < 1e-4, "{frame_count} frames: {rotation:?}");
+        }
+    }
+
+    #[test]
+    fn overlay_layers_keep_values_written_from_outside() {
+        let metadata = bone_metadata(2);
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut runtime_animation = additive_runtime_animation();
+        model.add_runtime_animation(NonNull::from(runtime_animation.as_mut()), 1.0);
+
+        // bone 1 and the visibility are not animated by the layer
+        let written = Quat::from_rotation_x(0.3);
+        model.animation_arena_mut().bone_arena_mut()[1].rotation = written;
+        model.animation_arena_mut().set_visibility(0);
+
+        let ground = Ground::new();
+        for i in 1..=4 {
+            model.before_physics(Some(2.5 * i as f32), 1.0, &ground);
+        }
+        let bone_arena = model.animation_arena().bone_arena();
+        assert!(bone_arena[0].rotation.angle_between(Quat::from_rotation_y(0.5)) < 1e-4, "{:?}", bone_arena[0].rotation);
+        assert_eq!(bone_arena[1].rotation, written);
+        assert_eq!(model.animation_arena().visibility(), 0);
+    }
+
+    // rotates the bone linearly from the rest pose to 1 radian about y over 10 frames
+    fn linear_runtime_animation() -> Box<MmdRuntimeAnimation> {
+        let mut track = MmdBoneAnimationTrack::new(2);
+        track.frame_numbers[1] = 10;
+        track.rotations_mut()[1] = Quat::from_rotation_y(1.0);
+
+        let animation = MmdAnimation::new(
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+
+        Box::new(MmdRuntimeAnimation::new(animation, Box::new([0]), Box::new([]), Box::new([]), Box::new([])))
+    }
+
+    fn assert_sampled_angle(runtime_animation: &mut MmdRuntimeAnimation, model: &mut MmdModel, frame_time: f32, expected_angle: f32) {
+        runtime_animation.animate(frame_time, model);
+        let rotation = model.animation_arena().bone_arena()[0].rotation;
+        let expected = Quat::from_rotation_y(expected_angle);
+        assert!(rotation.angle_between(expected) < 1e-3, "frame {frame_time}: {rotation:?}, expected {expected_angle} radians");
+    }
+
+    #[test]
+    fn loop_wraps_at_the_loop_end() {
+        let metadata = single_bone_metadata();
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut runtime_animation = linear_runtime_animation();
+        runtime_animation.set_loop(PlaybackLoopMode::Loop, 2.0, 8.0, false);
+
+        for (frame_time, expected_angle) in [(5.0, 0.5), (8.0, 0.2), (9.0, 0.3), (15.0, 0.3), (1.0, 0.7), (-4.0, 0.2)] {
+            assert_sampled_angle(&mut runtime_animation, &mut model, frame_time, expected_angle);
+        }
+
+        // the whole animation is used for an empty range
+        runtime_animation.set_loop(PlaybackLoopMode::Loop, 0.0, 0.0, false);
+        for (frame_time, expected_angle) in [(9.0, 0.9), (10.0, 0.0), (13.0, 0.3), (27.5, 0.75)] {
+            assert_sampled_angle(&mut runtime_animation, &mut model, frame_time, expected_angle);
+        }
+    }
+
+    #[test]
+    fn ping_pong_reverses_at_the_loop_ends() {
+        let metadata = single_bone_metadata();
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut runtime_animation = linear_runtime_animation();
+        runtime_animation.set_loop(PlaybackLoopMode::PingPong, 0.0, 0.0, false);
+
+        // forward over 0..10, backward over 10..20, then forward again
+        for (frame_time, expected_angle) in [(4.0, 0.4), (10.0, 1.0), (13.0, 0.7), (20.0, 0.0), (26.0, 0.6), (-3.0, 0.3)] {
+            assert_sampled_angle(&mut runtime_animation, &mut model, frame_time, expected_angle);
+        }
+    }
+
+    #[test]
+    fn seamless_loop_interpolates_from_the_last_key_to_the_first() {
+        let metadata = single_bone_metadata();
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut runtime_animation = linear_runtime_animation();
+
+        // the loop range ends 4 frames after the last key, the seam blends back to the first key over them
+        runtime_animation.set_loop(PlaybackLoopMode::Loop, 0.0, 14.0, true);
+        for (frame_time, expected_angle) in [(10.0, 1.0), (11.0, 0.75), (12.0, 0.5), (13.0, 0.25), (14.0, 0.0), (15.0, 0.1)] {
+            assert_sampled_angle(&mut runtime_animation, &mut model, frame_time, expected_angle);
+        }
+
+        // without seamless looping the last key is held until the loop end
+        runtime_animation.set_loop(PlaybackLoopMode::Loop, 0.0, 14.0, false);
+        assert_sampled_angle(&mut runtime_animation, &mut model, 12.0, 1.0);
+    }
+
+    #[test]
+    fn edited_tracks_are_sampled_after_the_state_is_reset() {
+        let metadata = single_bone_metadata();
+        let mut model = MmdModel::new(MetadataBuffer::new(&metadata));
+        let mut track = MmdBoneAnimationTrack::new(2);
+        track.frame_numbers[1] = 10;
+        track.rotations_mut()[1] = Quat::from_rotation_y(1.0);
+        let animation = Box::into_raw(Box::new(MmdAnimation::new(
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        )));
+        let mut runtime_animation = MmdRuntimeAnimation::new(animation, Box::new([0]), Box::new([]), Box::new([]), Box::new([]));
+
+        // caches the segment ending at the second keyframe
+        assert_sampled_angle(&mut runtime_animation, &mut model, 8.0, 0.8);
+
+        // a keyframe inserted before the cached one shifts it
+        unsafe {
+            (*animation).edit_bone_track(0, |track, curve_table| {
+                let index = track.insert_keyframe(curve_table, 5);
+                track.rotations_mut()[index as u32] = Quat::from_rotation_y(1.5);
+            });
+        }
+        runtime_animation.reset_state();
+        assert_sampled_angle(&mut runtime_animation, &mut model, 8.0, 1.2);
+
+        // the cached keyframe no longer exists
+        unsafe {
+            assert_eq!((*animation).edit_bone_track(0, |track, _| track.remove_keyframe(2)), Some(true));
+        }
+        runtime_animation.reset_state();
+        assert_sampled_angle(&mut runtime_animation, &mut model, 8.0, 1.5);
+    }
+}
@@ -1,5 +1,14 @@
-mod mmd_animation;
-mod mmd_animation_track;
+pub(crate) mod mmd_animation;
+pub(crate) mod mmd_animation_track;
 mod bezier_interpolation;
+mod retargeting;
+mod root_motion;
+mod keyframe_reduction;
+mod compressed_animation_track;
+mod ik_baking;
+mod animation_composition;
+mod pose_recording;
 pub(crate) mod mmd_runtime_animation;
+pub(crate) mod mmd_camera_animation;
+pub(crate) mod mmd_light_animation;
 pub(crate) mod animation_pool;
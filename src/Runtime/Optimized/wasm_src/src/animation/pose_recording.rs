@@ -0,0 +1,198 @@
+use glam::{Quat, Vec3};
+
+use crate::mmd_model::MmdModel;
+
+use super::keyframe_reduction::quat_angle;
+use super::mmd_animation::MmdAnimation;
+use super::mmd_animation_track::{MmdBoneAnimationTrack, MmdMorphAnimationTrack, MmdMovableBoneAnimationTrack};
+use super::mmd_runtime_animation::{AppendedTracks, MmdRuntimeAnimation};
+
+// differences within these are not written when only changes are recorded
+const ROTATION_EPSILON: f32 = 1.0e-4;
+const POSITION_EPSILON: f32 = 1.0e-4;
+const WEIGHT_EPSILON: f32 = 1.0e-4;
+
+// writes the current animation arena pose of model into animation at frame_number,
+// animation must be the animation of runtime_animation which provides the track bindings,
+// bones and morphs without a track get new tracks that are returned so that they can be bound,
+// with changed_only, values that the animation already evaluates to (the rest pose for new tracks) are skipped,
+// with post_ik, the ik solvers enabled in the pose are disabled by a property keyframe at frame_number
+// and None is returned if one of them has no ik state in the property track
+pub(crate) fn record_pose(
+    animation: &mut MmdAnimation,
+    runtime_animation: &MmdRuntimeAnimation,
+    model: &MmdModel,
+    frame_number: u32,
+    post_ik: bool,
+    changed_only: bool,
+) -> Option<AppendedTracks> {
+    // retargeted values can not be mapped back to the tracks
+    if animation.compressed_tracks().is_some() || animation.is_additive() || runtime_animation.retargeting().is_some() {
+        return None;
+    }
+
+    let animation_arena = model.animation_arena();
+    let animated_bones = animation_arena.bone_arena();
+    let morphs = animation_arena.morph_arena();
+    let bones = model.bone_arena().arena();
+    let frame_time = frame_number as f32;
+
+    // post ik rotations are only meaningful while the ik solvers are disabled on playback
+    let mut disabled_iks = Vec::new();
+    if post_ik {
+        let ik_solver_bind_index_map = runtime_animation.ik_solver_bind_index_map();
+        if ik_solver_bind_index_map.len() != animation.property_track().ik_count() {
+            return None;
+        }
+        let ik_states = animation_arena.iksolver_state_arena();
+        for ik_solver in 0..ik_states.len() as u32 {
+            if ik_states[ik_solver] == 0 {
+                continue;
+            }
+            let ik_index = ik_solver_bind_index_map.iter().position(|bound_ik_solver| *bound_ik_solver as u32 == ik_solver)?;
+            disabled_iks.push(ik_index);
+        }
+    }
+
+    let rotation_of = |bone: u32| -> Quat {
+        let rotation = animated_bones[bone].rotation;
+        match bones[bone].ik_rotation {
+            Some(ik_rotation) if post_ik => ik_rotation * rotation,
+            _ => rotation,
+        }
+    };
+    let position_offset_of = |bone: u32| -> Vec3 {
+        Vec3::from(animated_bones[bone].position - bones[bone].rest_position)
+    };
+
+    let mut has_track = vec![false; bones.len()];
+    for (track_index, bone) in runtime_animation.bone_bind_index_map().iter().enumerate() {
+        if bones.get(*bone as u32).is_none() {
+            continue;
+        }
+        let bone = *bone as u32;
+        has_track[bone as usize] = true;
+
+        let rotation = rotation_of(bone);
+        animation.edit_bone_track(track_index, |track, curve_table| {
+            if changed_only && quat_angle(track.sample_rotation(curve_table, frame_time), rotation) <= ROTATION_EPSILON {
+                return;
+            }
+            let index = track.insert_keyframe(curve_table, frame_number);
+            track.rotations_mut()[index as u32] = rotation;
+        });
+    }
+    for (track_index, bone) in runtime_animation.movable_bone_bind_index_map().iter().enumerate() {
+        if bones.get(*bone as u32).is_none() {
+            continue;
+        }
+        let bone = *bone as u32;
+        has_track[bone as usize] = true;
+
+        let position_offset = position_offset_of(bone);
+        let rotation = rotation_of(bone);
+        animation.edit_movable_bone_track(track_index, |track, curve_table| {
+            let (sampled_position, sampled_rotation) = track.sample(curve_table, frame_time);
+            if changed_only
+                && sampled_position.distance(position_offset) <= POSITION_EPSILON
+                && quat_angle(sampled_rotation, rotation) <= ROTATION_EPSILON
+            {
+                return;
+            }
+            let index = track.insert_keyframe(curve_table, frame_number);
+            track.positions_mut()[index as u32] = position_offset;
+            track.rotations_mut()[index as u32] = rotation;
+        });
+    }
+
+    let mut has_morph_track = vec![false; morphs.len()];
+    for (track_index, morph_indices) in runtime_animation.morph_bind_index_map().iter().enumerate() {
+        // morphs bound to the same track share a weight, the first one is recorded
+        let morph = match morph_indices.iter().copied().find(|morph| morphs.get(*morph as u32).is_some()) {
+            Some(morph) => morph as u32,
+            None => continue,
+        };
+        for morph in morph_indices.iter() {
+            if let Some(has_morph_track) = has_morph_track.get_mut(*morph as usize) {
+                *has_morph_track = true;
+            }
+        }
+
+        let weight = morphs[morph];
+        animation.edit_morph_track(track_index, |track, curve_table| {
+            if changed_only && (track.sample(curve_table, frame_time) - weight).abs() <= WEIGHT_EPSILON {
+                return;
+            }
+            let index = track.insert_keyframe(curve_table, frame_number);
+            track.weights_mut()[index as u32] = weight;
+        });
+    }
+
+    if !disabled_iks.is_empty() {
+        let property_track = animation.property_track_mut();
+        let index = property_track.insert_keyframe(frame_number);
+        for ik_index in disabled_iks {
+            property_track.ik_states_mut(ik_index)[index as u32] = 0;
+        }
+    }
+
+    // bones driven by physics or by an ik solver other than through recorded post ik rotations are not posed by the user
+    let mut is_poseable: Vec<bool> = bones.iter().map(|bone| !bone.physics_driven && (post_ik || bone.ik_rotation.is_none())).collect();
+    for ik_solver in model.ik_solver_arena().arena().iter() {
+        if let Some(is_poseable) = is_poseable.get_mut(ik_solver.target_bone() as usize) {
+            *is_poseable = false;
+        }
+    }
+
+    let mut appended_tracks = AppendedTracks {
+        bone_bind_index_map: Vec::new(),
+        movable_bone_bind_index_map: Vec::new(),
+        morph_bind_index_map: Vec::new(),
+    };
+
+    // translated bones get a movable track, the rest a rotation only track
+    for bone in 0..bones.len() as u32 {
+        if has_track[bone as usize] || !is_poseable[bone as usize] {
+            continue;
+        }
+        let position_offset = position_offset_of(bone);
+        let rotation = rotation_of(bone);
+        let is_translated = POSITION_EPSILON < position_offset.length();
+        if changed_only && !is_translated && quat_angle(Quat::IDENTITY, rotation) <= ROTATION_EPSILON {
+            continue;
+        }
+
+        if is_translated {
+            let mut track = MmdMovableBoneAnimationTrack::new(1);
+            track.frame_numbers[0] = frame_number;
+            track.positions_mut()[0] = position_offset;
+            track.rotations_mut()[0] = rotation;
+            animation.push_movable_bone_track(track);
+            appended_tracks.movable_bone_bind_index_map.push(bone as i32);
+        } else {
+            let mut track = MmdBoneAnimationTrack::new(1);
+            track.frame_numbers[0] = frame_number;
+            track.rotations_mut()[0] = rotation;
+            animation.push_bone_track(track);
+            appended_tracks.bone_bind_index_map.push(bone as i32);
+        }
+    }
+
+    for morph in 0..morphs.len() as u32 {
+        if has_morph_track[morph as usize] {
+            continue;
+        }
+        let weight = morphs[morph];
+        if changed_only && weight.abs() <= WEIGHT_EPSILON {
+            continue;
+        }
+
+        let mut track = MmdMorphAnimationTrack::new(1);
+        track.frame_numbers[0] = frame_number;
+        track.weights_mut()[0] = weight;
+        animation.push_morph_track(track);
+        appended_tracks.morph_bind_index_map.push(Box::new([morph as i32]));
+    }
+
+    Some(appended_tracks)
+}
@@ -0,0 +1,109 @@
+use glam::{Quat, Vec3, Vec3A};
+
+use crate::mmd_runtime_bone::MmdRuntimeBoneArena;
+
+#[derive(Clone)]
+struct RetargetingCorrection {
+    inverse_parent_correction: Quat,
+    correction: Quat,
+}
+
+// maps motion authored for a source skeleton onto the target model without touching the stored animation
+#[derive(Clone)]
+pub(crate) struct Retargeting {
+    translation_scale: f32,
+    corrections: Box<[RetargetingCorrection]>,
+}
+
+impl Retargeting {
+    // source_rest_positions holds the source skeleton's local rest offset (x, y, z) for each target bone,
+    // leg_bones is (leg, knee, ankle) of the target model, retargeted_bones flags the bones driven by the animation
+    pub(crate) fn new(source_rest_positions: &[f32], bone_arena: &MmdRuntimeBoneArena, leg_bones: &[u32], retargeted_bones: &[bool]) -> Self {
+        let bones = bone_arena.arena();
+        let (source_rest_positions, _) = source_rest_positions.as_chunks::<3>();
+        let source_rest_position = |bone: u32| -> Vec3A {
+            match source_rest_positions.get(bone as usize) {
+                Some(position) => Vec3A::from_array(*position),
+                None => bones[bone].rest_position,
+            }
+        };
+
+        let translation_scale = {
+            let mut source_leg_length = 0.0;
+            let mut target_leg_length = 0.0;
+            for bone in leg_bones.iter().skip(1).copied() {
+                if bones.get(bone).is_none() {
+                    continue;
+                }
+                source_leg_length += source_rest_position(bone).length();
+                target_leg_length += bones[bone].rest_position.length();
+            }
+            if 0.0 < source_leg_length && 0.0 < target_leg_length {
+                target_leg_length / source_leg_length
+            } else {
+                1.0
+            }
+        };
+
+        // mmd skeletons have no rest rotations, so a child's local rest offset is the bone's rest direction
+        let mut rest_corrections = Vec::with_capacity(bones.len());
+        for bone in bones.iter() {
+            let child = bone.child_bones.iter().copied().find(|child| bones[*child].rest_position != Vec3A::ZERO);
+            let correction = match child {
+                Some(child) => {
+                    let target_direction = Vec3::from(bones[child].rest_position).normalize_or_zero();
+                    let source_direction = Vec3::from(source_rest_position(child)).normalize_or_zero();
+                    if target_direction == Vec3::ZERO || source_direction == Vec3::ZERO {
+                        Quat::IDENTITY
+                    } else {
+                        Quat::from_rotation_arc(target_direction, source_direction)
+                    }
+                }
+                None => Quat::IDENTITY,
+            };
+            rest_corrections.push(correction);
+        }
+
+        // bones without a track keep their rest rotation and apply no correction,
+        // so a bone undoes the correction of its nearest retargeted ancestor for the corrections to cancel along the chain
+        let is_retargeted = |bone: u32| retargeted_bones.get(bone as usize).copied().unwrap_or(false);
+        let mut corrections = Vec::with_capacity(bones.len());
+        for (bone, correction) in bones.iter().zip(rest_corrections.iter()) {
+            let mut ancestor = bone.parent_bone;
+            while let Some(ancestor_bone) = ancestor {
+                if is_retargeted(ancestor_bone) {
+                    break;
+                }
+                ancestor = bones[ancestor_bone].parent_bone;
+            }
+            let inverse_parent_correction = match ancestor {
+                Some(ancestor) => rest_corrections[ancestor as usize].inverse(),
+                None => Quat::IDENTITY,
+            };
+            corrections.push(RetargetingCorrection {
+                inverse_parent_correction,
+                correction: *correction,
+            });
+        }
+
+        Retargeting {
+            translation_scale,
+            corrections: corrections.into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn retarget_rotation(&self, bone: u32, rotation: Quat) -> Quat {
+        match self.corrections.get(bone as usize) {
+            Some(RetargetingCorrection {inverse_parent_correction, correction}) => {
+                (*inverse_parent_correction * rotation * *correction).normalize()
+            }
+            None => rotation,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn retarget_position_offset(&self, position_offset: Vec3A) -> Vec3A {
+        position_offset * self.translation_scale
+    }
+}
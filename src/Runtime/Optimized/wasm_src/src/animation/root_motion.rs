@@ -0,0 +1,181 @@
+use glam::{Quat, Vec3, Vec3A};
+
+// root motion treats larger jumps between updates as seeks
+pub(crate) const ROOT_MOTION_MAX_FRAME_STEP: f32 = 30.0;
+
+// horizontal translation and yaw of the root bone
+#[derive(Clone, Copy)]
+pub(crate) struct RootTransform {
+    position: Vec3,
+    yaw: f32,
+}
+
+#[inline]
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI
+}
+
+// splits rotation into the twist around the y axis and the remaining swing
+#[inline]
+fn decompose_yaw(rotation: Quat) -> (f32, Quat) {
+    let length = (rotation.y * rotation.y + rotation.w * rotation.w).sqrt();
+    if length < 1.0e-6 {
+        return (0.0, rotation);
+    }
+    let twist = Quat::from_xyzw(0.0, rotation.y / length, 0.0, rotation.w / length);
+    (2.0 * rotation.y.atan2(rotation.w), twist.inverse() * rotation)
+}
+
+impl RootTransform {
+    pub(crate) const IDENTITY: Self = Self {
+        position: Vec3::ZERO,
+        yaw: 0.0,
+    };
+
+    pub(crate) fn from_sample(position_offset: Vec3, rotation: Quat) -> Self {
+        Self {
+            position: Vec3::new(position_offset.x, 0.0, position_offset.z),
+            yaw: decompose_yaw(rotation).0,
+        }
+    }
+
+    // motion from self to other, expressed in the heading of self so that it can be applied after any accumulated yaw
+    pub(crate) fn delta_to(&self, other: &Self) -> Self {
+        Self {
+            position: Quat::from_rotation_y(-self.yaw) * (other.position - self.position),
+            yaw: wrap_angle(other.yaw - self.yaw),
+        }
+    }
+
+    pub(crate) fn then(&self, other: &Self) -> Self {
+        Self {
+            position: self.position + Quat::from_rotation_y(self.yaw) * other.position,
+            yaw: self.yaw + other.yaw,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn to_position_rotation(self) -> (Vec3, Quat) {
+        (self.position, Quat::from_rotation_y(self.yaw))
+    }
+}
+
+pub(crate) struct RootMotion {
+    pub(crate) track_index: usize,
+    pub(crate) last_local_time: Option<f32>,
+    pub(crate) delta: RootTransform,
+}
+
+impl RootMotion {
+    pub(crate) fn new(track_index: usize) -> Self {
+        Self {
+            track_index,
+            last_local_time: None,
+            delta: RootTransform::IDENTITY,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.last_local_time = None;
+        self.delta = RootTransform::IDENTITY;
+    }
+
+    // removes the extracted horizontal translation and yaw from the animated root bone
+    pub(crate) fn strip(position_offset: Vec3A, rotation: Quat) -> (Vec3A, Quat) {
+        (Vec3A::new(0.0, position_offset.y, 0.0), decompose_yaw(rotation).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3};
+
+    use super::ROOT_MOTION_MAX_FRAME_STEP;
+    use crate::animation::mmd_animation::MmdAnimation;
+    use crate::animation::mmd_animation_track::{MmdMovableBoneAnimationTrack, MmdPropertyAnimationTrack};
+    use crate::animation::mmd_runtime_animation::{MmdRuntimeAnimation, PlaybackLoopMode};
+
+    const LOOP_LENGTH: f32 = 30.0;
+
+    // walks 3 units along z over a 30 frame loop while turning by yaw
+    fn walk_runtime_animation(yaw: f32) -> MmdRuntimeAnimation {
+        let mut track = MmdMovableBoneAnimationTrack::new(2);
+        track.frame_numbers[1] = LOOP_LENGTH as u32;
+        track.positions_mut()[1] = Vec3::new(0.0, 0.0, 3.0);
+        track.rotations_mut()[1] = Quat::from_rotation_y(yaw);
+        let animation = MmdAnimation::new(
+            Box::new([]),
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+
+        let mut runtime_animation = MmdRuntimeAnimation::new(animation, Box::new([]), Box::new([0]), Box::new([]), Box::new([]));
+        runtime_animation.set_loop(PlaybackLoopMode::Loop, 0.0, 0.0, false);
+        runtime_animation.set_root_motion_bone(Some(0));
+        runtime_animation
+    }
+
+    // (position, yaw) accumulated the way a character controller applies the deltas
+    fn accumulate(runtime_animation: &mut MmdRuntimeAnimation, frame_times: impl IntoIterator<Item = f32>) -> (Vec3, f32) {
+        let (mut position, mut yaw) = (Vec3::ZERO, 0.0);
+        for frame_time in frame_times {
+            runtime_animation.update_root_motion(frame_time);
+            let (delta_position, delta_rotation) = runtime_animation.root_motion_delta().unwrap();
+            position += Quat::from_rotation_y(yaw) * delta_position;
+            yaw += 2.0 * delta_rotation.y.atan2(delta_rotation.w);
+        }
+        (position, yaw)
+    }
+
+    #[test]
+    fn full_loop_accumulates_one_loop_of_motion() {
+        let mut runtime_animation = walk_runtime_animation(0.0);
+        // starts mid loop and crosses the loop end with steps that do not divide the loop
+        let frame_times = (0..=20).map(|i| 5.0 + LOOP_LENGTH * i as f32 / 20.0 + if i % 3 == 1 { 0.37 } else { 0.0 });
+        let (position, yaw) = accumulate(&mut runtime_animation, frame_times);
+        assert!(position.distance(Vec3::new(0.0, 0.0, 3.0)) < 1.0e-4, "{position}");
+        assert!(yaw.abs() < 1.0e-5);
+
+        // with a turn the yaw of one loop is accumulated, and the part after the wrap continues in the turned heading
+        let turn = 0.6;
+        let mut runtime_animation = walk_runtime_animation(turn);
+        let (position, yaw) = accumulate(&mut runtime_animation, (0..=30).map(|i| 10.0 + i as f32));
+        assert!((yaw - turn).abs() < 1.0e-4, "{yaw}");
+        let start_yaw = turn * 10.0 / LOOP_LENGTH;
+        let expected = Quat::from_rotation_y(-start_yaw) * Vec3::new(0.0, 0.0, 2.0)
+            + Quat::from_rotation_y(turn - start_yaw) * Vec3::new(0.0, 0.0, 1.0);
+        assert!(position.distance(expected) < 1.0e-3, "{position} != {expected}");
+    }
+
+    #[test]
+    fn loop_wrap_delta_joins_both_loop_ends() {
+        let mut runtime_animation = walk_runtime_animation(0.0);
+        runtime_animation.update_root_motion(28.0);
+        runtime_animation.update_root_motion(32.0);
+        let (position, rotation) = runtime_animation.root_motion_delta().unwrap();
+        assert!(position.distance(Vec3::new(0.0, 0.0, 0.4)) < 1.0e-5, "{position}");
+        assert!(rotation.angle_between(Quat::IDENTITY) < 1.0e-3);
+
+        // playing backwards through the loop start moves back by the same amount
+        runtime_animation.update_root_motion(28.0);
+        let (position, _) = runtime_animation.root_motion_delta().unwrap();
+        assert!(position.distance(Vec3::new(0.0, 0.0, -0.4)) < 1.0e-5, "{position}");
+    }
+
+    #[test]
+    fn larger_steps_than_the_max_frame_step_are_seeks() {
+        let mut runtime_animation = walk_runtime_animation(0.0);
+        runtime_animation.update_root_motion(0.0);
+        assert_eq!(runtime_animation.root_motion_delta().unwrap().0, Vec3::ZERO);
+
+        runtime_animation.update_root_motion(ROOT_MOTION_MAX_FRAME_STEP + 1.0);
+        assert_eq!(runtime_animation.root_motion_delta().unwrap().0, Vec3::ZERO);
+
+        // a step of exactly the max frame step is still motion, here one whole loop
+        runtime_animation.update_root_motion(2.0 * ROOT_MOTION_MAX_FRAME_STEP + 1.0);
+        let (position, _) = runtime_animation.root_motion_delta().unwrap();
+        assert!(position.distance(Vec3::new(0.0, 0.0, 3.0)) < 1.0e-4, "{position}");
+    }
+}
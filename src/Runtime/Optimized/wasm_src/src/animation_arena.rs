@@ -10,10 +10,12 @@ pub struct AnimatedBoneData {
     pub(crate) scale: Vec3A,
 }
 
+#[derive(Clone)]
 pub(crate) struct AnimationArena {
     bone_arena: Box<[AnimatedBoneData]>,
     iksolver_state_arena: Box<[u8]>,
     morph_arena: Box<[f32]>,
+    visibility: u8,
 }
 
 impl AnimationArena {
@@ -32,6 +34,53 @@ impl AnimationArena {
             bone_arena: bone_arena.into_boxed_slice(),
             iksolver_state_arena: vec![1; ik_count as usize].into_boxed_slice(),
             morph_arena: vec![0.0; morph_count as usize].into_boxed_slice(),
+            visibility: 1,
+        }
+    }
+
+    pub(crate) fn reset(&mut self, runtime_bones: &[MmdRuntimeBone]) {
+        for (bone, runtime_bone) in self.bone_arena.iter_mut().zip(runtime_bones) {
+            bone.position = runtime_bone.rest_position;
+            bone.rotation = Quat::IDENTITY;
+            bone.scale = Vec3A::ONE;
+        }
+        self.iksolver_state_arena.fill(1);
+        self.morph_arena.fill(0.0);
+        self.visibility = 1;
+    }
+
+    // resets a single bone to the rest pose, out of range indices are ignored
+    pub(crate) fn reset_bone(&mut self, index: i32, runtime_bones: &[MmdRuntimeBone]) {
+        if let (Some(bone), Some(runtime_bone)) = (self.bone_arena.get_mut(index as usize), runtime_bones.get(index as usize)) {
+            bone.position = runtime_bone.rest_position;
+            bone.rotation = Quat::IDENTITY;
+            bone.scale = Vec3A::ONE;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn reset_morph(&mut self, index: i32) {
+        if let Some(morph) = self.morph_arena.get_mut(index as usize) {
+            *morph = 0.0;
+        }
+    }
+
+    pub(crate) fn copy_from(&mut self, other: &AnimationArena) {
+        self.bone_arena.clone_from_slice(&other.bone_arena);
+        self.iksolver_state_arena.copy_from_slice(&other.iksolver_state_arena);
+        self.morph_arena.copy_from_slice(&other.morph_arena);
+        self.visibility = other.visibility;
+    }
+
+    // moves this arena toward other by ratio, ik states and visibility are left to the caller
+    pub(crate) fn blend(&mut self, other: &AnimationArena, ratio: f32) {
+        for (bone, other_bone) in self.bone_arena.iter_mut().zip(other.bone_arena.iter()) {
+            bone.position = bone.position.lerp(other_bone.position, ratio);
+            bone.rotation = bone.rotation.slerp(other_bone.rotation, ratio);
+            bone.scale = bone.scale.lerp(other_bone.scale, ratio);
+        }
+        for (morph, other_morph) in self.morph_arena.iter_mut().zip(other.morph_arena.iter()) {
+            *morph += (other_morph - *morph) * ratio;
         }
     }
 
@@ -55,6 +104,16 @@ impl AnimationArena {
         UncheckedSliceMut::new(&mut self.iksolver_state_arena)
     }
 
+    #[inline]
+    pub(crate) fn visibility(&self) -> u8 {
+        self.visibility
+    }
+
+    #[inline]
+    pub(crate) fn set_visibility(&mut self, visibility: u8) {
+        self.visibility = visibility;
+    }
+
     #[inline]
     pub(crate) fn morph_arena(&self) -> UncheckedSlice<f32> {
         UncheckedSlice::new(&self.morph_arena)
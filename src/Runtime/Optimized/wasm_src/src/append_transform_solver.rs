@@ -5,6 +5,7 @@ use crate::mmd_model_metadata::BoneFlag;
 use crate::animation_arena::AnimationArena;
 use crate::unchecked_slice::{UncheckedSlice, UncheckedSliceMut};
 
+#[derive(Clone)]
 pub(crate) struct AppendTransformSolverArena {
     arena: Box<[AppendTransformSolver]>,
 }
@@ -63,6 +64,7 @@ impl AppendTransformSolverArena {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct AppendTransformSolver {
     is_local: bool,
     affect_rotation: bool,
@@ -0,0 +1,236 @@
+use glam::{Mat4, Quat, Vec2, Vec3, Vec3A};
+
+use crate::ik_solver::IkSolverArena;
+use crate::mmd_runtime_bone::MmdRuntimeBoneArena;
+
+struct GroundPlane {
+    point: Vec3A,
+    normal: Vec3A,
+    min: Vec2,
+    max: Vec2,
+}
+
+impl GroundPlane {
+    fn sample(&self, x: f32, z: f32) -> Option<f32> {
+        if x < self.min.x || self.max.x < x || z < self.min.y || self.max.y < z {
+            return None;
+        }
+        Some(self.point.y - (self.normal.x * (x - self.point.x) + self.normal.z * (z - self.point.z)) / self.normal.y)
+    }
+}
+
+struct HeightField {
+    origin_x: f32,
+    origin_z: f32,
+    cell_size: f32,
+    width: u32,
+    depth: u32,
+    heights: Box<[f32]>,
+}
+
+impl HeightField {
+    #[inline]
+    fn height(&self, x: u32, z: u32) -> f32 {
+        self.heights[(z * self.width + x) as usize]
+    }
+
+    fn sample(&self, x: f32, z: f32) -> Option<(f32, Vec3A)> {
+        if self.width < 2 || self.depth < 2 || self.cell_size <= 0.0 {
+            return None;
+        }
+
+        let grid_x = (x - self.origin_x) / self.cell_size;
+        let grid_z = (z - self.origin_z) / self.cell_size;
+        if grid_x < 0.0 || grid_z < 0.0 || (self.width - 1) as f32 <= grid_x || (self.depth - 1) as f32 <= grid_z {
+            return None;
+        }
+
+        let x0 = grid_x as u32;
+        let z0 = grid_z as u32;
+        let tx = grid_x - x0 as f32;
+        let tz = grid_z - z0 as f32;
+
+        let h00 = self.height(x0, z0);
+        let h10 = self.height(x0 + 1, z0);
+        let h01 = self.height(x0, z0 + 1);
+        let h11 = self.height(x0 + 1, z0 + 1);
+
+        let h0 = h00 + (h10 - h00) * tx;
+        let h1 = h01 + (h11 - h01) * tx;
+        let height = h0 + (h1 - h0) * tz;
+
+        let dx = ((h10 - h00) * (1.0 - tz) + (h11 - h01) * tz) / self.cell_size;
+        let dz = (h1 - h0) / self.cell_size;
+        let normal = Vec3A::new(-dx, 1.0, -dz).normalize();
+
+        Some((height, normal))
+    }
+}
+
+pub(crate) const GROUND_PLANE_STRIDE: usize = 10;
+
+pub(crate) struct Ground {
+    planes: Vec<GroundPlane>,
+    height_field: Option<HeightField>,
+}
+
+impl Ground {
+    pub(crate) fn new() -> Self {
+        Ground {
+            planes: Vec::new(),
+            height_field: None,
+        }
+    }
+
+    // each plane is (point.x, point.y, point.z, normal.x, normal.y, normal.z, min.x, min.z, max.x, max.z),
+    // the plane only covers its x, z bounds which may be infinite
+    pub(crate) fn set_planes(&mut self, planes: &[f32]) {
+        self.planes.clear();
+        let (planes, _) = planes.as_chunks::<GROUND_PLANE_STRIDE>();
+        for plane in planes {
+            let normal = Vec3A::new(plane[3], plane[4], plane[5]).normalize_or_zero();
+            if normal.y <= 1.0e-6 {
+                continue;
+            }
+            self.planes.push(GroundPlane {
+                point: Vec3A::new(plane[0], plane[1], plane[2]),
+                normal,
+                min: Vec2::new(plane[6], plane[7]),
+                max: Vec2::new(plane[8], plane[9]),
+            });
+        }
+    }
+
+    pub(crate) fn set_height_field(&mut self, origin_x: f32, origin_z: f32, cell_size: f32, width: u32, depth: u32, heights: Box<[f32]>) {
+        self.height_field = Some(HeightField {
+            origin_x,
+            origin_z,
+            cell_size,
+            width,
+            depth,
+            heights,
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.planes.clear();
+        self.height_field = None;
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.planes.is_empty() && self.height_field.is_none()
+    }
+
+    // returns the highest ground surface covering (x, z) and its normal
+    pub(crate) fn sample(&self, x: f32, z: f32) -> Option<(f32, Vec3A)> {
+        let mut result = self.height_field.as_ref().and_then(|height_field| height_field.sample(x, z));
+
+        for plane in &self.planes {
+            let height = match plane.sample(x, z) {
+                Some(height) => height,
+                None => continue,
+            };
+            match result {
+                Some((result_height, _)) if height <= result_height => {}
+                _ => result = Some((height, plane.normal)),
+            }
+        }
+
+        result
+    }
+}
+
+impl Default for Ground {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) struct FootGrounding {
+    centre_bone: u32,
+    leg_ik_solvers: Box<[u32]>,
+    world_matrix: Mat4,
+    leg_offsets: Vec<(u32, Vec3A, Vec3A)>,
+}
+
+impl FootGrounding {
+    pub(crate) fn new(centre_bone: u32, leg_ik_solvers: Box<[u32]>) -> Self {
+        let leg_count = leg_ik_solvers.len();
+        FootGrounding {
+            centre_bone,
+            leg_ik_solvers,
+            world_matrix: Mat4::IDENTITY,
+            leg_offsets: Vec::with_capacity(leg_count),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_world_matrix(&mut self, world_matrix: Mat4) {
+        self.world_matrix = world_matrix;
+    }
+
+    // moves the centre and the leg ik bones onto the ground before the ik solvers run,
+    // the motion is assumed to be authored on the model's y = 0 plane
+    pub(crate) fn offset_ik_bones(&mut self, ground: &Ground, bone_arena: &mut MmdRuntimeBoneArena, ik_solver_arena: &mut IkSolverArena) {
+        self.leg_offsets.clear();
+        if ground.is_empty() || bone_arena.arena().get(self.centre_bone).is_none() {
+            return;
+        }
+
+        let inverse_world_matrix = self.world_matrix.inverse();
+        let floor_height = self.world_matrix.w_axis.y;
+
+        let mut centre_offset = f32::MAX;
+        for ik_solver in self.leg_ik_solvers.iter().copied() {
+            let ik_bone = match ik_solver_arena.arena_mut().get(ik_solver) {
+                Some(ik_solver) => ik_solver.ik_bone(),
+                None => continue,
+            };
+            let ik_position = Vec3A::from(bone_arena.world_matrices()[ik_bone].w_axis);
+            let world_ik_position = self.world_matrix.transform_point3a(ik_position);
+
+            let (height, normal) = match ground.sample(world_ik_position.x, world_ik_position.z) {
+                Some((height, normal)) => (height - floor_height, normal),
+                None => (0.0, Vec3A::Y),
+            };
+            let offset = inverse_world_matrix.transform_vector3a(Vec3A::new(0.0, height, 0.0));
+            let normal = inverse_world_matrix.transform_vector3a(normal).normalize_or_zero();
+            self.leg_offsets.push((ik_solver, ik_position + offset, normal));
+
+            centre_offset = centre_offset.min(height);
+        }
+        if self.leg_offsets.is_empty() {
+            return;
+        }
+
+        {
+            let offset = inverse_world_matrix.transform_vector3a(Vec3A::new(0.0, centre_offset, 0.0));
+            let centre_world_matrix = Mat4::from_translation(offset.into()) * bone_arena.world_matrices()[self.centre_bone];
+            bone_arena.set_world_matrix(self.centre_bone, centre_world_matrix);
+        }
+
+        // the grounded positions are absolute so that ik bones parented to the centre are not offset twice
+        let ik_solvers = ik_solver_arena.arena_mut();
+        for (ik_solver, position, _) in self.leg_offsets.iter() {
+            let ik_bone = ik_solvers[*ik_solver].ik_bone();
+            let (scale, rotation, _) = bone_arena.world_matrices()[ik_bone].to_scale_rotation_translation();
+            bone_arena.set_world_matrix(ik_bone, Mat4::from_scale_rotation_translation(scale, rotation, (*position).into()));
+        }
+    }
+
+    // aligns the foot of a solved leg to the ground normal
+    pub(crate) fn align_target_bone(&self, ik_solver: u32, target_bone: u32, bone_arena: &mut MmdRuntimeBoneArena) {
+        let normal = match self.leg_offsets.iter().find(|(leg_ik_solver, _, _)| *leg_ik_solver == ik_solver) {
+            Some((_, _, normal)) => *normal,
+            None => return,
+        };
+        if normal == Vec3A::ZERO {
+            return;
+        }
+
+        let (scale, rotation, translation) = bone_arena.world_matrices()[target_bone].to_scale_rotation_translation();
+        let rotation = Quat::from_rotation_arc(Vec3::Y, normal.into()) * rotation;
+        bone_arena.set_world_matrix(target_bone, Mat4::from_scale_rotation_translation(scale, rotation, translation));
+    }
+}
@@ -0,0 +1,518 @@
+use std::fmt::Write;
+
+use glam::{Mat4, Quat, Vec3};
+
+use crate::animation::mmd_runtime_animation::MmdRuntimeAnimation;
+use crate::mmd_model::MmdModel;
+
+const MMD_FRAME_RATE: f32 = 30.0;
+
+const GLB_MAGIC: u32 = 0x46546C67; // "glTF"
+const GLB_VERSION: u32 = 2;
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A; // "JSON"
+const GLB_CHUNK_BIN: u32 = 0x004E4942; // "BIN\0"
+
+const COMPONENT_TYPE_UNSIGNED_SHORT: u32 = 5123;
+const COMPONENT_TYPE_FLOAT: u32 = 5126;
+
+// local scales closer to 1 than this are decomposition noise, mmd bones are never scaled by animation
+const SCALE_EPSILON: f32 = 1.0e-4;
+
+// mmd runtime data is left handed, gltf is right handed with +z forward
+#[inline]
+fn to_right_handed_position(position: Vec3) -> Vec3 {
+    Vec3::new(position.x, position.y, -position.z)
+}
+
+#[inline]
+fn to_right_handed_rotation(rotation: Quat) -> Quat {
+    Quat::from_xyzw(-rotation.x, -rotation.y, rotation.z, rotation.w)
+}
+
+fn write_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+// json has no nan or infinity, the caller rejects non-finite values before writing
+fn write_json_number(json: &mut String, value: f32) {
+    debug_assert!(value.is_finite());
+    let _ = write!(json, "{}", if value.is_finite() { value } else { 0.0 });
+}
+
+fn write_json_numbers(json: &mut String, values: &[f32]) {
+    json.push('[');
+    for (i, value) in values.iter().enumerate() {
+        if 0 < i {
+            json.push(',');
+        }
+        write_json_number(json, *value);
+    }
+    json.push(']');
+}
+
+struct BinaryBuffer {
+    bytes: Vec<u8>,
+    buffer_views: Vec<(usize, usize)>,
+    accessors: String,
+    accessor_count: u32,
+}
+
+impl BinaryBuffer {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            buffer_views: Vec::new(),
+            accessors: String::new(),
+            accessor_count: 0,
+        }
+    }
+
+    // appends a float accessor and returns its index, min and max are written for accessors that require them
+    fn push_accessor(&mut self, values: &[f32], accessor_type: &str, component_count: usize, bounds: bool) -> u32 {
+        let offset = self.bytes.len();
+        for value in values {
+            self.bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let buffer_view = self.buffer_views.len();
+        self.buffer_views.push((offset, values.len() * 4));
+
+        if 0 < self.accessor_count {
+            self.accessors.push(',');
+        }
+        let count = values.len() / component_count;
+        let _ = write!(
+            self.accessors,
+            "{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\"",
+            buffer_view, COMPONENT_TYPE_FLOAT, count, accessor_type
+        );
+        if bounds {
+            let mut min = vec![f32::MAX; component_count];
+            let mut max = vec![f32::MIN; component_count];
+            for element in values.chunks_exact(component_count) {
+                for (i, value) in element.iter().enumerate() {
+                    min[i] = min[i].min(*value);
+                    max[i] = max[i].max(*value);
+                }
+            }
+            self.accessors.push_str(",\"min\":");
+            write_json_numbers(&mut self.accessors, &min);
+            self.accessors.push_str(",\"max\":");
+            write_json_numbers(&mut self.accessors, &max);
+        }
+        self.accessors.push('}');
+
+        self.accessor_count += 1;
+        self.accessor_count - 1
+    }
+
+    // appends an unsigned short VEC4 accessor for vertex joints and returns its index
+    fn push_joints_accessor(&mut self, joints: &[u16]) -> u32 {
+        let offset = self.bytes.len();
+        for joint in joints {
+            self.bytes.extend_from_slice(&joint.to_le_bytes());
+        }
+        let buffer_view = self.buffer_views.len();
+        self.buffer_views.push((offset, joints.len() * 2));
+
+        if 0 < self.accessor_count {
+            self.accessors.push(',');
+        }
+        let _ = write!(
+            self.accessors,
+            "{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"VEC4\"}}",
+            buffer_view, COMPONENT_TYPE_UNSIGNED_SHORT, joints.len() / 4
+        );
+
+        self.accessor_count += 1;
+        self.accessor_count - 1
+    }
+}
+
+struct AnimationChannels {
+    channels: String,
+    samplers: String,
+    sampler_count: u32,
+}
+
+impl AnimationChannels {
+    fn new() -> Self {
+        Self {
+            channels: String::new(),
+            samplers: String::new(),
+            sampler_count: 0,
+        }
+    }
+
+    fn push(&mut self, input_accessor: u32, output_accessor: u32, node: usize, path: &str) {
+        if 0 < self.sampler_count {
+            self.samplers.push(',');
+            self.channels.push(',');
+        }
+        let _ = write!(self.samplers, "{{\"input\":{},\"output\":{},\"interpolation\":\"LINEAR\"}}", input_accessor, output_accessor);
+        let _ = write!(self.channels, "{{\"sampler\":{},\"target\":{{\"node\":{},\"path\":\"{}\"}}}}", self.sampler_count, node, path);
+        self.sampler_count += 1;
+    }
+}
+
+// exports the skeleton as a skin of a point mesh and runtime_animation sampled every frame from start_frame to end_frame as a glb file,
+// bone_names and morph_names are newline separated, missing names fall back to the index.
+// returns an empty vec if the rest pose or the baked animation has non-finite values
+pub(crate) fn export_glb(
+    model: &MmdModel,
+    runtime_animation: &mut MmdRuntimeAnimation,
+    start_frame: f32,
+    end_frame: f32,
+    bone_names: &str,
+    morph_names: &str,
+) -> Vec<u8> {
+    let bones = model.bone_arena().arena();
+    let bone_count = bones.len();
+    let mut bone_names = bone_names.split('\n');
+    let mut morph_names = morph_names.split('\n');
+
+    let baked = model.bake_runtime_animation(runtime_animation, start_frame, end_frame, 1.0);
+    let morph_count = model.morph_count();
+    let frame_size = bone_count * 16 + morph_count;
+    let frame_count = baked.len().checked_div(frame_size).unwrap_or(0);
+    if !bones.iter().all(|bone| bone.rest_position.is_finite()) || !baked.iter().all(|value| value.is_finite()) {
+        return Vec::new();
+    }
+
+    let mut buffer = BinaryBuffer::new();
+    let mut json = String::new();
+    json.push_str("{\"asset\":{\"version\":\"2.0\",\"generator\":\"babylon-mmd\"},\"scene\":0");
+
+    // rest pose nodes, mmd bones have no rest rotation so the rest translation is the local offset
+    let mut rest_world_positions = vec![Vec3::ZERO; bone_count];
+    let mut nodes = String::new();
+    for (i, bone) in bones.iter().enumerate() {
+        let rest_position = to_right_handed_position(bone.rest_position.into());
+        rest_world_positions[i] = rest_position;
+
+        if 0 < i {
+            nodes.push(',');
+        }
+        nodes.push_str("{\"name\":");
+        match bone_names.next() {
+            Some(name) if !name.is_empty() => write_json_string(&mut nodes, name),
+            _ => {
+                let _ = write!(nodes, "\"bone{}\"", i);
+            }
+        }
+        nodes.push_str(",\"translation\":");
+        write_json_numbers(&mut nodes, &rest_position.to_array());
+        if !bone.child_bones.is_empty() {
+            let _ = write!(nodes, ",\"children\":{:?}", bone.child_bones);
+        }
+        nodes.push('}');
+    }
+    // parents can come after their children in the arena, so accumulate along the chain
+    for (i, bone) in bones.iter().enumerate() {
+        let mut parent_bone = bone.parent_bone;
+        while let Some(parent) = parent_bone {
+            rest_world_positions[i] += to_right_handed_position(bones[parent].rest_position.into());
+            parent_bone = bones[parent].parent_bone;
+        }
+    }
+
+    let mut inverse_bind_matrices = Vec::with_capacity(bone_count * 16);
+    for rest_world_position in rest_world_positions.iter() {
+        inverse_bind_matrices.extend_from_slice(&Mat4::from_translation(-*rest_world_position).to_cols_array());
+    }
+    let inverse_bind_matrices_accessor = buffer.push_accessor(&inverse_bind_matrices, "MAT4", 16, false);
+
+    let mut scene_nodes: Vec<usize> = (0..bone_count).filter(|i| bones[*i as u32].parent_bone.is_none()).collect();
+
+    // gltf skins and morph weights belong to a mesh, so both are carried by a single point mesh
+    // with empty targets whose vertex is bound to the first joint
+    let mut meshes = String::new();
+    if 0 < bone_count || 0 < morph_count {
+        let position_accessor = buffer.push_accessor(&[0.0, 0.0, 0.0], "VEC3", 3, true);
+        let _ = write!(meshes, "{{\"name\":\"model\",\"primitives\":[{{\"attributes\":{{\"POSITION\":{}", position_accessor);
+        if 0 < bone_count {
+            let joints_accessor = buffer.push_joints_accessor(&[0, 0, 0, 0]);
+            let weights_accessor = buffer.push_accessor(&[1.0, 0.0, 0.0, 0.0], "VEC4", 4, false);
+            let _ = write!(meshes, ",\"JOINTS_0\":{},\"WEIGHTS_0\":{}", joints_accessor, weights_accessor);
+        }
+        meshes.push_str("},\"mode\":0");
+
+        if 0 < morph_count {
+            let target_accessor = buffer.push_accessor(&[0.0, 0.0, 0.0], "VEC3", 3, true);
+            meshes.push_str(",\"targets\":[");
+            for i in 0..morph_count {
+                if 0 < i {
+                    meshes.push(',');
+                }
+                let _ = write!(meshes, "{{\"POSITION\":{}}}", target_accessor);
+            }
+            meshes.push_str("]}],\"weights\":");
+            write_json_numbers(&mut meshes, &vec![0.0; morph_count]);
+            meshes.push_str(",\"extras\":{\"targetNames\":[");
+            for i in 0..morph_count {
+                if 0 < i {
+                    meshes.push(',');
+                }
+                match morph_names.next() {
+                    Some(name) if !name.is_empty() => write_json_string(&mut meshes, name),
+                    _ => {
+                        let _ = write!(meshes, "\"morph{}\"", i);
+                    }
+                }
+            }
+            meshes.push_str("]}}");
+        } else {
+            meshes.push_str("}]}");
+        }
+
+        if 0 < bone_count {
+            nodes.push(',');
+        }
+        nodes.push_str("{\"name\":\"model\",\"mesh\":0");
+        if 0 < bone_count {
+            nodes.push_str(",\"skin\":0");
+        }
+        nodes.push('}');
+        scene_nodes.push(bone_count);
+    }
+
+    let mut animation_channels = AnimationChannels::new();
+    if 0 < frame_count {
+        let times: Vec<f32> = (0..frame_count).map(|i| i as f32 / MMD_FRAME_RATE).collect();
+        let time_accessor = buffer.push_accessor(&times, "SCALAR", 1, true);
+
+        let mut translations = vec![0.0; frame_count * 3];
+        let mut rotations = vec![0.0; frame_count * 4];
+        let mut scales = vec![0.0; frame_count * 3];
+        for (i, bone) in bones.iter().enumerate() {
+            let mut previous_rotation = Quat::IDENTITY;
+            let mut scaled = false;
+            for frame in 0..frame_count {
+                let frame_offset = frame * frame_size;
+                let world_matrix = Mat4::from_cols_slice(&baked[frame_offset + i * 16..frame_offset + i * 16 + 16]);
+                let local_matrix = match bone.parent_bone {
+                    Some(parent) => {
+                        let parent_offset = frame_offset + parent as usize * 16;
+                        Mat4::from_cols_slice(&baked[parent_offset..parent_offset + 16]).inverse() * world_matrix
+                    }
+                    None => world_matrix,
+                };
+                let (scale, rotation, translation) = local_matrix.to_scale_rotation_translation();
+                let translation = to_right_handed_position(translation);
+                let mut rotation = to_right_handed_rotation(rotation.normalize());
+                // keep neighbouring keys on the same hemisphere so the importer interpolates the short way
+                if rotation.dot(previous_rotation) < 0.0 {
+                    rotation = -rotation;
+                }
+                previous_rotation = rotation;
+
+                translations[frame * 3..frame * 3 + 3].copy_from_slice(&translation.to_array());
+                rotations[frame * 4..frame * 4 + 4].copy_from_slice(&rotation.to_array());
+                scales[frame * 3..frame * 3 + 3].copy_from_slice(&scale.to_array());
+                scaled |= SCALE_EPSILON < (scale - Vec3::ONE).abs().max_element();
+            }
+
+            for (path, values, accessor_type, component_count) in [
+                ("translation", &translations, "VEC3", 3),
+                ("rotation", &rotations, "VEC4", 4),
+            ] {
+                let output_accessor = buffer.push_accessor(values, accessor_type, component_count, false);
+                animation_channels.push(time_accessor, output_accessor, i, path);
+            }
+            // the rest scale is 1, so the channel is only needed once some frame is scaled
+            if scaled {
+                let output_accessor = buffer.push_accessor(&scales, "VEC3", 3, false);
+                animation_channels.push(time_accessor, output_accessor, i, "scale");
+            }
+        }
+
+        if 0 < morph_count {
+            let mut weights = Vec::with_capacity(frame_count * morph_count);
+            for frame in 0..frame_count {
+                let morph_offset = frame * frame_size + bone_count * 16;
+                weights.extend_from_slice(&baked[morph_offset..morph_offset + morph_count]);
+            }
+            let output_accessor = buffer.push_accessor(&weights, "SCALAR", 1, false);
+            animation_channels.push(time_accessor, output_accessor, bone_count, "weights");
+        }
+    }
+
+    let _ = write!(json, ",\"scenes\":[{{\"nodes\":{:?}}}],\"nodes\":[{}]", scene_nodes, nodes);
+    if 0 < bone_count {
+        let joints: Vec<usize> = (0..bone_count).collect();
+        let _ = write!(json, ",\"skins\":[{{\"inverseBindMatrices\":{},\"joints\":{:?}}}]", inverse_bind_matrices_accessor, joints);
+    }
+    if !meshes.is_empty() {
+        let _ = write!(json, ",\"meshes\":[{}]", meshes);
+    }
+    if 0 < animation_channels.sampler_count {
+        let _ = write!(
+            json,
+            ",\"animations\":[{{\"name\":\"animation\",\"channels\":[{}],\"samplers\":[{}]}}]",
+            animation_channels.channels, animation_channels.samplers
+        );
+    }
+    let _ = write!(json, ",\"accessors\":[{}],\"bufferViews\":[", buffer.accessors);
+    for (i, (offset, length)) in buffer.buffer_views.iter().enumerate() {
+        if 0 < i {
+            json.push(',');
+        }
+        let _ = write!(json, "{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{}}}", offset, length);
+    }
+    let _ = write!(json, "],\"buffers\":[{{\"byteLength\":{}}}]}}", buffer.bytes.len());
+
+    let mut json = json.into_bytes();
+    while json.len() % 4 != 0 {
+        json.push(b' ');
+    }
+    let mut bin = buffer.bytes;
+    while bin.len() % 4 != 0 {
+        bin.push(0);
+    }
+
+    let total_length = 12 + 8 + json.len() + 8 + bin.len();
+    let mut glb = Vec::with_capacity(total_length);
+    glb.extend_from_slice(&GLB_MAGIC.to_le_bytes());
+    glb.extend_from_slice(&GLB_VERSION.to_le_bytes());
+    glb.extend_from_slice(&(total_length as u32).to_le_bytes());
+    glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    glb.extend_from_slice(&GLB_CHUNK_JSON.to_le_bytes());
+    glb.extend_from_slice(&json);
+    glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+    glb.extend_from_slice(&GLB_CHUNK_BIN.to_le_bytes());
+    glb.extend_from_slice(&bin);
+    glb
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+    use serde_json::Value;
+
+    use super::{export_glb, write_json_numbers, GLB_CHUNK_BIN, GLB_CHUNK_JSON, GLB_MAGIC};
+    use crate::animation::mmd_animation::MmdAnimation;
+    use crate::animation::mmd_animation_track::{MmdBoneAnimationTrack, MmdPropertyAnimationTrack};
+    use crate::animation::mmd_runtime_animation::MmdRuntimeAnimation;
+    use crate::mmd_model::MmdModel;
+    use crate::mmd_model_metadata::MetadataBuffer;
+
+    // a chain of bones one unit apart along y and a single empty group morph
+    fn chain_metadata(bone_count: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for count in [bone_count, 0, 0] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        for i in 0..bone_count {
+            let rest_y = if i == 0 { 0.0_f32 } else { 1.0 };
+            for value in [0.0, rest_y, 0.0] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&(i as i32 - 1).to_le_bytes());
+            bytes.extend_from_slice(&0_i32.to_le_bytes());
+            bytes.extend_from_slice(&0_u16.to_le_bytes());
+            bytes.extend_from_slice(&[0; 2]);
+        }
+        bytes.extend_from_slice(&1_u32.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&0_i32.to_le_bytes());
+        for count in [0_u32, 0] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn chunk(glb: &[u8], offset: usize, chunk_type: u32) -> &[u8] {
+        let length = u32::from_le_bytes(glb[offset..offset + 4].try_into().unwrap()) as usize;
+        assert_eq!(u32::from_le_bytes(glb[offset + 4..offset + 8].try_into().unwrap()), chunk_type);
+        &glb[offset + 8..offset + 8 + length]
+    }
+
+    fn component_count(accessor_type: &str) -> u64 {
+        match accessor_type {
+            "SCALAR" => 1,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            "MAT4" => 16,
+            _ => panic!("unexpected accessor type {accessor_type}"),
+        }
+    }
+
+    #[test]
+    fn exported_accessors_match_their_buffer_views() {
+        let metadata = chain_metadata(3);
+        let model = MmdModel::new(MetadataBuffer::new(&metadata));
+
+        let mut track = MmdBoneAnimationTrack::new(2);
+        track.frame_numbers[1] = 10;
+        track.rotations_mut()[1] = Quat::from_rotation_z(1.0);
+        let animation = MmdAnimation::new(
+            vec![track].into_boxed_slice(),
+            Box::new([]),
+            Box::new([]),
+            MmdPropertyAnimationTrack::new(0, 0),
+        );
+        let animation: &'static MmdAnimation = Box::leak(Box::new(animation));
+        let mut runtime_animation = MmdRuntimeAnimation::new(animation, Box::new([1]), Box::new([]), Box::new([]), Box::new([]));
+
+        let glb = export_glb(&model, &mut runtime_animation, 0.0, 10.0, "root\nmiddle", "");
+        assert_eq!(u32::from_le_bytes(glb[0..4].try_into().unwrap()), GLB_MAGIC);
+        assert_eq!(u32::from_le_bytes(glb[8..12].try_into().unwrap()) as usize, glb.len());
+        let json_chunk = chunk(&glb, 12, GLB_CHUNK_JSON);
+        let bin_chunk = chunk(&glb, 12 + 8 + json_chunk.len(), GLB_CHUNK_BIN);
+        let json: Value = serde_json::from_slice(json_chunk).expect("the json chunk is valid json");
+
+        let buffer_length = json["buffers"][0]["byteLength"].as_u64().unwrap();
+        assert!(buffer_length <= bin_chunk.len() as u64);
+
+        let buffer_views = json["bufferViews"].as_array().unwrap();
+        let accessors = json["accessors"].as_array().unwrap();
+        for accessor in accessors {
+            let buffer_view = &buffer_views[accessor["bufferView"].as_u64().unwrap() as usize];
+            let component_size = match accessor["componentType"].as_u64().unwrap() {
+                5123 => 2,
+                5126 => 4,
+                component_type => panic!("unexpected component type {component_type}"),
+            };
+            let count = accessor["count"].as_u64().unwrap();
+            let byte_offset = buffer_view["byteOffset"].as_u64().unwrap();
+            let byte_length = buffer_view["byteLength"].as_u64().unwrap();
+            assert_eq!(byte_length, count * component_count(accessor["type"].as_str().unwrap()) * component_size, "{accessor}");
+            assert_eq!(byte_offset % component_size, 0);
+            assert!(byte_offset + byte_length <= buffer_length);
+        }
+
+        // 11 sampled frames, one node per bone plus the mesh node
+        let nodes = json["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(nodes[1]["name"], "middle");
+        assert_eq!(nodes[2]["name"], "bone2");
+        let skin = &json["skins"][0];
+        assert_eq!(skin["joints"].as_array().unwrap().len(), 3);
+        assert_eq!(accessors[skin["inverseBindMatrices"].as_u64().unwrap() as usize]["count"], 3);
+        for sampler in json["animations"][0]["samplers"].as_array().unwrap() {
+            let input = &accessors[sampler["input"].as_u64().unwrap() as usize];
+            let output = &accessors[sampler["output"].as_u64().unwrap() as usize];
+            assert_eq!(input["count"], 11);
+            assert_eq!(output["count"].as_u64().unwrap() % 11, 0);
+        }
+    }
+
+    #[test]
+    fn json_numbers_are_valid_json() {
+        let mut json = String::new();
+        write_json_numbers(&mut json, &[0.0, -0.0, 1.5, 1.0e-30, f32::MAX, f32::MIN]);
+        let values: Vec<f64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(values.len(), 6);
+        assert_eq!(values[2], 1.5);
+    }
+}
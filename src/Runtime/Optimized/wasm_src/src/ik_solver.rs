@@ -1,11 +1,14 @@
-use glam::{Vec3, Vec3A, Vec4, Mat3, Quat};
+use glam::{Vec3, Vec3A, Vec4, Mat3, Mat4, Quat};
 
-use crate::unchecked_slice::UncheckedSliceMut;
+use crate::unchecked_slice::{UncheckedSlice, UncheckedSliceMut};
 use crate::mmd_runtime_bone::{MmdRuntimeBone, MmdRuntimeBoneArena};
 use crate::mmd_model_metadata::IkChainAngleLimits;
 use crate::animation_arena::AnimationArena;
 use crate::append_transform_solver::AppendTransformSolverArena;
 
+const FABRIK_TOLERANCE: f32 = 1.0e-4;
+
+#[derive(Clone)]
 pub(crate) struct IkSolverArena {
     arena: Box<[IkSolver]>,    
 }
@@ -17,12 +20,18 @@ impl IkSolverArena {
         }
     }
 
+    #[inline]
+    pub(crate) fn arena(&self) -> UncheckedSlice<'_, IkSolver> {
+        UncheckedSlice::new(&self.arena)
+    }
+
     #[inline]
     pub(crate) fn arena_mut(&mut self) -> UncheckedSliceMut<IkSolver> {
         UncheckedSliceMut::new(&mut self.arena)
     }
 }
 
+#[derive(Clone)]
 struct IkChain {
     bone: u32,
     angle_limits: Option<IkChainAngleLimits>,
@@ -52,13 +61,75 @@ enum SolveAxis {
     Z,
 }
 
+#[repr(C)]
+#[derive(Clone)]
+pub(crate) struct IkSolverDiagnostics {
+    pub(crate) distance: f32,
+    pub(crate) iteration_count: u32, // iterations executed, a rejected ccd iteration included
+    pub(crate) limit_hit_mask: u32, // bit n is set when ik_chains[n] hit its angle limits, bit 31 also covers the links after it
+    pub(crate) early_exit: u8,
+    pub(crate) solved: u8,
+    _padding: [u8; 2],
+}
+
+impl IkSolverDiagnostics {
+    pub(crate) fn new() -> Self {
+        IkSolverDiagnostics {
+            distance: 0.0,
+            iteration_count: 0,
+            limit_hit_mask: 0,
+            early_exit: 0,
+            solved: 0,
+            _padding: [0; 2],
+        }
+    }
+}
+
+// links past the mask width are reported on its last bit
+#[inline]
+fn limit_hit_bit(chain_index: usize) -> u32 {
+    1 << chain_index.min(u32::BITS as usize - 1)
+}
+
+#[derive(Clone)]
+struct IkTargetPin {
+    position: Vec3A,
+    rotation: Option<Quat>,
+    weight: f32,
+    fade_out_rate: f32, // weight removed per frame while the pin is being released
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IkSolverMode {
+    Ccd = 0,
+    TwoBone = 1,
+    Fabrik = 2,
+}
+
+impl IkSolverMode {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(IkSolverMode::Ccd),
+            1 => Some(IkSolverMode::TwoBone),
+            2 => Some(IkSolverMode::Fabrik),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct IkSolver {
+    mode: IkSolverMode,
     iteration: i32,
     limit_angle: f32,
 
     ik_bone: u32,
     target_bone: u32,
     ik_chains: Vec<IkChain>,
+    fabrik_positions: Vec<Vec3A>,
+    fabrik_lengths: Vec<f32>,
+    limit_hit_mask: u32,
+    target_pin: Option<IkTargetPin>,
 }
 
 impl IkSolver {
@@ -70,12 +141,112 @@ impl IkSolver {
         chain_capacity: u32,
     ) -> IkSolver {
         IkSolver {
+            mode: IkSolverMode::Ccd,
             iteration,
             limit_angle,
             ik_bone,
             target_bone,
             ik_chains: Vec::with_capacity(chain_capacity as usize),
+            fabrik_positions: Vec::new(),
+            fabrik_lengths: Vec::new(),
+            limit_hit_mask: 0,
+            target_pin: None,
+        }
+    }
+
+    // the two bone mode needs exactly two chain links, returns false and keeps the current mode otherwise
+    #[inline]
+    pub(crate) fn set_mode(&mut self, mode: IkSolverMode) -> bool {
+        if mode == IkSolverMode::TwoBone && self.ik_chains.len() != 2 {
+            return false;
+        }
+        self.mode = mode;
+        true
+    }
+
+    pub(crate) fn set_target_pin(&mut self, position: Vec3A, weight: f32) {
+        let weight = weight.clamp(0.0, 1.0);
+        if weight <= 0.0 {
+            self.target_pin = None;
+            return;
+        }
+        match &mut self.target_pin {
+            Some(pin) => {
+                pin.position = position;
+                pin.weight = weight;
+                pin.fade_out_rate = 0.0;
+            }
+            None => {
+                self.target_pin = Some(IkTargetPin {
+                    position,
+                    rotation: None,
+                    weight,
+                    fade_out_rate: 0.0,
+                });
+            }
+        }
+    }
+
+    pub(crate) fn set_target_pin_rotation(&mut self, rotation: Option<Quat>) {
+        if let Some(pin) = &mut self.target_pin {
+            pin.rotation = rotation;
+        }
+    }
+
+    // releases the pin, fading its current weight out over fade_out_duration frames
+    pub(crate) fn clear_target_pin(&mut self, fade_out_duration: f32) {
+        if fade_out_duration <= 0.0 {
+            self.target_pin = None;
+        } else if let Some(pin) = &mut self.target_pin {
+            pin.fade_out_rate = pin.weight / fade_out_duration;
+        }
+    }
+
+    // moves the ik bone toward the pinned position, returns true if the chain should be solved for the pin,
+    // delta_time is the time in frames elapsed since the previous update
+    pub(crate) fn apply_target_pin(&mut self, bone_arena: &mut MmdRuntimeBoneArena, delta_time: f32) -> bool {
+        let pin = match &mut self.target_pin {
+            Some(pin) => pin,
+            None => return false,
+        };
+
+        pin.weight -= pin.fade_out_rate * delta_time;
+        if pin.weight <= 0.0 {
+            self.target_pin = None;
+            return false;
         }
+
+        let (scale, rotation, translation) = bone_arena.world_matrices()[self.ik_bone].to_scale_rotation_translation();
+        let position = Vec3A::from(translation).lerp(pin.position, pin.weight);
+        let world_matrix = Mat4::from_scale_rotation_translation(scale, rotation, position.into());
+        bone_arena.set_world_matrix(self.ik_bone, world_matrix);
+        true
+    }
+
+    pub(crate) fn apply_target_pin_rotation(&self, bone_arena: &mut MmdRuntimeBoneArena) {
+        let pin = match &self.target_pin {
+            Some(pin) => pin,
+            None => return,
+        };
+        let pin_rotation = match pin.rotation {
+            Some(rotation) => rotation,
+            None => return,
+        };
+
+        let (scale, rotation, translation) = bone_arena.world_matrices()[self.target_bone].to_scale_rotation_translation();
+        let rotation = rotation.slerp(pin_rotation, pin.weight);
+        let world_matrix = Mat4::from_scale_rotation_translation(scale, rotation, translation);
+        bone_arena.set_world_matrix(self.target_bone, world_matrix);
+    }
+
+    #[inline]
+    pub(crate) fn ik_bone(&self) -> u32 {
+        self.ik_bone
+    }
+
+    #[inline]
+    pub(crate) fn target_bone(&self) -> u32 {
+        self.target_bone
     }
 
     pub(crate) fn add_ik_chain(
@@ -91,7 +262,7 @@ impl IkSolver {
         bone.ik_rotation = Some(Quat::IDENTITY);
     }
 
-    pub(crate) fn solve(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena) {
+    pub(crate) fn solve(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena, diagnostics: &mut IkSolverDiagnostics) {
         for chain in &mut self.ik_chains {
             chain.prev_angle = Vec3A::ZERO;
             chain.plane_mode_angle = 0.0;
@@ -102,8 +273,25 @@ impl IkSolver {
             bone_arena.update_world_matrix(chain.bone);
         };
 
+        self.limit_hit_mask = 0;
+        let (iteration_count, early_exit) = match self.mode {
+            IkSolverMode::TwoBone => (self.solve_two_bone(animation_arena, bone_arena, append_transform_sovler_arena), false),
+            IkSolverMode::Fabrik if !self.ik_chains.is_empty() => self.solve_fabrik(animation_arena, bone_arena, append_transform_sovler_arena),
+            _ => self.solve_ccd(animation_arena, bone_arena, append_transform_sovler_arena),
+        };
 
+        let target_position = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis);
+        let ik_position = Vec3A::from(bone_arena.world_matrices()[self.ik_bone].w_axis);
+        diagnostics.distance = target_position.distance(ik_position);
+        diagnostics.iteration_count = iteration_count;
+        diagnostics.limit_hit_mask = self.limit_hit_mask;
+        diagnostics.early_exit = early_exit as u8;
+        diagnostics.solved = 1;
+    }
+
+    fn solve_ccd(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena) -> (u32, bool) {
         let mut max_distance = f32::MAX;
+        let mut saved_limit_hit_mask = 0;
         for i in 0..self.iteration {
             self.solve_core(animation_arena, bone_arena, append_transform_sovler_arena, i);
 
@@ -112,19 +300,356 @@ impl IkSolver {
             let distance = target_position.distance_squared(ik_position);
             if distance < max_distance {
                 max_distance = distance;
+                saved_limit_hit_mask = self.limit_hit_mask;
                 for chain in &mut self.ik_chains {
                     chain.saved_ik_rotation = bone_arena.arena()[chain.bone].ik_rotation.unwrap();
                 }
             } else {
+                // the pose of the rejected iteration is discarded, the iteration itself still ran
                 for chain in &mut self.ik_chains {
                     let chain_bone = &mut bone_arena.arena_mut()[chain.bone];
                     chain_bone.ik_rotation = Some(chain.saved_ik_rotation);
                     chain_bone.update_local_matrix(animation_arena, append_transform_sovler_arena);
                     bone_arena.update_world_matrix(chain.bone);
                 }
+                self.limit_hit_mask = saved_limit_hit_mask;
+                return (i as u32 + 1, true);
+            }
+        }
+        (self.iteration.max(0) as u32, false)
+    }
+
+    // analytic solver for two-link chains (e.g. leg: ik_chains[0] = knee, ik_chains[1] = thigh),
+    // returns 0 and leaves the animated pose if the chain is degenerate
+    fn solve_two_bone(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena) -> u32 {
+        let ik_position = Vec3A::from(bone_arena.world_matrices()[self.ik_bone].w_axis);
+        let initial_distance = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis).distance_squared(ik_position);
+
+        let mid_bone = self.ik_chains[0].bone;
+        let root_bone = self.ik_chains[1].bone;
+        if mid_bone == self.target_bone || root_bone == self.target_bone {
+            return 0;
+        }
+
+        // bend the middle joint so that root to target distance equals root to ik distance
+        {
+            let root_position = Vec3A::from(bone_arena.world_matrices()[root_bone].w_axis);
+            let target_position = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis);
+            let mid_world = bone_arena.world_matrices()[mid_bone];
+            let inverse_mid = mid_world.inverse();
+            let animated_rotation = bone_arena.arena()[mid_bone].animated_rotation(animation_arena);
+
+            let mid_limits = &self.ik_chains[0].angle_limits;
+            let plane_axis = mid_limits.as_ref().and_then(IkSolver::plane_axis);
+
+            // (rotation axis, vector to bend, base rotation) in the mid bone's parent space
+            let (axis, end_vector, root_vector, base_rotation) = if let Some(plane_axis) = plane_axis {
+                let end_vector = Vec3A::from(inverse_mid * Vec4::from((target_position, 1.0)));
+                let root_vector = animated_rotation * Vec3A::from(inverse_mid * Vec4::from((root_position, 1.0)));
+                (Vec3A::from(plane_axis), end_vector, root_vector, Quat::IDENTITY)
+            } else {
+                let end_vector = animated_rotation * Vec3A::from(inverse_mid * Vec4::from((target_position, 1.0)));
+                let root_vector = animated_rotation * Vec3A::from(inverse_mid * Vec4::from((root_position, 1.0)));
+                let cross = root_vector.cross(end_vector);
+                let axis = if cross.length_squared() < 1.0e-12 {
+                    // a straightened limb bends toward the ik position, or about its widest limit axis
+                    let ik_vector = animated_rotation * Vec3A::from(inverse_mid * Vec4::from((ik_position, 1.0)));
+                    let pole_cross = root_vector.cross(ik_vector);
+                    if 1.0e-12 <= pole_cross.length_squared() {
+                        pole_cross.normalize()
+                    } else if let Some(limits) = mid_limits {
+                        let range = limits.maximum_angle - limits.minimum_angle;
+                        let limit_axis = if range.y <= range.x && range.z <= range.x {
+                            Vec3A::X
+                        } else if range.z <= range.y {
+                            Vec3A::Y
+                        } else {
+                            Vec3A::Z
+                        };
+                        animated_rotation * limit_axis
+                    } else {
+                        root_vector.any_orthogonal_vector().normalize_or_zero()
+                    }
+                } else {
+                    cross.normalize()
+                };
+                (axis, end_vector, root_vector, animated_rotation)
+            };
+
+            let desired_distance = root_position.distance(ik_position);
+
+            let root_parallel = axis * root_vector.dot(axis);
+            let end_parallel = axis * end_vector.dot(axis);
+            let root_perpendicular = root_vector - root_parallel;
+            let end_perpendicular = end_vector - end_parallel;
+            let perpendicular_length = root_perpendicular.length() * end_perpendicular.length();
+            if perpendicular_length <= 1.0e-6 {
+                return 0;
+            }
+
+            let cos = (root_vector.length_squared() + end_vector.length_squared()
+                - desired_distance * desired_distance
+                - 2.0 * root_parallel.dot(end_parallel)) / (2.0 * perpendicular_length);
+            let offset = cos.clamp(-1.0, 1.0).acos();
+            let phase = axis.dot(end_perpendicular.cross(root_perpendicular))
+                .atan2(end_perpendicular.dot(root_perpendicular));
+
+            let candidate_a = IkSolver::diff_angle(phase + offset, 0.0);
+            let candidate_b = IkSolver::diff_angle(phase - offset, 0.0);
+
+            let mut angle = if let Some(plane_axis) = plane_axis {
+                let (minimum_angle, maximum_angle) = IkSolver::plane_limits(mid_limits.as_ref().unwrap(), plane_axis);
+                let clamped_a = candidate_a.clamp(minimum_angle, maximum_angle);
+                let clamped_b = candidate_b.clamp(minimum_angle, maximum_angle);
+                let (clamped, candidate) = if (clamped_a - candidate_a).abs() <= (clamped_b - candidate_b).abs() {
+                    (clamped_a, candidate_a)
+                } else {
+                    (clamped_b, candidate_b)
+                };
+                if clamped != candidate {
+                    self.limit_hit_mask |= limit_hit_bit(0);
+                }
+                clamped
+            } else if candidate_a.abs() < candidate_b.abs() { // the general case starts from the animated pose, so prefer the smaller bend
+                candidate_a
+            } else {
+                candidate_b
+            };
+            if angle.is_nan() {
+                angle = 0.0;
+            }
+
+            let mut chain_rotation = Quat::from_axis_angle(axis.into(), angle) * base_rotation;
+            if plane_axis.is_none() {
+                if let Some(limits) = mid_limits {
+                    let (limited_rotation, limit_hit) = IkSolver::limit_rotation(chain_rotation, limits);
+                    chain_rotation = limited_rotation;
+                    if limit_hit {
+                        self.limit_hit_mask |= limit_hit_bit(0);
+                    }
+                }
+            }
+
+            let chain_bone = &mut bone_arena.arena_mut()[mid_bone];
+            chain_bone.ik_rotation = Some(chain_rotation * animated_rotation.inverse());
+            chain_bone.update_local_matrix(animation_arena, append_transform_sovler_arena);
+            bone_arena.update_world_matrix(mid_bone);
+        }
+
+        // swing the root joint so that the target points toward the ik position
+        {
+            let target_position = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis);
+            let inverse_root = bone_arena.world_matrices()[root_bone].inverse();
+
+            let root_ik_vector = Vec3A::from(inverse_root * Vec4::from((ik_position, 1.0)));
+            let root_target_vector = Vec3A::from(inverse_root * Vec4::from((target_position, 1.0)));
+
+            if 0.0 < root_ik_vector.length_squared() && 0.0 < root_target_vector.length_squared() {
+                let rotation = Quat::from_rotation_arc(root_target_vector.normalize().into(), root_ik_vector.normalize().into());
+
+                let animated_rotation = bone_arena.arena()[root_bone].animated_rotation(animation_arena);
+                let mut chain_rotation = animated_rotation * rotation;
+                if let Some(limits) = &self.ik_chains[1].angle_limits {
+                    let (limited_rotation, limit_hit) = IkSolver::limit_rotation(chain_rotation, limits);
+                    chain_rotation = limited_rotation;
+                    if limit_hit {
+                        self.limit_hit_mask |= limit_hit_bit(1);
+                    }
+                }
+
+                let chain_bone = &mut bone_arena.arena_mut()[root_bone];
+                chain_bone.ik_rotation = Some(chain_rotation * animated_rotation.inverse());
+                chain_bone.update_local_matrix(animation_arena, append_transform_sovler_arena);
+                bone_arena.update_world_matrix(root_bone);
+            }
+        }
+
+        // keep the analytic result only if it is better than the unsolved pose,
+        // the diagnostics then describe the animated pose
+        let target_position = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis);
+        if initial_distance < target_position.distance_squared(ik_position) {
+            for chain in &mut self.ik_chains {
+                let chain_bone = &mut bone_arena.arena_mut()[chain.bone];
+                chain_bone.ik_rotation = Some(Quat::IDENTITY);
+                chain_bone.update_local_matrix(animation_arena, append_transform_sovler_arena);
+                bone_arena.update_world_matrix(chain.bone);
+            }
+            self.limit_hit_mask = 0;
+            return 0;
+        }
+        1
+    }
+
+    // forward and backward reaching ik over chain bone positions, converted back to rotations root first,
+    // the angle limits are applied in every iteration so that the next pass starts from the limited pose
+    fn solve_fabrik(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena) -> (u32, bool) {
+        let ik_position = Vec3A::from(bone_arena.world_matrices()[self.ik_bone].w_axis);
+        let initial_distance = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis).distance_squared(ik_position);
+
+        let chain_count = self.ik_chains.len();
+        self.read_fabrik_positions(bone_arena);
+        let lengths = &mut self.fabrik_lengths;
+        lengths.clear();
+        for i in 0..chain_count {
+            lengths.push(self.fabrik_positions[i].distance(self.fabrik_positions[i + 1]));
+        }
+        let total_length: f32 = lengths.iter().sum();
+
+        // the best iteration is kept, the animated pose if none improves on it
+        let mut best_distance = initial_distance;
+        let mut best_limit_hit_mask = 0;
+        let mut best_iteration = 0;
+        for chain in &mut self.ik_chains {
+            chain.saved_ik_rotation = Quat::IDENTITY;
+        }
+
+        let mut iteration_count = 0;
+        let mut early_exit = false;
+        for _ in 0..self.iteration {
+            iteration_count += 1;
+            if 1 < iteration_count {
+                self.read_fabrik_positions(bone_arena);
+            }
+
+            let positions = &mut self.fabrik_positions;
+            let lengths = &self.fabrik_lengths;
+            let root_position = positions[0];
+            if total_length <= root_position.distance(ik_position) {
+                let direction = (ik_position - root_position).normalize_or_zero();
+                for i in 0..chain_count {
+                    positions[i + 1] = positions[i] + direction * lengths[i];
+                }
+            } else {
+                positions[chain_count] = ik_position;
+                for i in (0..chain_count).rev() {
+                    let direction = (positions[i] - positions[i + 1]).normalize_or_zero();
+                    positions[i] = positions[i + 1] + direction * lengths[i];
+                }
+
+                positions[0] = root_position;
+                for i in 0..chain_count {
+                    let direction = (positions[i + 1] - positions[i]).normalize_or_zero();
+                    positions[i + 1] = positions[i] + direction * lengths[i];
+                }
+            }
+
+            self.limit_hit_mask = 0;
+            self.apply_fabrik_positions(animation_arena, bone_arena, append_transform_sovler_arena);
+
+            let distance = Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis).distance_squared(ik_position);
+            if distance < best_distance {
+                best_distance = distance;
+                best_limit_hit_mask = self.limit_hit_mask;
+                best_iteration = iteration_count;
+                for chain in &mut self.ik_chains {
+                    chain.saved_ik_rotation = bone_arena.arena()[chain.bone].ik_rotation.unwrap();
+                }
+            }
+            if distance < FABRIK_TOLERANCE * FABRIK_TOLERANCE {
+                early_exit = true;
                 break;
             }
         }
+
+        if best_iteration != iteration_count {
+            for chain in &mut self.ik_chains {
+                let chain_bone = &mut bone_arena.arena_mut()[chain.bone];
+                chain_bone.ik_rotation = Some(chain.saved_ik_rotation);
+                chain_bone.update_local_matrix(animation_arena, append_transform_sovler_arena);
+                bone_arena.update_world_matrix(chain.bone);
+            }
+            early_exit = false;
+        }
+        self.limit_hit_mask = best_limit_hit_mask;
+        (iteration_count, early_exit)
+    }
+
+    // positions[0] is the root chain bone, positions[n] is the target bone
+    fn read_fabrik_positions(&mut self, bone_arena: &MmdRuntimeBoneArena) {
+        let positions = &mut self.fabrik_positions;
+        positions.clear();
+        for chain in self.ik_chains.iter().rev() {
+            positions.push(Vec3A::from(bone_arena.world_matrices()[chain.bone].w_axis));
+        }
+        positions.push(Vec3A::from(bone_arena.world_matrices()[self.target_bone].w_axis));
+    }
+
+    fn apply_fabrik_positions(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena) {
+        let chain_count = self.ik_chains.len();
+        for i in 0..chain_count {
+            let chain = &self.ik_chains[chain_count - 1 - i];
+            if chain.bone == self.target_bone {
+                continue;
+            }
+
+            let next_bone = if i + 1 < chain_count {
+                self.ik_chains[chain_count - 2 - i].bone
+            } else {
+                self.target_bone
+            };
+
+            let inverse_chain = bone_arena.world_matrices()[chain.bone].inverse();
+            let current_vector = Vec3A::from(inverse_chain * bone_arena.world_matrices()[next_bone].w_axis);
+            let desired_vector = Vec3A::from(inverse_chain * Vec4::from((self.fabrik_positions[i + 1], 1.0)));
+            if current_vector.length_squared() == 0.0 || desired_vector.length_squared() == 0.0 {
+                continue;
+            }
+            let rotation = Quat::from_rotation_arc(current_vector.normalize().into(), desired_vector.normalize().into());
+
+            let animated_rotation = bone_arena.arena()[chain.bone].animated_rotation(animation_arena);
+            let chain_bone = &bone_arena.arena()[chain.bone];
+            let mut chain_rotation = chain_bone.ik_rotation.unwrap() * animated_rotation * rotation;
+            if let Some(limits) = &chain.angle_limits {
+                let (limited_rotation, limit_hit) = IkSolver::limit_rotation(chain_rotation, limits);
+                chain_rotation = limited_rotation;
+                if limit_hit {
+                    self.limit_hit_mask |= limit_hit_bit(chain_count - 1 - i);
+                }
+            }
+
+            let chain_bone = &mut bone_arena.arena_mut()[chain.bone];
+            chain_bone.ik_rotation = Some(chain_rotation * animated_rotation.inverse());
+            chain_bone.update_local_matrix(animation_arena, append_transform_sovler_arena);
+            bone_arena.update_world_matrix(chain.bone);
+        }
+    }
+
+    fn plane_axis(limits: &IkChainAngleLimits) -> Option<Vec3> {
+        let IkChainAngleLimits{minimum_angle, maximum_angle} = limits;
+        let x = minimum_angle.x != 0.0 || maximum_angle.x != 0.0;
+        let y = minimum_angle.y != 0.0 || maximum_angle.y != 0.0;
+        let z = minimum_angle.z != 0.0 || maximum_angle.z != 0.0;
+        match (x, y, z) {
+            (true, false, false) => Some(Vec3::X),
+            (false, true, false) => Some(Vec3::Y),
+            (false, false, true) => Some(Vec3::Z),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn plane_limits(limits: &IkChainAngleLimits, axis: Vec3) -> (f32, f32) {
+        if axis == Vec3::X {
+            (limits.minimum_angle.x, limits.maximum_angle.x)
+        } else if axis == Vec3::Y {
+            (limits.minimum_angle.y, limits.maximum_angle.y)
+        } else {
+            (limits.minimum_angle.z, limits.maximum_angle.z)
+        }
+    }
+
+    fn limit_rotation(rotation: Quat, limits: &IkChainAngleLimits) -> (Quat, bool) {
+        let IkChainAngleLimits{minimum_angle, maximum_angle} = limits;
+        let rotation_xyz = IkSolver::decompose(&Mat3::from_quat(rotation), Vec3A::ZERO);
+        let clamp_xyz = Vec3A::new(
+            rotation_xyz.x.clamp(minimum_angle.x, maximum_angle.x),
+            rotation_xyz.y.clamp(minimum_angle.y, maximum_angle.y),
+            rotation_xyz.z.clamp(minimum_angle.z, maximum_angle.z),
+        );
+        let rotation = Quat::from_axis_angle(Vec3::X, clamp_xyz.x)
+            * Quat::from_axis_angle(Vec3::Y, clamp_xyz.y)
+            * Quat::from_axis_angle(Vec3::Z, clamp_xyz.z);
+        (rotation, clamp_xyz != rotation_xyz)
     }
 
     fn solve_core(&mut self, animation_arena: &AnimationArena, bone_arena: &mut MmdRuntimeBoneArena, append_transform_sovler_arena: &AppendTransformSolverArena, iteration: i32) {
@@ -194,6 +719,9 @@ impl IkSolver {
                     rotation_xyz.y.clamp(minimum_angle.y, maximum_angle.y),
                     rotation_xyz.z.clamp(minimum_angle.z, maximum_angle.z),
                 );
+                if clamp_xyz != rotation_xyz {
+                    self.limit_hit_mask |= limit_hit_bit(chain_index);
+                }
 
                 clamp_xyz -= chain.prev_angle;
                 clamp_xyz.x = clamp_xyz.x.clamp(-self.limit_angle, self.limit_angle);
@@ -275,6 +803,9 @@ impl IkSolver {
             }
         }
 
+        if new_angle < minimum_angle || maximum_angle < new_angle {
+            self.limit_hit_mask |= limit_hit_bit(chain_index);
+        }
         let new_angle = new_angle.clamp(minimum_angle, maximum_angle);
         chain.plane_mode_angle = new_angle;
 
@@ -372,3 +903,167 @@ impl IkSolver {
         r
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::{EulerRot, Quat, Vec3A};
+
+    use super::{limit_hit_bit, IkSolver, IkSolverDiagnostics, IkSolverMode};
+    use crate::animation_arena::AnimationArena;
+    use crate::append_transform_solver::AppendTransformSolverArena;
+    use crate::mmd_model_metadata::IkChainAngleLimits;
+    use crate::mmd_runtime_bone::{MmdRuntimeBone, MmdRuntimeBoneArena};
+
+    const CHAIN_LENGTH: u32 = 4;
+    const ITERATION: i32 = 50;
+
+    // bones 0..chain_length form a unit length chain along direction ending at the target bone, the last bone is the ik bone
+    fn solve_chain_along(
+        mode: IkSolverMode,
+        iteration: i32,
+        chain_length: u32,
+        direction: Vec3A,
+        ik_position: Vec3A,
+        angle_limits: &[Option<IkChainAngleLimits>],
+    ) -> (MmdRuntimeBoneArena, AnimationArena, IkSolverDiagnostics) {
+        let ik_bone = chain_length;
+        let target_bone = chain_length - 1;
+        let mut bones = Vec::new();
+        for i in 0..=chain_length {
+            let mut bone = MmdRuntimeBone::new(i);
+            if 0 < i && i < ik_bone {
+                bone.rest_position = direction;
+                bone.parent_bone = Some(i - 1);
+            }
+            if i + 1 < ik_bone {
+                bone.child_bones.push(i + 1);
+            }
+            bones.push(bone);
+        }
+
+        let mut animation_arena = AnimationArena::new(&bones, 1, 0);
+        animation_arena.bone_arena_mut()[ik_bone].position = ik_position;
+        let append_transform_solver_arena = AppendTransformSolverArena::new(Box::new([]));
+        let mut bone_arena = MmdRuntimeBoneArena::new(bones.into_boxed_slice(), Vec::new());
+
+        // ik_chains[0] is the link next to the target bone
+        let mut ik_solver = IkSolver::new(iteration, 0.5, ik_bone, target_bone, target_bone);
+        for (i, bone) in (0..target_bone).rev().enumerate() {
+            ik_solver.add_ik_chain(bone_arena.arena_mut(), bone, angle_limits.get(i).cloned().flatten());
+        }
+        assert!(ik_solver.set_mode(mode));
+        for bone in 0..=chain_length {
+            bone_arena.arena_mut()[bone].update_local_matrix(&animation_arena, &append_transform_solver_arena);
+        }
+        bone_arena.update_world_matrix(0);
+        bone_arena.update_world_matrix(ik_bone);
+
+        let mut diagnostics = IkSolverDiagnostics::new();
+        ik_solver.solve(&animation_arena, &mut bone_arena, &append_transform_solver_arena, &mut diagnostics);
+        (bone_arena, animation_arena, diagnostics)
+    }
+
+    fn solve_chain(mode: IkSolverMode, ik_position: Vec3A, angle_limits: Option<IkChainAngleLimits>) -> (MmdRuntimeBoneArena, AnimationArena, IkSolverDiagnostics) {
+        let angle_limits = vec![angle_limits; CHAIN_LENGTH as usize - 1];
+        solve_chain_along(mode, ITERATION, CHAIN_LENGTH, Vec3A::Y, ik_position, &angle_limits)
+    }
+
+    #[test]
+    fn fabrik_reaches_target_in_fewer_iterations_than_ccd() {
+        let ik_position = Vec3A::new(1.5, 1.5, 0.3);
+        let (_, _, ccd) = solve_chain(IkSolverMode::Ccd, ik_position, None);
+        let (_, _, fabrik) = solve_chain(IkSolverMode::Fabrik, ik_position, None);
+
+        assert!(fabrik.distance < 1.0e-3, "fabrik reach error {}", fabrik.distance);
+        assert!(fabrik.distance <= ccd.distance + 1.0e-4, "fabrik {} ccd {}", fabrik.distance, ccd.distance);
+        assert!(fabrik.iteration_count <= ccd.iteration_count, "fabrik {} ccd {}", fabrik.iteration_count, ccd.iteration_count);
+    }
+
+    #[test]
+    fn fabrik_keeps_angle_limits_and_reach() {
+        let limit = 0.6;
+        let angle_limits = IkChainAngleLimits {
+            minimum_angle: Vec3A::splat(-limit),
+            maximum_angle: Vec3A::splat(limit),
+        };
+        let ik_position = Vec3A::new(1.2, 1.8, 0.4);
+        let (_, _, ccd) = solve_chain(IkSolverMode::Ccd, ik_position, Some(angle_limits.clone()));
+        let (bone_arena, animation_arena, fabrik) = solve_chain(IkSolverMode::Fabrik, ik_position, Some(angle_limits.clone()));
+
+        // limit_rotation composes the clamped angles as x * y * z
+        for bone in 0..CHAIN_LENGTH - 1 {
+            let bone = &bone_arena.arena()[bone];
+            let rotation = bone.ik_rotation.unwrap() * bone.animated_rotation(&animation_arena);
+            let (x, y, z) = rotation.to_euler(EulerRot::XYZ);
+            assert!(x.abs().max(y.abs()).max(z.abs()) <= limit + 1.0e-3, "angles {x} {y} {z}");
+        }
+        assert!(fabrik.distance <= ccd.distance + 1.0e-2, "fabrik {} ccd {}", fabrik.distance, ccd.distance);
+    }
+
+    #[test]
+    fn two_bone_reaches_target_from_a_straightened_limb() {
+        // the limb lies along x, so a fixed x bend axis would leave it straight
+        for ik_position in [Vec3A::new(1.2, 0.9, 0.0), Vec3A::new(0.6, 0.0, -1.1), Vec3A::new(1.9, 0.1, 0.2)] {
+            let (_, _, diagnostics) = solve_chain_along(IkSolverMode::TwoBone, ITERATION, 3, Vec3A::X, ik_position, &[]);
+            assert!(diagnostics.distance < 1.0e-4, "reach error {} for {ik_position}", diagnostics.distance);
+            assert_eq!(diagnostics.iteration_count, 1);
+            assert_eq!(diagnostics.limit_hit_mask, 0);
+        }
+    }
+
+    #[test]
+    fn two_bone_keeps_knee_limits() {
+        let (minimum_angle, maximum_angle) = (0.1, 1.2);
+        let knee_limits = IkChainAngleLimits {
+            minimum_angle: Vec3A::new(0.0, 0.0, minimum_angle),
+            maximum_angle: Vec3A::new(0.0, 0.0, maximum_angle),
+        };
+
+        // reachable within the limits
+        let ik_position = Vec3A::new(1.5, 0.8, 0.0);
+        let (bone_arena, animation_arena, diagnostics) = solve_chain_along(IkSolverMode::TwoBone, ITERATION, 3, Vec3A::X, ik_position, &[Some(knee_limits.clone())]);
+        assert!(diagnostics.distance < 1.0e-4, "reach error {}", diagnostics.distance);
+        assert_eq!(diagnostics.limit_hit_mask, 0);
+        let knee = &bone_arena.arena()[1];
+        let (axis, angle) = (knee.ik_rotation.unwrap() * knee.animated_rotation(&animation_arena)).to_axis_angle();
+        assert!(minimum_angle - 1.0e-4 <= angle * axis.z && angle * axis.z <= maximum_angle + 1.0e-4, "knee angle {}", angle * axis.z);
+
+        // close to the root the knee would have to fold past its limit
+        let (bone_arena, animation_arena, diagnostics) = solve_chain_along(IkSolverMode::TwoBone, ITERATION, 3, Vec3A::X, Vec3A::new(0.2, 0.1, 0.0), &[Some(knee_limits)]);
+        assert_eq!(diagnostics.limit_hit_mask & limit_hit_bit(0), limit_hit_bit(0));
+        let knee = &bone_arena.arena()[1];
+        let rotation = knee.ik_rotation.unwrap() * knee.animated_rotation(&animation_arena);
+        assert!(rotation.angle_between(Quat::from_rotation_z(maximum_angle)) < 1.0e-4, "knee rotation {rotation}");
+    }
+
+    #[test]
+    fn two_bone_mode_needs_two_links() {
+        let mut ik_solver = IkSolver::new(ITERATION, 0.5, 3, 2, 2);
+        assert!(!ik_solver.set_mode(IkSolverMode::TwoBone));
+        assert!(ik_solver.set_mode(IkSolverMode::Fabrik));
+    }
+
+    #[test]
+    fn iteration_count_includes_every_executed_iteration() {
+        // an unreachable target stops improving after the chain is straightened
+        let ik_position = Vec3A::new(6.0, 4.0, 0.0);
+        let (_, _, ccd) = solve_chain(IkSolverMode::Ccd, ik_position, None);
+        assert!(ccd.early_exit != 0);
+        // the rejected iteration counts, so one iteration less runs to the end without rejecting
+        let (_, _, shorter) = solve_chain_along(IkSolverMode::Ccd, ccd.iteration_count as i32 - 1, CHAIN_LENGTH, Vec3A::Y, ik_position, &[None, None, None]);
+        assert_eq!(shorter.early_exit, 0);
+        assert_eq!(shorter.iteration_count, ccd.iteration_count - 1);
+
+        // fabrik keeps its best pose but still reports every pass
+        let (_, _, fabrik) = solve_chain(IkSolverMode::Fabrik, ik_position, None);
+        assert_eq!(fabrik.early_exit, 0);
+        assert_eq!(fabrik.iteration_count, ITERATION as u32);
+    }
+
+    #[test]
+    fn limit_hit_bits_clamp_to_the_last_bit() {
+        assert_eq!(limit_hit_bit(0), 1);
+        assert_eq!(limit_hit_bit(31), 1 << 31);
+        assert_eq!(limit_hit_bit(40), 1 << 31);
+    }
+}
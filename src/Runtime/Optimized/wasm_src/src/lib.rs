@@ -1,9 +1,14 @@
+#![cfg_attr(test, feature(test))]
+
 mod animation_arena;
 mod ik_solver;
 mod mmd_model;
 mod mmd_runtime_bone;
 mod mmd_runtime;
 mod append_transform_solver;
+mod foot_grounding;
+mod gltf_export;
+mod look_at_solver;
 mod mmd_model_metadata;
 mod mmd_morph_controller;
 mod animation;
@@ -0,0 +1,134 @@
+use glam::{Mat4, Quat, Vec3, Vec3A};
+
+use crate::animation_arena::AnimationArena;
+use crate::append_transform_solver::AppendTransformSolverArena;
+use crate::mmd_runtime_bone::MmdRuntimeBoneArena;
+
+struct LookAtChain {
+    bone: u32,
+    weight: f32,
+    limit_angle: f32,
+    current_rotation: Quat,
+}
+
+pub(crate) struct LookAtSolver {
+    chains: Box<[LookAtChain]>,
+    forward: Vec3A,
+    smoothing: f32, // part of the remaining rotation kept after one frame
+    weight: f32,
+    target: Option<Vec3A>,
+    inverse_world_matrix: Mat4,
+}
+
+impl LookAtSolver {
+    pub(crate) fn new(bones: &[u32], weights: &[f32], limit_angles: &[f32], forward: Vec3A, smoothing: f32) -> Self {
+        let mut chains = Vec::with_capacity(bones.len());
+        for ((bone, weight), limit_angle) in bones.iter().zip(weights).zip(limit_angles) {
+            chains.push(LookAtChain {
+                bone: *bone,
+                weight: weight.clamp(0.0, 1.0),
+                limit_angle: *limit_angle,
+                current_rotation: Quat::IDENTITY,
+            });
+        }
+
+        LookAtSolver {
+            chains: chains.into_boxed_slice(),
+            forward: forward.normalize_or_zero(),
+            smoothing: smoothing.clamp(0.0, 1.0),
+            weight: 1.0,
+            target: None,
+            inverse_world_matrix: Mat4::IDENTITY,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_target(&mut self, target: Option<Vec3A>) {
+        self.target = target;
+    }
+
+    #[inline]
+    pub(crate) fn set_world_matrix(&mut self, world_matrix: Mat4) {
+        self.inverse_world_matrix = world_matrix.inverse();
+    }
+
+    #[inline]
+    pub(crate) fn set_weight(&mut self, weight: f32) {
+        self.weight = weight.clamp(0.0, 1.0);
+    }
+
+    // drops the rotations of the previous update so that the local matrices of the stage are built from the animated pose
+    pub(crate) fn reset(&self, bone_arena: &mut MmdRuntimeBoneArena, after_physics_stage: bool) {
+        let mut bones = bone_arena.arena_mut();
+        for chain in self.chains.iter() {
+            if let Some(bone) = bones.get_mut(chain.bone) {
+                if bone.transform_after_physics == after_physics_stage {
+                    bone.look_at_rotation = None;
+                }
+            }
+        }
+    }
+
+    // rotates each chain bone of the stage toward the target on top of the animated pose, root to tip,
+    // the rotations are stored on the bones so that append transforms and ik see them,
+    // delta_time is the time in frames elapsed since the previous update
+    pub(crate) fn solve(
+        &mut self,
+        animation_arena: &AnimationArena,
+        bone_arena: &mut MmdRuntimeBoneArena,
+        append_transform_solver_arena: &AppendTransformSolverArena,
+        after_physics_stage: bool,
+        delta_time: f32,
+    ) {
+        if self.forward == Vec3A::ZERO {
+            return;
+        }
+
+        let target = self.target.map(|target| self.inverse_world_matrix.transform_point3a(target));
+        let ratio = if self.smoothing <= 0.0 {
+            1.0
+        } else {
+            1.0 - self.smoothing.powf(delta_time)
+        };
+
+        for chain in self.chains.iter_mut() {
+            let parent_bone = match bone_arena.arena().get(chain.bone) {
+                Some(bone) if bone.transform_after_physics == after_physics_stage => bone.parent_bone,
+                _ => continue,
+            };
+
+            let (_, rotation, translation) = bone_arena.world_matrices()[chain.bone].to_scale_rotation_translation();
+
+            let desired_rotation = match target {
+                Some(target) => {
+                    let forward = (rotation * Vec3::from(self.forward)).normalize();
+                    let direction = (Vec3::from(target) - translation).normalize_or_zero();
+                    if direction == Vec3::ZERO {
+                        Quat::IDENTITY
+                    } else {
+                        let full_rotation = Quat::from_rotation_arc(forward, direction);
+                        let (axis, angle) = full_rotation.to_axis_angle();
+                        let angle = angle.min(chain.limit_angle);
+                        Quat::IDENTITY.slerp(Quat::from_axis_angle(axis, angle), chain.weight * self.weight)
+                    }
+                }
+                None => Quat::IDENTITY,
+            };
+
+            chain.current_rotation = chain.current_rotation.slerp(desired_rotation, ratio).normalize();
+            if chain.current_rotation.abs_diff_eq(Quat::IDENTITY, 1.0e-6) {
+                continue;
+            }
+
+            // the model space rotation expressed in the parent space, applied before the local rotation
+            let parent_rotation = match parent_bone {
+                Some(parent_bone) => bone_arena.world_matrices()[parent_bone].to_scale_rotation_translation().1,
+                None => Quat::IDENTITY,
+            };
+            let bone = &mut bone_arena.arena_mut()[chain.bone];
+            bone.look_at_rotation = Some((parent_rotation.inverse() * chain.current_rotation * parent_rotation).normalize());
+            bone.update_local_matrix(animation_arena, append_transform_solver_arena);
+            bone_arena.update_world_matrix(chain.bone);
+        }
+    }
+}
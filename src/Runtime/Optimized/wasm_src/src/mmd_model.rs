@@ -2,21 +2,85 @@ use std::num::NonZeroUsize;
 use std::ptr::NonNull;
 
 use crate::mmd_runtime_bone::{MmdRuntimeBone, MmdRuntimeBoneArena};
-use crate::mmd_model_metadata::{MetadataBuffer, BoneMetadataReader, BoneFlag};
+use crate::mmd_model_metadata::{MetadataBuffer, BoneMetadataReader, BoneFlag, RigidbodyPhysicsMode};
 use crate::append_transform_solver::{AppendTransformSolver, AppendTransformSolverArena};
-use crate::ik_solver::{IkSolver, IkSolverArena};
+use crate::ik_solver::{IkSolver, IkSolverArena, IkSolverDiagnostics};
+use crate::foot_grounding::{FootGrounding, Ground};
+use crate::look_at_solver::LookAtSolver;
 use crate::animation_arena::AnimationArena;
 use crate::mmd_morph_controller::MmdMorphController;
 use crate::animation::mmd_runtime_animation::MmdRuntimeAnimation;
 use crate::unchecked_slice::UncheckedSliceMut;
 
+struct RuntimeAnimationLayer {
+    runtime_animation: NonZeroUsize,
+    weight: f32,
+    fade_from_weight: f32,
+    order: i32,
+    scratch_arena: Option<Box<AnimationArena>>,
+}
+
+impl RuntimeAnimationLayer {
+    fn new(runtime_animation: NonNull<MmdRuntimeAnimation>, weight: f32) -> Self {
+        RuntimeAnimationLayer {
+            runtime_animation: NonZeroUsize::new(runtime_animation.as_ptr() as usize).unwrap(),
+            weight,
+            fade_from_weight: weight,
+            order: 0,
+            scratch_arena: None,
+        }
+    }
+
+    #[inline]
+    fn runtime_animation(&self) -> &MmdRuntimeAnimation {
+        unsafe {
+            &*(self.runtime_animation.get() as *const MmdRuntimeAnimation)
+        }
+    }
+
+    // unmasked layers are blended together as the base pose, masked layers are applied over it in order
+    #[inline]
+    fn is_base_layer(&self) -> bool {
+        0.0 < self.weight && !self.runtime_animation().is_overlay()
+    }
+
+    #[inline]
+    fn runtime_animation_mut(&mut self) -> &mut MmdRuntimeAnimation {
+        unsafe {
+            &mut *(self.runtime_animation.get() as *mut MmdRuntimeAnimation)
+        }
+    }
+
+    fn animate_scratch(&mut self, frame_time: f32, template_arena: &AnimationArena, bone_arena: &MmdRuntimeBoneArena) -> &AnimationArena {
+        let runtime_animation = unsafe {
+            &mut *(self.runtime_animation.get() as *mut MmdRuntimeAnimation)
+        };
+        let scratch_arena = self.scratch_arena.get_or_insert_with(|| Box::new(template_arena.clone()));
+        scratch_arena.reset(&bone_arena.arena());
+        runtime_animation.animate_into(frame_time, scratch_arena, bone_arena, 1.0);
+        scratch_arena
+    }
+}
+
+struct CrossFade {
+    runtime_animation: NonZeroUsize,
+    duration: f32,
+    elapsed: f32,
+}
+
 pub(crate) struct MmdModel {
-    runtime_animation: Option<NonZeroUsize>,
+    runtime_animation_layers: Vec<RuntimeAnimationLayer>,
+    cross_fade: Option<CrossFade>,
     animation_arena: AnimationArena,
     bone_arena: MmdRuntimeBoneArena,
     append_transform_solver_arena: AppendTransformSolverArena,
     ik_solver_arena: IkSolverArena,
+    ik_solver_diagnostics_arena: Box<[IkSolverDiagnostics]>,
     morph_controller: MmdMorphController,
+    foot_grounding: Option<FootGrounding>,
+    look_at_solver: Option<LookAtSolver>,
+    skip_update_when_invisible: bool,
+    delta_time: f32,
     sorted_runtime_bones: Box<[u32]>,
     sorted_runtime_root_bones: Box<[u32]>,
 }
@@ -101,8 +165,13 @@ impl MmdModel {
         let animation_arena = AnimationArena::new(&bone_arena, ik_solver_arena.len() as u32, morphs.len() as u32);
         let morph_controller = MmdMorphController::new(morphs.into_boxed_slice());
 
-        let reader = reader.for_each(|_metadata| {
+        let reader = reader.for_each(|metadata| {
             // todo add physics
+            if matches!(RigidbodyPhysicsMode::from_u8(metadata.physics_mode), Some(RigidbodyPhysicsMode::Physics | RigidbodyPhysicsMode::PhysicsWithBone)) {
+                if let Some(bone) = bone_arena.get_mut(metadata.bone_index as usize) {
+                    bone.physics_driven = true;
+                }
+            }
         });
 
         reader.for_each(|_metadata| {
@@ -141,42 +210,293 @@ impl MmdModel {
         }
 
         MmdModel {
-            runtime_animation: None,
+            runtime_animation_layers: Vec::new(),
+            cross_fade: None,
             animation_arena,
             bone_arena: MmdRuntimeBoneArena::new(bone_arena, Vec::with_capacity(bone_max_depth as usize)),
             append_transform_solver_arena: AppendTransformSolverArena::new(append_transform_solver_arena.into_boxed_slice()),
+            ik_solver_diagnostics_arena: vec![IkSolverDiagnostics::new(); ik_solver_arena.len()].into_boxed_slice(),
             ik_solver_arena: IkSolverArena::new(ik_solver_arena.into_boxed_slice()),
             morph_controller,
+            foot_grounding: None,
+            look_at_solver: None,
+            skip_update_when_invisible: false,
+            delta_time: 0.0,
             sorted_runtime_bones: sorted_runtime_bones.into_boxed_slice(),
             sorted_runtime_root_bones: sorted_runtime_root_bones.into_boxed_slice(),
         }
     }
 
+    pub(crate) fn set_runtime_animation(&mut self, runtime_animation: Option<NonNull<MmdRuntimeAnimation>>) {
+        self.runtime_animation_layers.clear();
+        self.cross_fade = None;
+        if let Some(runtime_animation) = runtime_animation {
+            self.runtime_animation_layers.push(RuntimeAnimationLayer::new(runtime_animation, 1.0));
+        }
+    }
+
+    pub(crate) fn add_runtime_animation(&mut self, runtime_animation: NonNull<MmdRuntimeAnimation>, weight: f32) {
+        let key = runtime_animation.as_ptr() as usize;
+        match self.runtime_animation_layers.iter_mut().find(|layer| layer.runtime_animation.get() == key) {
+            Some(layer) => layer.weight = weight,
+            None => {
+                self.runtime_animation_layers.push(RuntimeAnimationLayer::new(runtime_animation, weight));
+                self.runtime_animation_layers.sort_by_key(|layer| layer.order);
+            }
+        }
+    }
+
+    pub(crate) fn set_runtime_animation_weight(&mut self, runtime_animation: NonNull<MmdRuntimeAnimation>, weight: f32) {
+        let key = runtime_animation.as_ptr() as usize;
+        if let Some(layer) = self.runtime_animation_layers.iter_mut().find(|layer| layer.runtime_animation.get() == key) {
+            layer.weight = weight;
+        }
+    }
+
+    pub(crate) fn set_runtime_animation_order(&mut self, runtime_animation: NonNull<MmdRuntimeAnimation>, order: i32) {
+        let key = runtime_animation.as_ptr() as usize;
+        if let Some(layer) = self.runtime_animation_layers.iter_mut().find(|layer| layer.runtime_animation.get() == key) {
+            layer.order = order;
+        }
+        self.runtime_animation_layers.sort_by_key(|layer| layer.order);
+    }
+
+    pub(crate) fn subtree_bone_mask(&self, root_bone: u32, weight: f32, bone_mask: &mut [f32]) {
+        let bones = self.bone_arena.arena();
+        if bones.get(root_bone).is_none() {
+            return;
+        }
+
+        let mut stack = vec![root_bone];
+        while let Some(bone) = stack.pop() {
+            if let Some(mask) = bone_mask.get_mut(bone as usize) {
+                *mask = weight;
+            }
+            stack.extend_from_slice(&bones[bone].child_bones);
+        }
+    }
+
     #[inline]
-    pub(crate) fn runtime_animation_mut(&mut self) -> &mut Option<NonNull<MmdRuntimeAnimation>> {
-        unsafe {
-            std::mem::transmute(&mut self.runtime_animation)
+    pub(crate) fn bone_count(&self) -> usize {
+        self.bone_arena.arena().len()
+    }
+
+    #[inline]
+    pub(crate) fn morph_count(&self) -> usize {
+        self.animation_arena.morph_arena().len()
+    }
+
+    pub(crate) fn remove_runtime_animation(&mut self, runtime_animation: NonNull<MmdRuntimeAnimation>) {
+        let key = runtime_animation.as_ptr() as usize;
+        self.runtime_animation_layers.retain(|layer| layer.runtime_animation.get() != key);
+        if self.cross_fade.as_ref().is_some_and(|cross_fade| cross_fade.runtime_animation.get() == key) {
+            self.cross_fade = None;
+        }
+    }
+
+    // fades runtime_animation in and every other runtime animation out over duration frames
+    pub(crate) fn cross_fade_runtime_animation(&mut self, runtime_animation: NonNull<MmdRuntimeAnimation>, duration: f32) {
+        let key = runtime_animation.as_ptr() as usize;
+        if !self.runtime_animation_layers.iter().any(|layer| layer.runtime_animation.get() == key) {
+            self.runtime_animation_layers.push(RuntimeAnimationLayer::new(runtime_animation, 0.0));
+            self.runtime_animation_layers.sort_by_key(|layer| layer.order);
+        }
+        for layer in &mut self.runtime_animation_layers {
+            layer.fade_from_weight = layer.weight;
+        }
+
+        if duration <= 0.0 {
+            self.runtime_animation_layers.retain(|layer| layer.runtime_animation.get() == key);
+            self.runtime_animation_layers[0].weight = 1.0;
+            self.cross_fade = None;
+            return;
+        }
+
+        self.cross_fade = Some(CrossFade {
+            runtime_animation: NonZeroUsize::new(key).unwrap(),
+            duration,
+            elapsed: 0.0,
+        });
+    }
+
+    // the fade advances with the elapsed time rather than the animation time so that pausing or seeking does not affect it
+    fn update_cross_fade(&mut self, delta_time: f32) {
+        let cross_fade = match &mut self.cross_fade {
+            Some(cross_fade) => cross_fade,
+            None => return,
+        };
+
+        cross_fade.elapsed += delta_time;
+        let ratio = (cross_fade.elapsed / cross_fade.duration).min(1.0);
+        let key = cross_fade.runtime_animation.get();
+
+        for layer in &mut self.runtime_animation_layers {
+            if layer.runtime_animation.get() == key {
+                layer.weight = layer.fade_from_weight + (1.0 - layer.fade_from_weight) * ratio;
+            } else {
+                layer.weight = layer.fade_from_weight * (1.0 - ratio);
+            }
+        }
+
+        if 1.0 <= ratio {
+            self.runtime_animation_layers.retain(|layer| layer.runtime_animation.get() == key);
+            self.cross_fade = None;
+        }
+    }
+
+    fn animate_runtime_animations(&mut self, frame_time: f32) {
+        if self.runtime_animation_layers.is_empty() {
+            return;
+        }
+        // additive and masked layers would accumulate over the previous result, so the bones and morphs
+        // the layers animate start from the rest pose. values written from outside are left untouched
+        if self.runtime_animation_layers.iter().any(|layer| layer.runtime_animation().is_overlay()) {
+            self.reset_animated_targets();
+        }
+
+        for layer in &mut self.runtime_animation_layers {
+            layer.runtime_animation_mut().update_root_motion(frame_time);
+        }
+
+        let base_layer_count = self.runtime_animation_layers.iter().filter(|layer| layer.is_base_layer()).count();
+        if base_layer_count == 1 {
+            let layer = self.runtime_animation_layers.iter_mut().find(|layer| layer.is_base_layer()).unwrap();
+            let weight = layer.weight.min(1.0);
+            layer.runtime_animation_mut().animate_into(frame_time, &mut self.animation_arena, &self.bone_arena, weight);
+        } else if 1 < base_layer_count {
+            self.blend_base_layers(frame_time);
+        }
+
+        for layer in &mut self.runtime_animation_layers {
+            if layer.weight <= 0.0 || layer.is_base_layer() {
+                continue;
+            }
+            let weight = layer.weight;
+            layer.runtime_animation_mut().animate_into(frame_time, &mut self.animation_arena, &self.bone_arena, weight);
+        }
+    }
+
+    fn reset_animated_targets(&mut self) {
+        let bones = self.bone_arena.arena();
+        for layer in &self.runtime_animation_layers {
+            let runtime_animation = layer.runtime_animation();
+            for bone_index in runtime_animation.bone_bind_index_map().iter().chain(runtime_animation.movable_bone_bind_index_map()) {
+                self.animation_arena.reset_bone(*bone_index, &bones);
+            }
+            for morph_index in runtime_animation.morph_bind_index_map().iter().flat_map(|morph_indices| morph_indices.iter()) {
+                self.animation_arena.reset_morph(*morph_index);
+            }
         }
     }
 
+    // the rest pose takes the weight the base layers leave, so that fading in from a single layer does not pop
+    fn blend_base_layers(&mut self, frame_time: f32) {
+        let base_weight: f32 = self.runtime_animation_layers.iter()
+            .filter(|layer| layer.is_base_layer())
+            .map(|layer| layer.weight)
+            .sum();
+        let mut total_weight = (1.0 - base_weight).max(0.0);
+        let mut max_weight = 0.0;
+        for layer in &mut self.runtime_animation_layers {
+            if !layer.is_base_layer() {
+                continue;
+            }
+
+            let weight = layer.weight;
+            let scratch_arena = layer.animate_scratch(frame_time, &self.animation_arena, &self.bone_arena);
+
+            if total_weight == 0.0 {
+                self.animation_arena.copy_from(scratch_arena);
+            } else {
+                self.animation_arena.blend(scratch_arena, weight / (total_weight + weight));
+            }
+            total_weight += weight;
+
+            // ik states and visibility are not blendable, the most weighted layer wins
+            if max_weight < weight {
+                max_weight = weight;
+                let mut iksolver_state_arena = self.animation_arena.iksolver_state_arena_mut();
+                iksolver_state_arena.copy_from_slice(&scratch_arena.iksolver_state_arena());
+                self.animation_arena.set_visibility(scratch_arena.visibility());
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn animation_arena(&self) -> &AnimationArena {
+        &self.animation_arena
+    }
+
     #[inline]
     pub(crate) fn animation_arena_mut(&mut self) -> &mut AnimationArena {
         &mut self.animation_arena
     }
 
+    #[inline]
+    pub(crate) fn animation_and_bone_arena_mut(&mut self) -> (&mut AnimationArena, &MmdRuntimeBoneArena) {
+        (&mut self.animation_arena, &self.bone_arena)
+    }
+
+    #[inline]
+    pub(crate) fn bone_arena(&self) -> &MmdRuntimeBoneArena {
+        &self.bone_arena
+    }
+
     #[inline]
     pub(crate) fn bone_arena_mut(&mut self) -> &mut MmdRuntimeBoneArena {
         &mut self.bone_arena
     }
 
-    pub(crate) fn before_physics(&mut self, frame_time: Option<f32>) {
+    #[inline]
+    pub(crate) fn ik_solver_arena(&self) -> &IkSolverArena {
+        &self.ik_solver_arena
+    }
+
+    #[inline]
+    pub(crate) fn ik_solver_arena_mut(&mut self) -> &mut IkSolverArena {
+        &mut self.ik_solver_arena
+    }
+
+    #[inline]
+    pub(crate) fn ik_solver_diagnostics_arena_mut(&mut self) -> UncheckedSliceMut<'_, IkSolverDiagnostics> {
+        UncheckedSliceMut::new(&mut self.ik_solver_diagnostics_arena)
+    }
+
+    #[inline]
+    pub(crate) fn foot_grounding_mut(&mut self) -> &mut Option<FootGrounding> {
+        &mut self.foot_grounding
+    }
+
+    #[inline]
+    pub(crate) fn look_at_solver_mut(&mut self) -> &mut Option<LookAtSolver> {
+        &mut self.look_at_solver
+    }
+
+    #[inline]
+    pub(crate) fn is_visible(&self) -> bool {
+        self.animation_arena.visibility() != 0
+    }
+
+    #[inline]
+    pub(crate) fn set_skip_update_when_invisible(&mut self, skip_update_when_invisible: bool) {
+        self.skip_update_when_invisible = skip_update_when_invisible;
+    }
+
+    #[inline]
+    fn is_update_skipped(&self) -> bool {
+        self.skip_update_when_invisible && !self.is_visible()
+    }
+
+    // delta_time is the time in frames elapsed since the previous update
+    pub(crate) fn before_physics(&mut self, frame_time: Option<f32>, delta_time: f32, ground: &Ground) {
+        self.delta_time = delta_time;
+        self.update_cross_fade(delta_time);
         if let Some(frame_time) = frame_time {
-            if let Some(runtime_animation) = self.runtime_animation {
-                let runtime_animation: &mut MmdRuntimeAnimation = unsafe {
-                    std::mem::transmute(runtime_animation)
-                };
-                runtime_animation.animate(frame_time, self);
-            }
+            self.animate_runtime_animations(frame_time);
+        }
+
+        if self.is_update_skipped() {
+            return;
         }
 
         #[cfg(debug_assertions)]
@@ -189,11 +509,85 @@ impl MmdModel {
         }
 
         self.morph_controller.update(&mut self.bone_arena, self.animation_arena.morph_arena());
-        self.update(false);
+        self.update(false, Some(ground));
     }
 
     pub(crate) fn after_physics(&mut self) {
-        self.update(true);
+        if self.is_update_skipped() {
+            return;
+        }
+        self.update(true, None);
+    }
+
+    // evaluates runtime_animation on a copy of the model so the live pose is left untouched,
+    // visit is called with the animated and solved arenas of each frame
+    pub(crate) fn evaluate_runtime_animation(
+        &self,
+        runtime_animation: &mut MmdRuntimeAnimation,
+        frame_times: impl IntoIterator<Item = f32>,
+        mut visit: impl FnMut(&AnimationArena, &MmdRuntimeBoneArena),
+    ) {
+        let mut model = MmdModel {
+            runtime_animation_layers: Vec::new(),
+            cross_fade: None,
+            animation_arena: self.animation_arena.clone(),
+            bone_arena: self.bone_arena.clone(),
+            append_transform_solver_arena: self.append_transform_solver_arena.clone(),
+            ik_solver_arena: self.ik_solver_arena.clone(),
+            ik_solver_diagnostics_arena: self.ik_solver_diagnostics_arena.clone(),
+            morph_controller: self.morph_controller.clone(),
+            foot_grounding: None,
+            look_at_solver: None,
+            skip_update_when_invisible: false,
+            delta_time: 0.0,
+            sorted_runtime_bones: self.sorted_runtime_bones.clone(),
+            sorted_runtime_root_bones: self.sorted_runtime_root_bones.clone(),
+        };
+        // pins are interactive overrides of the live model, not part of the animation
+        for ik_solver in model.ik_solver_arena.arena_mut().iter_mut() {
+            ik_solver.clear_target_pin(0.0);
+        }
+        for bone in model.bone_arena.arena_mut().iter_mut() {
+            bone.look_at_rotation = None;
+        }
+
+        for frame_time in frame_times {
+            // the runtime animation state only caches keyframe lookups, so sharing it with the live model is safe
+            model.animation_arena.reset(&model.bone_arena.arena());
+            runtime_animation.animate_into(frame_time, &mut model.animation_arena, &model.bone_arena, 1.0);
+
+            model.morph_controller.update(&mut model.bone_arena, model.animation_arena.morph_arena());
+            model.update(false, None);
+            model.update(true, None);
+
+            visit(&model.animation_arena, &model.bone_arena);
+        }
+    }
+
+    // each baked frame is the bone world matrices followed by the morph weights
+    pub(crate) fn bake_runtime_animation(
+        &self,
+        runtime_animation: &mut MmdRuntimeAnimation,
+        start_frame: f32,
+        end_frame: f32,
+        step: f32,
+    ) -> Vec<f32> {
+        let frame_count = if 0.0 < step && start_frame <= end_frame {
+            ((end_frame - start_frame) / step).floor() as usize + 1
+        } else {
+            0
+        };
+        let frame_size = self.bone_count() * 16 + self.morph_count();
+        let mut baked = Vec::with_capacity(frame_count * frame_size);
+
+        let frame_times = (0..frame_count).map(|i| start_frame + step * i as f32);
+        self.evaluate_runtime_animation(runtime_animation, frame_times, |animation_arena, bone_arena| {
+            for world_matrix in bone_arena.world_matrices().iter() {
+                baked.extend_from_slice(&world_matrix.to_cols_array());
+            }
+            baked.extend_from_slice(&animation_arena.morph_arena());
+        });
+        baked
     }
 
     pub(crate) fn update_local_matrices(&mut self) {
@@ -203,7 +597,11 @@ impl MmdModel {
         }
     }
 
-    fn update(&mut self, after_physics_stage: bool) {
+    fn update(&mut self, after_physics_stage: bool, ground: Option<&Ground>) {
+        if let Some(look_at_solver) = &self.look_at_solver {
+            look_at_solver.reset(&mut self.bone_arena, after_physics_stage);
+        }
+
         for bone in self.sorted_runtime_bones.iter() {
             let bone = &mut self.bone_arena.arena_mut()[*bone];
             if bone.transform_after_physics != after_physics_stage {
@@ -223,6 +621,24 @@ impl MmdModel {
             self.bone_arena.update_world_matrix(bone_index);
         }
 
+        if let Some(look_at_solver) = &mut self.look_at_solver {
+            look_at_solver.solve(
+                &self.animation_arena,
+                &mut self.bone_arena,
+                &self.append_transform_solver_arena,
+                after_physics_stage,
+                self.delta_time,
+            );
+        }
+
+        let foot_grounding = match (ground, &mut self.foot_grounding) {
+            (Some(ground), Some(foot_grounding)) if !after_physics_stage => {
+                foot_grounding.offset_ik_bones(ground, &mut self.bone_arena, &mut self.ik_solver_arena);
+                Some(&*foot_grounding)
+            }
+            _ => None,
+        };
+
         for i in 0..self.sorted_runtime_bones.len() {
             let bone_index = self.sorted_runtime_bones[i];
             let bone = &self.bone_arena.arena()[bone_index];
@@ -238,11 +654,22 @@ impl MmdModel {
             }
 
             let bone = &self.bone_arena.arena()[bone_index];
-            if let Some(ik_solver) = bone.ik_solver {
-                if self.animation_arena.iksolver_state_arena()[ik_solver] != 0 {
-                    let ik_solver = &mut self.ik_solver_arena.arena_mut()[ik_solver];
-                    ik_solver.solve(&self.animation_arena, &mut self.bone_arena, &self.append_transform_solver_arena);
+            if let Some(ik_solver_index) = bone.ik_solver {
+                let diagnostics = &mut self.ik_solver_diagnostics_arena[ik_solver_index as usize];
+                let ik_enabled = self.animation_arena.iksolver_state_arena()[ik_solver_index] != 0;
+                let ik_solver = &mut self.ik_solver_arena.arena_mut()[ik_solver_index];
+                let pinned = ik_solver.apply_target_pin(&mut self.bone_arena, self.delta_time);
+                if ik_enabled || pinned {
+                    ik_solver.solve(&self.animation_arena, &mut self.bone_arena, &self.append_transform_solver_arena, diagnostics);
+                    if pinned {
+                        ik_solver.apply_target_pin_rotation(&mut self.bone_arena);
+                    }
+                    if let Some(foot_grounding) = foot_grounding {
+                        foot_grounding.align_target_bone(ik_solver_index, ik_solver.target_bone(), &mut self.bone_arena);
+                    }
                     self.bone_arena.update_world_matrix(bone_index);
+                } else {
+                    diagnostics.solved = 0;
                 }
             }
         }
@@ -135,6 +135,7 @@ pub(crate) struct IkLinkMetadata {
     pub(crate) limits: Option<IkChainAngleLimits>,
 }
 
+#[derive(Clone)]
 pub(crate) struct IkChainAngleLimits {
     pub(crate) minimum_angle: Vec3A,
     pub(crate) maximum_angle: Vec3A,
@@ -253,17 +254,20 @@ impl<'a> BoneMetadataReader<'a> {
     }
 }
 
+#[derive(Clone)]
 pub(crate) enum MorphMetadata {
     Bone(BoneMorphMetadata),
     Group(GroupMorphMetadata),
 }
 
+#[derive(Clone)]
 pub(crate) struct BoneMorphMetadata {
     pub(crate) indices: Vec<i32>,
     pub(crate) positions: Vec<Vec3A>,
     pub(crate) rotations: Vec<Quat>,
 }
 
+#[derive(Clone)]
 pub(crate) struct GroupMorphMetadata {
     pub(crate) indices: Vec<i32>,
     pub(crate) ratios: Vec<f32>,
@@ -328,7 +332,7 @@ impl<'a> MorphMetadataReader<'a> {
 }
 
 pub(crate) struct RigidbodyMetadata {
-    bone_index: i32,
+    pub(crate) bone_index: i32,
     collision_group: u8,
     collision_mask: u16,
     shape_type: u8,
@@ -340,7 +344,7 @@ pub(crate) struct RigidbodyMetadata {
     angular_damping: f32,
     repulsion: f32,
     friction: f32,
-    physics_mode: u8,
+    pub(crate) physics_mode: u8,
 }
 
 pub(crate) enum RigidbodyShapeType {
@@ -355,6 +359,17 @@ pub(crate) enum RigidbodyPhysicsMode {
     PhysicsWithBone = 2,
 }
 
+impl RigidbodyPhysicsMode {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RigidbodyPhysicsMode::FollowBone),
+            1 => Some(RigidbodyPhysicsMode::Physics),
+            2 => Some(RigidbodyPhysicsMode::PhysicsWithBone),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) struct RigidbodyMetadataReader<'a> {
     buffer: MetadataBuffer<'a>,
     count: u32,
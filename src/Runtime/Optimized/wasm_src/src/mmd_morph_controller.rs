@@ -6,6 +6,7 @@ use crate::mmd_model_metadata::MorphMetadata;
 use crate::mmd_runtime_bone::MmdRuntimeBoneArena;
 use crate::unchecked_slice::{UncheckedSlice, UncheckedSliceMut};
 
+#[derive(Clone)]
 pub(crate) struct MmdMorphController {
     morphs: Box<[MorphMetadata]>,
     active_morphs: Box<[bool]>,
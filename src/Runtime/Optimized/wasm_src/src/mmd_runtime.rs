@@ -1,18 +1,34 @@
+use std::num::NonZeroUsize;
 use std::ptr::NonNull;
 
+use glam::{Mat4, Quat, Vec3A};
 use wasm_bindgen::prelude::*;
 
+use crate::animation::mmd_camera_animation::{MmdCameraAnimation, MmdCameraState};
+use crate::animation::mmd_light_animation::{MmdLightAnimation, MmdLightState};
 use crate::animation::mmd_runtime_animation::MmdRuntimeAnimation;
+use crate::ik_solver::IkSolverMode;
+use crate::foot_grounding::{FootGrounding, Ground, GROUND_PLANE_STRIDE};
+use crate::gltf_export;
+use crate::look_at_solver::LookAtSolver;
 use crate::mmd_model::MmdModel;
 use crate::mmd_model_metadata::MetadataBuffer;
 
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
 
+const MMD_FRAME_RATE: f32 = 30.0;
+
 #[wasm_bindgen]
 pub struct MmdRuntime {
     #[allow(clippy::vec_box)]
     mmd_models: Vec<Box<MmdModel>>,
+    ground: Ground,
+    delta_time: f32, // in frames
+    camera_animation: Option<NonZeroUsize>,
+    camera_state: MmdCameraState,
+    light_animation: Option<NonZeroUsize>,
+    light_state: MmdLightState,
 }
 
 #[wasm_bindgen]
@@ -20,6 +36,12 @@ impl MmdRuntime {
     pub(crate) fn new() -> Self {
         MmdRuntime {
             mmd_models: Vec::new(),
+            ground: Ground::new(),
+            delta_time: 60.0_f32.recip() * MMD_FRAME_RATE,
+            camera_animation: None,
+            camera_state: MmdCameraState::new(),
+            light_animation: None,
+            light_state: MmdLightState::new(),
         }
     }
 
@@ -88,6 +110,31 @@ impl MmdRuntime {
         animation_arena.morph_arena_mut().as_mut_ptr()
     }
     
+    #[wasm_bindgen(js_name = "isVisible")]
+    pub fn is_visible(&self, ptr: *mut usize) -> bool {
+        let ptr = ptr as *mut MmdModel;
+        unsafe {
+            &*ptr
+        }.is_visible()
+    }
+
+    #[wasm_bindgen(js_name = "setSkipUpdateWhenInvisible")]
+    pub fn set_skip_update_when_invisible(&mut self, ptr: *mut usize, skip_update_when_invisible: bool) {
+        let ptr = ptr as *mut MmdModel;
+        unsafe {
+            &mut *ptr
+        }.set_skip_update_when_invisible(skip_update_when_invisible);
+    }
+
+    #[wasm_bindgen(js_name = "getIkSolverDiagnosticsArena")]
+    pub fn get_ik_solver_diagnostics_arena(&mut self, ptr: *mut usize) -> *mut u8 {
+        let ptr = ptr as *mut MmdModel;
+        let mut ik_solver_diagnostics_arena = unsafe {
+            &mut *ptr
+        }.ik_solver_diagnostics_arena_mut();
+        ik_solver_diagnostics_arena.as_mut_ptr() as *mut u8
+    }
+
     #[wasm_bindgen(js_name = "getBoneWorldMatrixArena")]
     pub fn get_bone_world_matrix_arena(&mut self, ptr: *mut usize) -> *mut f32 {
         let ptr = ptr as *mut MmdModel;
@@ -115,28 +162,400 @@ impl MmdRuntime {
             None => None,
         };
 
-        let animation = unsafe {
+        unsafe {
+            &mut *ptr
+        }.set_runtime_animation(runtime_animation);
+    }
+
+    // returns the baked frames of runtime_animation from start_frame to end_frame,
+    // each frame is bone count world matrices followed by the morph weights
+    #[wasm_bindgen(js_name = "bakeRuntimeAnimation")]
+    pub fn bake_runtime_animation(&mut self, ptr: *mut usize, runtime_animation: *mut usize, start_frame: f32, end_frame: f32, step: f32) -> Vec<f32> {
+        let ptr = ptr as *mut MmdModel;
+        let runtime_animation = match NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            Some(runtime_animation) => unsafe {
+                &mut *runtime_animation.as_ptr()
+            },
+            None => return Vec::new(),
+        };
+
+        unsafe {
+            &*ptr
+        }.bake_runtime_animation(runtime_animation, start_frame, end_frame, step)
+    }
+
+    // returns a glb file with the skeleton and runtime_animation baked from start_frame to end_frame,
+    // bone_names and morph_names are newline separated. the result is empty if the pose is not finite
+    #[wasm_bindgen(js_name = "exportGlb")]
+    pub fn export_glb(
+        &mut self,
+        ptr: *mut usize,
+        runtime_animation: *mut usize,
+        start_frame: f32,
+        end_frame: f32,
+        bone_names: &str,
+        morph_names: &str,
+    ) -> Vec<u8> {
+        let ptr = ptr as *mut MmdModel;
+        let runtime_animation = match NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            Some(runtime_animation) => unsafe {
+                &mut *runtime_animation.as_ptr()
+            },
+            None => return Vec::new(),
+        };
+
+        gltf_export::export_glb(unsafe { &*ptr }, runtime_animation, start_frame, end_frame, bone_names, morph_names)
+    }
+
+    #[wasm_bindgen(js_name = "addRuntimeAnimation")]
+    pub fn add_runtime_animation(&mut self, ptr: *mut usize, runtime_animation: *mut usize, weight: f32) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(runtime_animation) = NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            unsafe {
+                &mut *ptr
+            }.add_runtime_animation(runtime_animation, weight);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setRuntimeAnimationWeight")]
+    pub fn set_runtime_animation_weight(&mut self, ptr: *mut usize, runtime_animation: *mut usize, weight: f32) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(runtime_animation) = NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            unsafe {
+                &mut *ptr
+            }.set_runtime_animation_weight(runtime_animation, weight);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "removeRuntimeAnimation")]
+    pub fn remove_runtime_animation(&mut self, ptr: *mut usize, runtime_animation: *mut usize) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(runtime_animation) = NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            unsafe {
+                &mut *ptr
+            }.remove_runtime_animation(runtime_animation);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setRuntimeAnimationOrder")]
+    pub fn set_runtime_animation_order(&mut self, ptr: *mut usize, runtime_animation: *mut usize, order: i32) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(runtime_animation) = NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            unsafe {
+                &mut *ptr
+            }.set_runtime_animation_order(runtime_animation, order);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setRuntimeAnimationBoneMaskSubtree")]
+    pub fn set_runtime_animation_bone_mask_subtree(&mut self, ptr: *mut usize, runtime_animation: *mut usize, root_bone: u32, weight: f32) {
+        let ptr = ptr as *mut MmdModel;
+        let mut runtime_animation = match NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            Some(runtime_animation) => runtime_animation,
+            None => return,
+        };
+        let mmd_model = unsafe {
+            &mut *ptr
+        };
+        let runtime_animation = unsafe {
+            runtime_animation.as_mut()
+        };
+
+        let bone_count = mmd_model.bone_count();
+        let bone_mask = runtime_animation.bone_mask_mut().get_or_insert_with(|| vec![0.0; bone_count].into_boxed_slice());
+        mmd_model.subtree_bone_mask(root_bone, weight, bone_mask);
+    }
+
+    // duration is in seconds
+    #[wasm_bindgen(js_name = "crossFadeRuntimeAnimation")]
+    pub fn cross_fade_runtime_animation(&mut self, ptr: *mut usize, runtime_animation: *mut usize, duration: f32) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(runtime_animation) = NonNull::new(runtime_animation as *mut MmdRuntimeAnimation) {
+            unsafe {
+                &mut *ptr
+            }.cross_fade_runtime_animation(runtime_animation, duration * MMD_FRAME_RATE);
+        }
+    }
+
+    // returns false if the mode is unknown or the ik solver can not use it (two bone mode needs exactly two links)
+    #[wasm_bindgen(js_name = "setIkSolverMode")]
+    pub fn set_ik_solver_mode(&mut self, ptr: *mut usize, ik_solver_index: u32, mode: u8) -> bool {
+        let ptr = ptr as *mut MmdModel;
+        let mode = match IkSolverMode::from_u8(mode) {
+            Some(mode) => mode,
+            None => return false,
+        };
+
+        let mut ik_solver_arena = unsafe {
+            &mut *ptr
+        }.ik_solver_arena_mut().arena_mut();
+        match ik_solver_arena.get_mut(ik_solver_index) {
+            Some(ik_solver) => ik_solver.set_mode(mode),
+            None => false,
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setIkTargetPin")]
+    pub fn set_ik_target_pin(&mut self, ptr: *mut usize, ik_solver_index: u32, x: f32, y: f32, z: f32, weight: f32) {
+        let ptr = ptr as *mut MmdModel;
+        let mut ik_solver_arena = unsafe {
+            &mut *ptr
+        }.ik_solver_arena_mut().arena_mut();
+        if let Some(ik_solver) = ik_solver_arena.get_mut(ik_solver_index) {
+            ik_solver.set_target_pin(Vec3A::new(x, y, z), weight);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setIkTargetPinRotation")]
+    pub fn set_ik_target_pin_rotation(&mut self, ptr: *mut usize, ik_solver_index: u32, x: f32, y: f32, z: f32, w: f32) {
+        let ptr = ptr as *mut MmdModel;
+        let mut ik_solver_arena = unsafe {
+            &mut *ptr
+        }.ik_solver_arena_mut().arena_mut();
+        if let Some(ik_solver) = ik_solver_arena.get_mut(ik_solver_index) {
+            ik_solver.set_target_pin_rotation(Some(Quat::from_xyzw(x, y, z, w).normalize()));
+        }
+    }
+
+    #[wasm_bindgen(js_name = "clearIkTargetPinRotation")]
+    pub fn clear_ik_target_pin_rotation(&mut self, ptr: *mut usize, ik_solver_index: u32) {
+        let ptr = ptr as *mut MmdModel;
+        let mut ik_solver_arena = unsafe {
+            &mut *ptr
+        }.ik_solver_arena_mut().arena_mut();
+        if let Some(ik_solver) = ik_solver_arena.get_mut(ik_solver_index) {
+            ik_solver.set_target_pin_rotation(None);
+        }
+    }
+
+    // fade_out_duration is in frames, zero releases the pin immediately
+    #[wasm_bindgen(js_name = "clearIkTargetPin")]
+    pub fn clear_ik_target_pin(&mut self, ptr: *mut usize, ik_solver_index: u32, fade_out_duration: f32) {
+        let ptr = ptr as *mut MmdModel;
+        let mut ik_solver_arena = unsafe {
+            &mut *ptr
+        }.ik_solver_arena_mut().arena_mut();
+        if let Some(ik_solver) = ik_solver_arena.get_mut(ik_solver_index) {
+            ik_solver.clear_target_pin(fade_out_duration);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setGroundPlanes")]
+    pub fn set_ground_planes(&mut self, planes_ptr: *const f32, plane_count: usize) {
+        let planes = unsafe {
+            std::slice::from_raw_parts(planes_ptr, plane_count * GROUND_PLANE_STRIDE)
+        };
+        self.ground.set_planes(planes);
+    }
+
+    #[wasm_bindgen(js_name = "setGroundHeightField")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_ground_height_field(
+        &mut self,
+        origin_x: f32,
+        origin_z: f32,
+        cell_size: f32,
+        width: u32,
+        depth: u32,
+        heights_ptr: *const f32,
+    ) {
+        let heights = unsafe {
+            std::slice::from_raw_parts(heights_ptr, width as usize * depth as usize)
+        };
+        self.ground.set_height_field(origin_x, origin_z, cell_size, width, depth, heights.into());
+    }
+
+    #[wasm_bindgen(js_name = "clearGround")]
+    pub fn clear_ground(&mut self) {
+        self.ground.clear();
+    }
+
+    #[wasm_bindgen(js_name = "setFootGrounding")]
+    pub fn set_foot_grounding(&mut self, ptr: *mut usize, centre_bone: u32, leg_ik_solvers_ptr: *const u32, leg_count: usize) {
+        let ptr = ptr as *mut MmdModel;
+        let leg_ik_solvers = unsafe {
+            std::slice::from_raw_parts(leg_ik_solvers_ptr, leg_count)
+        };
+        let foot_grounding = unsafe {
+            &mut *ptr
+        }.foot_grounding_mut();
+        *foot_grounding = Some(FootGrounding::new(centre_bone, leg_ik_solvers.into()));
+    }
+
+    #[wasm_bindgen(js_name = "setFootGroundingWorldMatrix")]
+    pub fn set_foot_grounding_world_matrix(&mut self, ptr: *mut usize, matrix_ptr: *const f32) {
+        let ptr = ptr as *mut MmdModel;
+        let matrix = unsafe {
+            std::slice::from_raw_parts(matrix_ptr, 16)
+        };
+        if let Some(foot_grounding) = unsafe {
+            &mut *ptr
+        }.foot_grounding_mut() {
+            foot_grounding.set_world_matrix(Mat4::from_cols_slice(matrix));
+        }
+    }
+
+    #[wasm_bindgen(js_name = "clearFootGrounding")]
+    pub fn clear_foot_grounding(&mut self, ptr: *mut usize) {
+        let ptr = ptr as *mut MmdModel;
+        let foot_grounding = unsafe {
+            &mut *ptr
+        }.foot_grounding_mut();
+        *foot_grounding = None;
+    }
+
+    #[wasm_bindgen(js_name = "setLookAtSolver")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_look_at_solver(
+        &mut self,
+        ptr: *mut usize,
+        bones_ptr: *const u32,
+        weights_ptr: *const f32,
+        limit_angles_ptr: *const f32,
+        bone_count: usize,
+        forward_x: f32,
+        forward_y: f32,
+        forward_z: f32,
+        smoothing: f32,
+    ) {
+        let ptr = ptr as *mut MmdModel;
+        let (bones, weights, limit_angles) = unsafe {(
+            std::slice::from_raw_parts(bones_ptr, bone_count),
+            std::slice::from_raw_parts(weights_ptr, bone_count),
+            std::slice::from_raw_parts(limit_angles_ptr, bone_count),
+        )};
+        let look_at_solver = unsafe {
+            &mut *ptr
+        }.look_at_solver_mut();
+        *look_at_solver = Some(LookAtSolver::new(
+            bones,
+            weights,
+            limit_angles,
+            Vec3A::new(forward_x, forward_y, forward_z),
+            smoothing,
+        ));
+    }
+
+    #[wasm_bindgen(js_name = "setLookAtWorldMatrix")]
+    pub fn set_look_at_world_matrix(&mut self, ptr: *mut usize, matrix_ptr: *const f32) {
+        let ptr = ptr as *mut MmdModel;
+        let matrix = unsafe {
+            std::slice::from_raw_parts(matrix_ptr, 16)
+        };
+        if let Some(look_at_solver) = unsafe {
+            &mut *ptr
+        }.look_at_solver_mut() {
+            look_at_solver.set_world_matrix(Mat4::from_cols_slice(matrix));
+        }
+    }
+
+    // the target is in world space
+    #[wasm_bindgen(js_name = "setLookAtTarget")]
+    pub fn set_look_at_target(&mut self, ptr: *mut usize, x: f32, y: f32, z: f32) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(look_at_solver) = unsafe {
+            &mut *ptr
+        }.look_at_solver_mut() {
+            look_at_solver.set_target(Some(Vec3A::new(x, y, z)));
+        }
+    }
+
+    #[wasm_bindgen(js_name = "clearLookAtTarget")]
+    pub fn clear_look_at_target(&mut self, ptr: *mut usize) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(look_at_solver) = unsafe {
+            &mut *ptr
+        }.look_at_solver_mut() {
+            look_at_solver.set_target(None);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "setLookAtWeight")]
+    pub fn set_look_at_weight(&mut self, ptr: *mut usize, weight: f32) {
+        let ptr = ptr as *mut MmdModel;
+        if let Some(look_at_solver) = unsafe {
+            &mut *ptr
+        }.look_at_solver_mut() {
+            look_at_solver.set_weight(weight);
+        }
+    }
+
+    #[wasm_bindgen(js_name = "clearLookAtSolver")]
+    pub fn clear_look_at_solver(&mut self, ptr: *mut usize) {
+        let ptr = ptr as *mut MmdModel;
+        let look_at_solver = unsafe {
             &mut *ptr
-        }.runtime_animation_mut();
-        *animation = runtime_animation;
+        }.look_at_solver_mut();
+        *look_at_solver = None;
+    }
+
+    // the camera animation must be fully written before it is set
+    #[wasm_bindgen(js_name = "setCameraAnimation")]
+    pub fn set_camera_animation(&mut self, camera_animation: *mut usize) {
+        self.camera_animation = NonZeroUsize::new(camera_animation as usize);
+        if let Some(camera_animation) = self.camera_animation {
+            unsafe {
+                &mut *(camera_animation.get() as *mut MmdCameraAnimation)
+            }.build_curves();
+        }
+    }
+
+    // (position.xyz, rotation.xyz, distance, fov, is_perspective: u8)
+    #[wasm_bindgen(js_name = "getCameraStateArena")]
+    pub fn get_camera_state_arena(&mut self) -> *mut f32 {
+        &mut self.camera_state as *mut MmdCameraState as *mut f32
+    }
+
+    #[wasm_bindgen(js_name = "setLightAnimation")]
+    pub fn set_light_animation(&mut self, light_animation: *mut usize) {
+        self.light_animation = NonZeroUsize::new(light_animation as usize);
+    }
+
+    // (color.rgb, direction.xyz, shadow_distance, shadow_mode: u8)
+    #[wasm_bindgen(js_name = "getLightStateArena")]
+    pub fn get_light_state_arena(&mut self) -> *mut f32 {
+        &mut self.light_state as *mut MmdLightState as *mut f32
+    }
+
+    // seconds elapsed since the previous update, drives the time based fades and smoothing of the next updates
+    #[wasm_bindgen(js_name = "setDeltaTime")]
+    pub fn set_delta_time(&mut self, delta_time: f32) {
+        self.delta_time = delta_time.max(0.0) * MMD_FRAME_RATE;
     }
 
     #[wasm_bindgen(js_name = "beforePhysics")]
     pub fn before_physics(&mut self, frame_time: Option<f32>){
+        if let (Some(frame_time), Some(camera_animation)) = (frame_time, self.camera_animation) {
+            let camera_animation = unsafe {
+                &*(camera_animation.get() as *const MmdCameraAnimation)
+            };
+            camera_animation.animate(frame_time, &mut self.camera_state);
+        }
+
+        if let (Some(frame_time), Some(light_animation)) = (frame_time, self.light_animation) {
+            let light_animation = unsafe {
+                &*(light_animation.get() as *const MmdLightAnimation)
+            };
+            light_animation.animate(frame_time, &mut self.light_state);
+        }
+
+        let ground = &self.ground;
+        let delta_time = self.delta_time;
+
         #[cfg(feature = "parallel")]
         {
             if 1 < self.mmd_models.len() {
                 self.mmd_models.par_iter_mut().for_each(|mmd_model| {
-                    mmd_model.before_physics(frame_time);
+                    mmd_model.before_physics(frame_time, delta_time, ground);
                 });
             } else if 0 < self.mmd_models.len() {
-                self.mmd_models[0].before_physics(frame_time);
+                self.mmd_models[0].before_physics(frame_time, delta_time, ground);
             }
         }
 
         #[cfg(not(feature = "parallel"))]
         for mmd_model in &mut self.mmd_models {
-            mmd_model.before_physics(frame_time);
+            mmd_model.before_physics(frame_time, delta_time, ground);
         }
     }
 
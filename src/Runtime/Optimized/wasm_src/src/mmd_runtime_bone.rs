@@ -4,6 +4,7 @@ use crate::animation_arena::AnimationArena;
 use crate::append_transform_solver::AppendTransformSolverArena;
 use crate::unchecked_slice::{UncheckedSlice, UncheckedSliceMut};
 
+#[derive(Clone)]
 pub(crate) struct MmdRuntimeBoneArena {
     arena: Box<[MmdRuntimeBone]>,
     world_matrix_arena: Box<[Mat4]>,
@@ -58,6 +59,16 @@ impl MmdRuntimeBoneArena {
         UncheckedSliceMut::new(&mut self.world_matrix_arena)
     }
 
+    // overrides the local matrix so that the bone ends up at world_matrix, then updates the subtree
+    pub(crate) fn set_world_matrix(&mut self, bone: u32, world_matrix: Mat4) {
+        let local_matrix = match self.arena()[bone].parent_bone {
+            Some(parent_bone) => self.world_matrices()[parent_bone].inverse() * world_matrix,
+            None => world_matrix,
+        };
+        self.arena_mut()[bone].local_matrix = local_matrix;
+        self.update_world_matrix(bone);
+    }
+
     pub fn update_world_matrix(&mut self, root: u32) {
         self.bone_stack.push(root);
 
@@ -77,6 +88,7 @@ impl MmdRuntimeBoneArena {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct MmdRuntimeBone {
     pub rest_position: Vec3A,
     index: u32,
@@ -85,6 +97,8 @@ pub(crate) struct MmdRuntimeBone {
     pub child_bones: Vec<u32>,
     pub transform_order: i32,
     pub transform_after_physics: bool,
+    // moved by a rigidbody that is not in follow bone mode
+    pub physics_driven: bool,
 
     pub append_transform_solver: Option<u32>,
     pub ik_solver: Option<u32>,
@@ -93,6 +107,7 @@ pub(crate) struct MmdRuntimeBone {
     pub morph_rotation_offset: Option<Quat>,
 
     pub ik_rotation: Option<Quat>,
+    pub look_at_rotation: Option<Quat>,
 
     pub local_matrix: Mat4,
 }
@@ -107,6 +122,7 @@ impl MmdRuntimeBone {
             child_bones: Vec::new(),
             transform_order: 0,
             transform_after_physics: false,
+            physics_driven: false,
 
             append_transform_solver: None,
             ik_solver: None,
@@ -115,6 +131,7 @@ impl MmdRuntimeBone {
             morph_rotation_offset: None,
 
             ik_rotation: None,
+            look_at_rotation: None,
 
             local_matrix: Mat4::IDENTITY,
         }
@@ -133,6 +150,9 @@ impl MmdRuntimeBone {
         if let Some(morph_rotation_offset) = self.morph_rotation_offset {
             rotation *= morph_rotation_offset;
         }
+        if let Some(look_at_rotation) = self.look_at_rotation {
+            rotation = look_at_rotation * rotation;
+        }
         rotation
     }
 